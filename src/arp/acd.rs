@@ -0,0 +1,328 @@
+// IPv4 address conflict detection
+// Reference: https://tools.ietf.org/html/rfc5227
+
+use crate::arp::ARP;
+use crate::ethernet::{ChannelWriter, Ethernet, HwAddr, ProtocolAddr};
+use crate::events::{self, StackEvent};
+use crate::net_util;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Protocol constants, RFC 5227 section 1.1
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u32 = 3;
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+const UNSPECIFIED_ADDR: ProtocolAddr = [0, 0, 0, 0];
+
+lazy_static! {
+    static ref CLAIMS: Mutex<Claims> = Mutex::new(Claims::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClaimState {
+    Probing,
+    Bound,
+    // Somebody else won the address while we were probing for it.
+    Failed,
+}
+
+struct Claim {
+    state: ClaimState,
+    conflicting_hw_addr: Option<HwAddr>,
+    last_defended: Option<Instant>,
+}
+
+// The addresses being claimed or defended, keyed by address.
+#[derive(Default)]
+struct Claims {
+    claims: HashMap<ProtocolAddr, Claim>,
+}
+
+impl Claims {
+    fn insert(&mut self, addr: ProtocolAddr, state: ClaimState) {
+        self.claims.insert(
+            addr,
+            Claim {
+                state,
+                conflicting_hw_addr: None,
+                last_defended: None,
+            },
+        );
+    }
+
+    fn remove(&mut self, addr: &ProtocolAddr) {
+        self.claims.remove(addr);
+    }
+
+    fn set_state(&mut self, addr: &ProtocolAddr, state: ClaimState) {
+        if let Some(claim) = self.claims.get_mut(addr) {
+            claim.state = state;
+        }
+    }
+
+    fn is_tentative(&self, addr: &ProtocolAddr) -> bool {
+        match self.claims.get(addr) {
+            Some(claim) => claim.state != ClaimState::Bound,
+            None => false,
+        }
+    }
+
+    fn conflict(&self, addr: &ProtocolAddr) -> Option<HwAddr> {
+        self.claims
+            .get(addr)
+            .and_then(|claim| claim.conflicting_hw_addr)
+    }
+
+    // Records the conflicts an ARP packet from another host reveals. Returns whether the bound address it conflicts
+    // with must be defended with an announcement.
+    fn inspect(
+        &mut self,
+        sender_addr: ProtocolAddr,
+        sender_hw_addr: HwAddr,
+        target_addr: ProtocolAddr,
+        now: Instant,
+    ) -> bool {
+        // Some other host is probing for the address we are probing for.
+        if sender_addr == UNSPECIFIED_ADDR {
+            if let Some(claim) = self.claims.get_mut(&target_addr) {
+                if claim.state == ClaimState::Probing {
+                    claim.conflicting_hw_addr = Some(sender_hw_addr);
+                }
+            }
+            return false;
+        }
+
+        let claim = match self.claims.get_mut(&sender_addr) {
+            Some(claim) => claim,
+            None => return false,
+        };
+        match claim.state {
+            ClaimState::Probing => {
+                claim.conflicting_hw_addr = Some(sender_hw_addr);
+                false
+            }
+            ClaimState::Bound => {
+                events::publish(StackEvent::AddressConflict {
+                    addr: sender_addr,
+                    hw_addr: sender_hw_addr,
+                });
+                // Keep defending the address(RFC 5227 2.4 (c)), but with at most one announcement per DEFEND_INTERVAL
+                // so that two hosts fighting over an address don't flood the link.
+                let defend = match claim.last_defended {
+                    Some(last_defended) => now.duration_since(last_defended) >= DEFEND_INTERVAL,
+                    None => true,
+                };
+                if defend {
+                    claim.last_defended = Some(now);
+                }
+                defend
+            }
+            ClaimState::Failed => false,
+        }
+    }
+}
+
+// Claims `addr` for the stack: probes the link to make sure nobody else is using it and then announces it.
+// Blocks till the address is claimed, which takes a few seconds.
+pub fn claim(addr: ProtocolAddr, hw_addr: HwAddr, writer: &ChannelWriter) -> Result<(), String> {
+    run_claim(&CLAIMS, addr, hw_addr, writer, thread::sleep)
+}
+
+// The claim itself, waiting with `sleep` between the probes and announcements.
+fn run_claim(
+    claims: &Mutex<Claims>,
+    addr: ProtocolAddr,
+    hw_addr: HwAddr,
+    writer: &ChannelWriter,
+    mut sleep: impl FnMut(Duration),
+) -> Result<(), String> {
+    claims.lock().unwrap().insert(addr, ClaimState::Probing);
+
+    sleep(random_duration(Duration::from_secs(0), PROBE_WAIT));
+    for probe_num in 0..PROBE_NUM {
+        send(writer, ARP::make_probe(addr, &hw_addr))?;
+        if probe_num == PROBE_NUM - 1 {
+            sleep(ANNOUNCE_WAIT);
+        } else {
+            sleep(random_duration(PROBE_MIN, PROBE_MAX));
+        }
+        let conflict = claims.lock().unwrap().conflict(&addr);
+        if let Some(conflicting_hw_addr) = conflict {
+            claims.lock().unwrap().set_state(&addr, ClaimState::Failed);
+            return Err(format!(
+                "Address {} is already in use by {}",
+                Ipv4Addr::from(addr),
+                net_util::hw_addr_to_string(&conflicting_hw_addr)
+            ));
+        }
+    }
+
+    claims.lock().unwrap().set_state(&addr, ClaimState::Bound);
+    for announce_num in 0..ANNOUNCE_NUM {
+        send(writer, ARP::make_announcement(addr, &hw_addr))?;
+        if announce_num < ANNOUNCE_NUM - 1 {
+            sleep(ANNOUNCE_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+// Announces an address assigned while the stack runs, without probing for it first, and defends it from then on.
+pub fn announce(addr: ProtocolAddr, hw_addr: HwAddr, writer: &ChannelWriter) -> Result<(), String> {
+    CLAIMS.lock().unwrap().insert(addr, ClaimState::Bound);
+    send(writer, ARP::make_announcement(addr, &hw_addr))
+}
+
+// Stops defending an address which is no longer ours.
+pub fn release(addr: &ProtocolAddr) {
+    CLAIMS.lock().unwrap().remove(addr);
+}
+
+// Whether `addr` is not ours to use, either because it is still being probed or because it was lost to a conflict.
+pub fn is_tentative(addr: &ProtocolAddr) -> bool {
+    CLAIMS.lock().unwrap().is_tentative(addr)
+}
+
+// Looks for conflicts in every ARP packet received(RFC 5227 2.1.1 and 2.4).
+pub fn inspect_packet(
+    eth: &Ethernet,
+    sender_addr: ProtocolAddr,
+    sender_hw_addr: HwAddr,
+    target_addr: ProtocolAddr,
+) {
+    if sender_hw_addr == eth.hw_address() {
+        return;
+    }
+    let defend =
+        CLAIMS
+            .lock()
+            .unwrap()
+            .inspect(sender_addr, sender_hw_addr, target_addr, Instant::now());
+    if defend {
+        let announcement = ARP::make_announcement(sender_addr, &eth.hw_address());
+        let _ = eth.eth_layer_write(announcement);
+    }
+}
+
+fn send(writer: &ChannelWriter, packet: Box<ARP>) -> Result<(), String> {
+    writer
+        .send(packet)
+        .map_err(|_| "Failed to write to the eth chan".to_string())
+}
+
+fn random_duration(min: Duration, max: Duration) -> Duration {
+    let millis = rand::thread_rng().gen_range(min.as_millis() as u64, max.as_millis() as u64 + 1);
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    const STACK: HwAddr = [2, 0, 0, 0, 0, 1];
+    const OTHER_HOST: HwAddr = [2, 0, 0, 0, 0, 2];
+
+    #[test]
+    fn test_claim() {
+        let claims = Mutex::new(Claims::default());
+        let addr = [192, 168, 83, 2];
+        let (writer, reader) = channel();
+        run_claim(&claims, addr, STACK, &writer, |_| {}).unwrap();
+        assert!(!claims.lock().unwrap().is_tentative(&addr));
+        // The probes, then the announcements
+        assert_eq!(
+            reader.try_iter().count(),
+            (PROBE_NUM + ANNOUNCE_NUM) as usize
+        );
+    }
+
+    #[test]
+    fn test_probe_conflict() {
+        let claims = Mutex::new(Claims::default());
+        let addr = [192, 168, 83, 3];
+        let (writer, reader) = channel();
+        let mut sleeps = 0;
+        let result = run_claim(&claims, addr, STACK, &writer, |_| {
+            let mut claims = claims.lock().unwrap();
+            assert!(claims.is_tentative(&addr));
+            // Another host probes for the same address after our first probe
+            sleeps += 1;
+            if sleeps == 2 {
+                assert!(!claims.inspect(UNSPECIFIED_ADDR, OTHER_HOST, addr, Instant::now()));
+            }
+        });
+        assert_eq!(
+            result.unwrap_err(),
+            "Address 192.168.83.3 is already in use by 02:00:00:00:00:02"
+        );
+        // Nothing was announced, and the address stays unusable
+        assert_eq!(reader.try_iter().count(), 1);
+        assert!(claims.lock().unwrap().is_tentative(&addr));
+        assert_eq!(
+            claims.lock().unwrap().claims[&addr].state,
+            ClaimState::Failed
+        );
+    }
+
+    #[test]
+    fn test_defend_bound_address() {
+        let mut claims = Claims::default();
+        let addr = [192, 168, 83, 4];
+        let events = events::subscribe();
+        claims.insert(addr, ClaimState::Bound);
+        assert!(!claims.is_tentative(&addr));
+
+        let now = Instant::now();
+        assert!(claims.inspect(addr, OTHER_HOST, [192, 168, 83, 1], now));
+        // At most one defence per DEFEND_INTERVAL, the conflicts are still reported
+        assert!(!claims.inspect(
+            addr,
+            OTHER_HOST,
+            [192, 168, 83, 1],
+            now + Duration::from_secs(1)
+        ));
+        assert!(claims.inspect(addr, OTHER_HOST, [192, 168, 83, 1], now + DEFEND_INTERVAL));
+        let conflict = StackEvent::AddressConflict {
+            addr,
+            hw_addr: OTHER_HOST,
+        };
+        assert_eq!(
+            events.try_iter().filter(|event| *event == conflict).count(),
+            3
+        );
+
+        // Probes for a bound address aren't conflicts
+        assert!(!claims.inspect(UNSPECIFIED_ADDR, OTHER_HOST, addr, now));
+        assert_eq!(claims.conflict(&addr), None);
+    }
+
+    #[test]
+    fn test_release() {
+        let addr = [192, 168, 83, 5];
+        let (writer, reader) = channel();
+        announce(addr, STACK, &writer).unwrap();
+        assert_eq!(reader.try_iter().count(), 1);
+        assert!(CLAIMS.lock().unwrap().claims.contains_key(&addr));
+        release(&addr);
+        assert!(!CLAIMS.lock().unwrap().claims.contains_key(&addr));
+        assert!(!is_tentative(&addr));
+
+        // Released addresses are no longer defended
+        let mut claims = Claims::default();
+        claims.insert(addr, ClaimState::Bound);
+        claims.remove(&addr);
+        assert!(!claims.inspect(addr, OTHER_HOST, [192, 168, 83, 1], Instant::now()));
+    }
+}
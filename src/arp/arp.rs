@@ -1,244 +1,359 @@
+use crate::arp::neighbor::{NeighborTable, NEIGHBOR_TABLE};
+use crate::arp::packet::{ArpOp, ArpPacket};
+use crate::arp::policy::{self, ArpPolicy, DropReason};
+use crate::arp::{acd, arping, proxy};
 use crate::ethernet;
-use std::convert::TryInto;
-
-const ARP_REPLY_OPCODE: u16 = 2u16;
-const ARP_REQ_OPCODE: u16 = 1u16;
+use crate::ipv4::address;
 
 const BROADCAST_ADDR: [u8; 6] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+// Sender address of ARP probes(RFC 5227)
+const UNSPECIFIED_ADDR: ethernet::ProtocolAddr = [0, 0, 0, 0];
 
 pub struct ARP {
-    data: Vec<u8>,
-    kind: ARPKind,
-}
-
-enum ARPKind {
-    Req,
-    Reply,
+    packet: ArpPacket,
+    // Set when the packet's link layer destination is known up front, ex: broadcasts and replies.
+    link_dst: Option<ethernet::HwAddr>,
 }
 
 impl ethernet::LinkLayerWritable for ARP {
     fn data(&self) -> Vec<u8> {
-        self.data.to_owned()
+        self.packet.to_bytes()
     }
 
     fn spa(&self) -> ethernet::ProtocolAddr {
-        self.spa().try_into().unwrap()
+        self.packet.spa
     }
 
     fn tpa(&self) -> ethernet::ProtocolAddr {
-        self.tpa().try_into().unwrap()
+        self.packet.tpa
     }
 
     fn ether_type(&self) -> [u8; 2] {
         (ethernet::ETH_ARP as u16).to_be_bytes()
     }
+
+    fn dst_hw_addr(&self) -> Option<ethernet::HwAddr> {
+        self.link_dst
+    }
 }
 
 // Reference: https://en.wikipedia.org/wiki/Address_Resolution_Protocol
 impl ARP {
     pub fn process_packet(eth: &ethernet::Ethernet, frame: ethernet::EthernetFrame) {
-        let received_arp_packet = ARP::build_packet(frame.payload().to_owned());
-        match received_arp_packet.kind {
-            ARPKind::Reply => {
-                let (protocol_addr, hw_addr) = received_arp_packet.parse_for_addr();
-                eth.update_arp_cache(protocol_addr, hw_addr);
+        let packet = match ArpPacket::parse(frame.payload()) {
+            Ok(packet) => packet,
+            Err(_) => {
+                policy::record_drop(DropReason::Malformed);
+                return;
+            }
+        };
+        // Requests are rate limited before anything else is done with them, so that a flood of them costs next to
+        // nothing.
+        if packet.op == ArpOp::Request && !policy::allow_request() {
+            return;
+        }
+        let awaited_by_arping = packet.op == ArpOp::Reply && arping::awaiting_reply(&packet.spa);
+        let verdict = ARP::validate(
+            &packet,
+            frame.src(),
+            &NEIGHBOR_TABLE.lock().unwrap(),
+            awaited_by_arping,
+            &policy::policy(),
+        );
+        if verdict == Err(DropReason::Spoofed) {
+            policy::record_drop(DropReason::Spoofed);
+            return;
+        }
+        // Conflicts with our own addresses count whatever the verdict, ex: an unsolicited reply claiming one of them.
+        acd::inspect_packet(eth, packet.spa, packet.sha, packet.tpa);
+        if let Err(reason) = verdict {
+            policy::record_drop(reason);
+            return;
+        }
+        match packet.op {
+            ArpOp::Reply => {
+                let solicited = NEIGHBOR_TABLE.lock().unwrap().awaiting_reply(&packet.spa);
+                // Replies to arping's requests are only reported back to it, they don't go into the neighbor table
+                // unless we were resolving the address anyway.
+                if arping::deliver_reply(packet.spa, packet.sha) && !solicited {
+                    return;
+                }
+                eth.update_arp_cache(packet.spa, packet.sha, true)
             }
-            ARPKind::Req => {
-                let resp = received_arp_packet.build_response(eth.address());
-                eth.eth_layer_write(Box::new(resp)).unwrap();
+            ArpOp::Request => {
+                let answer_addr = match ARP::answer_addr(eth.name(), &packet) {
+                    Some(addr) => addr,
+                    None => return,
+                };
+                // The requester obviously wants to talk to us, remember its address too, but only as STALE since
+                // a request proves nothing about its reachability.
+                if packet.spa != UNSPECIFIED_ADDR && policy::policy().learn_from_requests {
+                    eth.update_arp_cache(packet.spa, packet.sha, false);
+                }
+                if !policy::allow_reply() {
+                    return;
+                }
+                let resp = ARP::make_reply(&packet, answer_addr, eth.address());
+                eth.eth_layer_write(resp).unwrap();
             }
         }
     }
 
-    fn build_packet(data: Vec<u8>) -> ARP {
-        let arp_kind = Self::arp_kind_from_bytes(&data);
-        ARP {
-            data: data,
-            kind: arp_kind,
+    // Checks a received packet against `policy` before anything is learnt from it. `neighbors` tells which replies
+    // were asked for, and which hw addresses are known for sure. Replies to arping's requests were asked for too.
+    fn validate(
+        packet: &ArpPacket,
+        frame_src: ethernet::HwAddr,
+        neighbors: &NeighborTable,
+        awaited_by_arping: bool,
+        policy: &ArpPolicy,
+    ) -> Result<(), DropReason> {
+        // The sender mustn't pretend to be someone else.
+        if packet.sha != frame_src {
+            return Err(DropReason::Spoofed);
         }
-    }
-
-    fn parse_for_addr(&self) -> (ethernet::ProtocolAddr, ethernet::HwAddr) {
-        (
-            self.spa().try_into().unwrap(),
-            self.sha().try_into().unwrap(),
-        )
-    }
-
-    fn arp_kind_from_bytes(bytes: &[u8]) -> ARPKind {
-        let op_code: [u8; 2] = bytes[6..8].try_into().unwrap();
-        if op_code == ARP_REPLY_OPCODE.to_be_bytes() {
-            ARPKind::Reply
-        } else if op_code == ARP_REQ_OPCODE.to_be_bytes() {
-            ARPKind::Req
-        } else {
-            unimplemented!()
+        // Nor take over an address from a neighbor we know is there.
+        if neighbors.conflicts(&packet.spa, &packet.sha) {
+            return Err(DropReason::Conflicting);
         }
+        if packet.op == ArpOp::Reply
+            && !neighbors.awaiting_reply(&packet.spa)
+            && !awaited_by_arping
+            && !policy.accept_unsolicited_replies
+        {
+            return Err(DropReason::Unsolicited);
+        }
+        Ok(())
     }
 
-    fn operation(&self) -> ARPKind {
-        let op = self.op();
-        if op == (ARP_REPLY_OPCODE.to_be_bytes()) {
-            ARPKind::Reply
-        } else if op == (ARP_REQ_OPCODE.to_be_bytes()) {
-            ARPKind::Req
-        } else {
-            unimplemented!()
+    // We only answer for the addresses `interface` owns, and for the proxied subnets when proxy ARP is configured.
+    // Returns the address to answer with, which comes from our own configuration rather than from the request.
+    fn answer_addr(interface: &str, request: &ArpPacket) -> Option<ethernet::ProtocolAddr> {
+        // Don't answer for an address we haven't finished claiming yet.
+        if acd::is_tentative(&request.tpa) {
+            return None;
+        }
+        if address::is_assigned(interface, &request.tpa) {
+            return Some(request.tpa);
+        }
+        // Gratuitous ARPs for a proxied address come from the host actually owning it, leave them alone.
+        if request.spa != request.tpa && proxy::is_proxied(&request.tpa) {
+            return Some(request.tpa);
         }
+        None
     }
 
-    pub fn make_req_for_addr(
-        target_addr: ethernet::ProtocolAddr,
-        sender_hw_addr: &[u8],
+    // `sender_addr` is at `sender_hw_addr`, sent straight back to whoever asked.
+    fn make_reply(
+        request: &ArpPacket,
+        sender_addr: ethernet::ProtocolAddr,
+        sender_hw_addr: ethernet::HwAddr,
     ) -> Box<ARP> {
-        let mut payload: Vec<u8> = Vec::new();
-        payload.extend_from_slice((1u16).to_be_bytes().as_ref());
-        payload.extend_from_slice((ethernet::ETH_IPV4 as u16).to_be_bytes().as_ref());
-        payload.push(6u8);
-        payload.push(4u8);
-        payload.extend_from_slice(ARP_REQ_OPCODE.to_be_bytes().as_ref());
-        payload.extend_from_slice(sender_hw_addr);
-        payload.extend_from_slice(&ethernet::IP_ADDR);
-        payload.extend_from_slice(&BROADCAST_ADDR);
-        payload.extend_from_slice(&target_addr);
         Box::new(ARP {
-            data: payload,
-            kind: ARPKind::Req,
+            packet: ArpPacket {
+                op: ArpOp::Reply,
+                sha: sender_hw_addr,
+                spa: sender_addr,
+                tha: request.sha,
+                tpa: request.spa,
+            },
+            link_dst: Some(request.sha),
         })
     }
 
-    fn handle_packet(data: Vec<u8>, eth_addr: [u8; 6]) -> ARP {
-        let req = ARP::build_request(data);
-        req.build_response(eth_addr)
+    // Who has `target_addr`? Tell `sender_addr`.
+    pub fn make_req_for_addr(
+        target_addr: ethernet::ProtocolAddr,
+        sender_addr: ethernet::ProtocolAddr,
+        sender_hw_addr: &ethernet::HwAddr,
+    ) -> Box<ARP> {
+        ARP::make_request(*sender_hw_addr, sender_addr, BROADCAST_ADDR, target_addr)
     }
 
-    fn build_request(data: Vec<u8>) -> ARP {
-        ARP {
-            data: data,
-            kind: ARPKind::Req,
-        }
+    // ARP probe(RFC 5227 2.1.1): asks whether anyone else is using `addr`, without claiming it ourselves.
+    pub fn make_probe(addr: ethernet::ProtocolAddr, sender_hw_addr: &ethernet::HwAddr) -> Box<ARP> {
+        ARP::make_request(*sender_hw_addr, UNSPECIFIED_ADDR, [0u8; 6], addr)
     }
 
-    fn build_response(&self, eth_addr: [u8; 6]) -> ARP {
-        let mut resp = ARP {
-            data: self.data.clone(),
-            kind: ARPKind::Reply,
-        };
-        resp.fill_values(eth_addr);
-        resp
+    // ARP announcement(gratuitous ARP, RFC 5227 2.3): tells everyone on the link that `addr` is ours.
+    pub fn make_announcement(
+        addr: ethernet::ProtocolAddr,
+        sender_hw_addr: &ethernet::HwAddr,
+    ) -> Box<ARP> {
+        ARP::make_request(*sender_hw_addr, addr, [0u8; 6], addr)
     }
 
-    fn fill_values(&mut self, eth_addr: [u8; 6]) {
-        self.set_op_code();
-        self.set_hw_addrs(eth_addr);
-        self.set_protocol_addrs();
+    // Requests are always broadcast.
+    fn make_request(
+        sha: ethernet::HwAddr,
+        spa: ethernet::ProtocolAddr,
+        tha: ethernet::HwAddr,
+        tpa: ethernet::ProtocolAddr,
+    ) -> Box<ARP> {
+        Box::new(ARP {
+            packet: ArpPacket {
+                op: ArpOp::Request,
+                sha,
+                spa,
+                tha,
+                tpa,
+            },
+            link_dst: Some(BROADCAST_ADDR),
+        })
     }
+}
 
-    fn set_protocol_addrs(&mut self) {
-        // TODO: Maybe explicitly set an IP address for the user space eth device?
-        // For now, the program assumes that its IP is whatever is seen in the ARP request's target ip(TPA attribute)
-        // Swap the target and sender ip address for the response
-        unsafe {
-            let sender_ip_start = self.data.as_mut_ptr().add(self.sha_boundary());
-            let target_ip_start = self.data.as_mut_ptr().add(self.tha_boundary());
-            let addr_length = self.pln();
-
-            std::ptr::swap_nonoverlapping(sender_ip_start, target_ip_start, addr_length as usize);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arp::neighbor::NeighborConfig;
+    use crate::ipv4::address::InterfaceAddr;
+    use crate::net_util::Cidr;
+    use std::time::Instant;
+
+    fn request(spa: ethernet::ProtocolAddr, tpa: ethernet::ProtocolAddr) -> ArpPacket {
+        ArpPacket {
+            op: ArpOp::Request,
+            sha: [0x02, 0, 0, 0, 0, 1],
+            spa,
+            tha: [0; 6],
+            tpa,
         }
     }
-    fn set_hw_addrs(&mut self, eth_addr: [u8; 6]) {
-        // Set the request's sender address as the target address for the reply.
-        let sender_hw = self.sha().to_owned();
-        self.data.splice(
-            self.spa_boundary()..self.tha_boundary(),
-            sender_hw.iter().cloned(),
-        );
 
-        // Set the virtual ethernet device's hw address as the sender addr for the reply.
-        self.data.splice(
-            self.op_boundary()..self.sha_boundary(),
-            eth_addr.iter().cloned(),
-        );
-    }
+    #[test]
+    fn test_validate() {
+        let mut neighbors = NeighborTable::new(NeighborConfig::default());
+        let policy = ArpPolicy::default();
+        let now = Instant::now();
+        let peer_hw = [0x02, 0, 0, 0, 0, 1];
+        let reply = ArpPacket {
+            op: ArpOp::Reply,
+            sha: peer_hw,
+            spa: [10, 0, 0, 9],
+            tha: [0x02, 0, 0, 0, 0, 2],
+            tpa: [10, 0, 0, 2],
+        };
 
-    fn set_op_code(&mut self) {
-        // Set reply as the op code.
-        self.data.splice(
-            self.pln_boundary()..self.op_boundary(),
-            ARP_REPLY_OPCODE.to_be_bytes().iter().cloned(),
+        assert_eq!(
+            ARP::validate(&reply, [0x02, 0, 0, 0, 0, 3], &neighbors, false, &policy),
+            Err(DropReason::Spoofed)
+        );
+        // Nobody asked for it
+        assert_eq!(
+            ARP::validate(&reply, peer_hw, &neighbors, false, &policy),
+            Err(DropReason::Unsolicited)
+        );
+        let permissive = ArpPolicy {
+            accept_unsolicited_replies: true,
+            ..policy
+        };
+        assert_eq!(
+            ARP::validate(&reply, peer_hw, &neighbors, false, &permissive),
+            Ok(())
+        );
+        // Nor arping
+        assert_eq!(
+            ARP::validate(&reply, peer_hw, &neighbors, true, &policy),
+            Ok(())
+        );
+        // Once we are resolving the address
+        neighbors.lookup(reply.spa, now);
+        neighbors.run_timers(now, |_| true);
+        assert_eq!(
+            ARP::validate(&reply, peer_hw, &neighbors, false, &policy),
+            Ok(())
         );
-    }
-}
-
-// Packet accessors and boundaries
-impl ARP {
-    pub fn hrd(&self) -> [u8; 2] {
-        let mut hrd = [0; 2];
-        hrd.copy_from_slice(&self.data[0..2]);
-        hrd
-    }
-
-    pub fn pro(&self) -> [u8; 2] {
-        let mut pro = [0; 2];
-        pro.copy_from_slice(&self.data[2..4]);
-        pro
-    }
-
-    pub fn hln(&self) -> u8 {
-        let mut hln = [0; 1];
-        hln.copy_from_slice(&self.data[4..5]);
-        hln[0]
-    }
-
-    pub fn pln(&self) -> u8 {
-        let mut pln = [0; 1];
-        pln.copy_from_slice(&self.data[5..6]);
-        pln[0]
-    }
-
-    pub fn op(&self) -> [u8; 2] {
-        let mut op = [0; 2];
-        op.copy_from_slice(&self.data[6..8]);
-        op
-    }
-
-    pub fn sha(&self) -> &[u8] {
-        &self.data[8..14]
-    }
-
-    pub fn spa(&self) -> &[u8] {
-        &self.data[14..18]
-    }
-
-    pub fn tha(&self) -> &[u8] {
-        &self.data[self.spa_boundary()..self.tha_boundary()]
-    }
-
-    pub fn tpa(&self) -> &[u8] {
-        &self.data[self.tha_boundary()..self.tpa_boundary()]
-    }
-
-    fn pln_boundary(&self) -> usize {
-        6
-    }
-
-    fn op_boundary(&self) -> usize {
-        8
     }
 
-    fn sha_boundary(&self) -> usize {
-        self.hln() as usize + self.op_boundary()
-    }
+    #[test]
+    fn test_validate_rejects_conflicting_overwrites() {
+        let mut neighbors = NeighborTable::new(NeighborConfig::default());
+        let policy = ArpPolicy {
+            accept_unsolicited_replies: true,
+            ..ArpPolicy::default()
+        };
+        let now = Instant::now();
+        let attacker_hw = [0x02, 0, 0, 0, 0, 6];
+        let forged = |op, spa| ArpPacket {
+            op,
+            sha: attacker_hw,
+            spa,
+            tha: [0; 6],
+            tpa: [10, 0, 0, 2],
+        };
 
-    fn spa_boundary(&self) -> usize {
-        self.sha_boundary() as usize + self.pln() as usize
+        neighbors.update([10, 0, 0, 7], [0x02, 0, 0, 0, 0, 7], true, now);
+        neighbors.add_permanent([10, 0, 0, 8], [0x02, 0, 0, 0, 0, 8], now);
+        for spa in &[[10, 0, 0, 7], [10, 0, 0, 8]] {
+            for op in &[ArpOp::Reply, ArpOp::Request] {
+                assert_eq!(
+                    ARP::validate(&forged(*op, *spa), attacker_hw, &neighbors, false, &policy),
+                    Err(DropReason::Conflicting)
+                );
+            }
+        }
+        // Unconfirmed entries can move, ex: a host which got a new NIC
+        neighbors.update([10, 0, 0, 7], [0x02, 0, 0, 0, 0, 17], false, now);
+        assert_eq!(
+            ARP::validate(
+                &forged(ArpOp::Request, [10, 0, 0, 7]),
+                attacker_hw,
+                &neighbors,
+                false,
+                &policy
+            ),
+            Ok(())
+        );
     }
 
-    fn tha_boundary(&self) -> usize {
-        self.spa_boundary() as usize + self.hln() as usize
-    }
+    #[test]
+    fn test_answer_addr() {
+        address::add(InterfaceAddr::parse("192.168.84.2/24").unwrap()).unwrap();
+        let answer = |spa, tpa| ARP::answer_addr(ethernet::INTERFACE_NAME, &request(spa, tpa));
+        assert_eq!(
+            answer([192, 168, 84, 1], [192, 168, 84, 2]),
+            Some([192, 168, 84, 2])
+        );
+        // Not on another interface
+        assert_eq!(
+            ARP::answer_addr("eth1", &request([192, 168, 84, 1], [192, 168, 84, 2])),
+            None
+        );
+        // Nobody configured the address
+        assert_eq!(answer([192, 168, 84, 1], [192, 168, 84, 3]), None);
 
-    fn tpa_boundary(&self) -> usize {
-        self.tha_boundary() + self.pln() as usize
+        proxy::add(Cidr::parse("192.168.85.0/24").unwrap());
+        assert_eq!(
+            answer([192, 168, 85, 1], [192, 168, 85, 7]),
+            Some([192, 168, 85, 7])
+        );
+        // Gratuitous ARPs from the host owning a proxied address
+        assert_eq!(answer([192, 168, 85, 7], [192, 168, 85, 7]), None);
+        assert!(proxy::remove(&Cidr::parse("192.168.85.0/24").unwrap()));
+        assert_eq!(answer([192, 168, 85, 1], [192, 168, 85, 7]), None);
+    }
+
+    #[test]
+    fn test_make_reply() {
+        let request = ArpPacket {
+            op: ArpOp::Request,
+            sha: [0x02, 0, 0, 0, 0, 1],
+            spa: [10, 0, 0, 1],
+            tha: [0; 6],
+            tpa: [10, 0, 0, 2],
+        };
+        let reply = ARP::make_reply(&request, [10, 0, 0, 2], [0x02, 0, 0, 0, 0, 2]);
+        assert_eq!(
+            reply.packet,
+            ArpPacket {
+                op: ArpOp::Reply,
+                sha: [0x02, 0, 0, 0, 0, 2],
+                spa: [10, 0, 0, 2],
+                tha: [0x02, 0, 0, 0, 0, 1],
+                tpa: [10, 0, 0, 1],
+            }
+        );
+        assert_eq!(reply.link_dst, Some([0x02, 0, 0, 0, 0, 1]));
     }
 }
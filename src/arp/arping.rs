@@ -0,0 +1,230 @@
+// arping style L2 reachability checks: send ARP requests for an address and collect whoever answers.
+// Reference: https://man7.org/linux/man-pages/man8/arping.8.html
+
+use crate::arp::ARP;
+use crate::ethernet::{self, HwAddr, ProtocolAddr};
+use crate::ipv4::{address, route};
+use lazy_static::lazy_static;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const UNSPECIFIED_ADDR: ProtocolAddr = [0, 0, 0, 0];
+
+lazy_static! {
+    static ref SESSIONS: Mutex<Vec<Session>> = Mutex::new(Vec::new());
+}
+
+struct Session {
+    id: u64,
+    target_addr: ProtocolAddr,
+    replies: Sender<(HwAddr, Instant)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArpingOptions {
+    // Number of requests to send.
+    pub count: u32,
+    // Time between two requests.
+    pub interval: Duration,
+    // Time to wait for late replies after the last request.
+    pub timeout: Duration,
+    // Duplicate address detection mode(`arping -D`): the requests are sent from 0.0.0.0, so that they don't update
+    // anyone's ARP cache, and we stop at the first reply.
+    pub duplicate_detection: bool,
+    // Sender address of the requests, the stack's address when not set.
+    pub source: Option<Ipv4Addr>,
+}
+
+impl Default for ArpingOptions {
+    fn default() -> Self {
+        ArpingOptions {
+            count: 3,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            duplicate_detection: false,
+            source: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArpingReply {
+    pub hw_addr: HwAddr,
+    // Time since the most recent request was sent.
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArpingResult {
+    pub target: Ipv4Addr,
+    pub sent: u32,
+    pub replies: Vec<ArpingReply>,
+}
+
+impl ArpingResult {
+    // Every hw address which answered, in the order they first did.
+    pub fn responders(&self) -> Vec<HwAddr> {
+        let mut responders: Vec<HwAddr> = Vec::new();
+        for reply in &self.replies {
+            if !responders.contains(&reply.hw_addr) {
+                responders.push(reply.hw_addr);
+            }
+        }
+        responders
+    }
+
+    // More than one host claims the address.
+    pub fn has_duplicates(&self) -> bool {
+        self.responders().len() > 1
+    }
+}
+
+pub fn arping(target_addr: ProtocolAddr, options: ArpingOptions) -> Result<ArpingResult, String> {
+    // The request goes out on the link the target is routed through, eth0 otherwise.
+    let interface = match route::lookup(&target_addr) {
+        Some(route) if ethernet::exists(&route.interface) => route.interface,
+        _ => ethernet::INTERFACE_NAME.to_string(),
+    };
+    let (writer, hw_addr) = match ethernet::link(&interface) {
+        Some(link) => link,
+        None => return Err("The stack is not running".to_string()),
+    };
+    let sender_addr = if options.duplicate_detection {
+        UNSPECIFIED_ADDR
+    } else {
+        match options.source {
+            Some(source) => source.octets(),
+            None => address::primary_addr(&interface, &target_addr)
+                .ok_or_else(|| format!("No address on {}", interface))?,
+        }
+    };
+
+    let (id, replies_rx) = register(target_addr);
+    let mut result = ArpingResult {
+        target: Ipv4Addr::from(target_addr),
+        sent: 0,
+        replies: Vec::new(),
+    };
+    for seq in 0..options.count {
+        let request = ARP::make_req_for_addr(target_addr, sender_addr, &hw_addr);
+        if writer.send(request).is_err() {
+            unregister(id);
+            return Err("Failed to write to the eth chan".to_string());
+        }
+        let sent_at = Instant::now();
+        result.sent += 1;
+
+        let wait = if seq == options.count - 1 {
+            options.timeout
+        } else {
+            options.interval
+        };
+        collect_replies(&replies_rx, sent_at, wait, &mut result);
+        if options.duplicate_detection && !result.replies.is_empty() {
+            break;
+        }
+    }
+    unregister(id);
+    Ok(result)
+}
+
+fn collect_replies(
+    replies_rx: &Receiver<(HwAddr, Instant)>,
+    sent_at: Instant,
+    wait: Duration,
+    result: &mut ArpingResult,
+) {
+    let deadline = sent_at + wait;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match replies_rx.recv_timeout(remaining) {
+            Ok((hw_addr, received_at)) => result.replies.push(ArpingReply {
+                hw_addr,
+                latency: received_at.duration_since(sent_at),
+            }),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn register(target_addr: ProtocolAddr) -> (u64, Receiver<(HwAddr, Instant)>) {
+    let (tx, rx) = channel();
+    let mut sessions = SESSIONS.lock().unwrap();
+    let id = sessions
+        .iter()
+        .map(|session| session.id + 1)
+        .max()
+        .unwrap_or(0);
+    sessions.push(Session {
+        id,
+        target_addr,
+        replies: tx,
+    });
+    (id, rx)
+}
+
+fn unregister(id: u64) {
+    SESSIONS.lock().unwrap().retain(|session| session.id != id);
+}
+
+// Whether an arping session is waiting for replies from `addr`.
+pub fn awaiting_reply(addr: &ProtocolAddr) -> bool {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|session| session.target_addr == *addr)
+}
+
+// Hands a received ARP reply over to the arping sessions waiting on its sender. Returns whether anyone was.
+pub fn deliver_reply(sender_addr: ProtocolAddr, sender_hw_addr: HwAddr) -> bool {
+    let now = Instant::now();
+    let sessions = SESSIONS.lock().unwrap();
+    let mut delivered = false;
+    for session in sessions.iter() {
+        if session.target_addr == sender_addr {
+            delivered |= session.replies.send((sender_hw_addr, now)).is_ok();
+        }
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_duplicates() {
+        let reply = |last_byte: u8| ArpingReply {
+            hw_addr: [0x02, 0, 0, 0, 0, last_byte],
+            latency: Duration::from_millis(1),
+        };
+        let mut result = ArpingResult {
+            target: Ipv4Addr::new(10, 0, 0, 1),
+            sent: 2,
+            replies: vec![reply(1), reply(1)],
+        };
+        assert!(!result.has_duplicates());
+
+        result.replies.push(reply(2));
+        assert!(result.has_duplicates());
+        assert_eq!(
+            result.responders(),
+            vec![[0x02, 0, 0, 0, 0, 1], [0x02, 0, 0, 0, 0, 2]]
+        );
+    }
+
+    #[test]
+    fn test_delivery() {
+        let target_addr = [10, 0, 9, 1];
+        let (id, rx) = register(target_addr);
+        assert!(!deliver_reply([10, 0, 9, 2], [0x02, 0, 0, 0, 0, 2]));
+        assert!(deliver_reply(target_addr, [0x02, 0, 0, 0, 0, 1]));
+        assert_eq!(rx.try_recv().unwrap().0, [0x02, 0, 0, 0, 0, 1]);
+
+        unregister(id);
+        assert!(!deliver_reply(target_addr, [0x02, 0, 0, 0, 0, 1]));
+    }
+}
@@ -1,2 +1,8 @@
+pub mod acd;
 mod arp;
+pub mod arping;
+pub mod neighbor;
+mod packet;
+pub mod policy;
+pub mod proxy;
 pub use arp::ARP;
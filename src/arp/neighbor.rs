@@ -0,0 +1,641 @@
+// Neighbor table, loosely following the linux neighbour subsystem(NUD states).
+// Reference: https://man7.org/linux/man-pages/man7/arp.7.html
+
+use crate::ethernet::{HwAddr, LinkLayerWritable, ProtocolAddr};
+use crate::net_util;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    pub static ref NEIGHBOR_TABLE: Mutex<NeighborTable> =
+        Mutex::new(NeighborTable::new(NeighborConfig::default()));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NeighborState {
+    // Address resolution is in progress, the hw address is not known yet.
+    Incomplete,
+    // The neighbor was confirmed to be reachable within the last `reachable_time`.
+    Reachable,
+    // The hw address is known but hasn't been confirmed recently.
+    Stale,
+    // A stale entry was used, waiting for `delay_first_probe_time` before probing.
+    Delay,
+    // Unicast ARP requests are being sent to confirm the neighbor.
+    Probe,
+    // Resolution or re-probing failed, the entry is evicted on the next timer run.
+    Failed,
+    // Static entry added by the user, it is never aged out or overwritten by received ARP packets.
+    Permanent,
+}
+
+impl fmt::Display for NeighborState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            NeighborState::Incomplete => "INCOMPLETE",
+            NeighborState::Reachable => "REACHABLE",
+            NeighborState::Stale => "STALE",
+            NeighborState::Delay => "DELAY",
+            NeighborState::Probe => "PROBE",
+            NeighborState::Failed => "FAILED",
+            NeighborState::Permanent => "PERMANENT",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+// A snapshot of a neighbor table entry, as shown by `ip neigh`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborEntry {
+    pub addr: Ipv4Addr,
+    pub hw_addr: Option<HwAddr>,
+    pub state: NeighborState,
+    // Time since the entry last changed its state.
+    pub age: Duration,
+}
+
+impl fmt::Display for NeighborEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if let Some(hw_addr) = self.hw_addr {
+            write!(f, " lladdr {}", net_util::hw_addr_to_string(&hw_addr))?;
+        }
+        write!(f, " {} age {}s", self.state, self.age.as_secs())
+    }
+}
+
+// The defaults are the same as the linux defaults(/proc/sys/net/ipv4/neigh/default/*).
+#[derive(Debug, Clone, Copy)]
+pub struct NeighborConfig {
+    pub base_reachable_time: Duration,
+    pub delay_first_probe_time: Duration,
+    pub retrans_time: Duration,
+    pub gc_stale_time: Duration,
+    pub ucast_probes: u32,
+    pub mcast_probes: u32,
+    // Max number of packets queued per neighbor while its address is being resolved.
+    pub unres_qlen: usize,
+}
+
+// A packet waiting for its next hop's address to get resolved.
+pub type PendingPacket = Box<dyn LinkLayerWritable + Send>;
+
+// Incomplete entries back off exponentially between retransmits, but never wait longer than this.
+const MAX_RETRANS_BACKOFF: u32 = 8;
+
+impl Default for NeighborConfig {
+    fn default() -> Self {
+        NeighborConfig {
+            base_reachable_time: Duration::from_secs(30),
+            delay_first_probe_time: Duration::from_secs(5),
+            retrans_time: Duration::from_secs(1),
+            gc_stale_time: Duration::from_secs(60),
+            ucast_probes: 3,
+            mcast_probes: 3,
+            unres_qlen: 101,
+        }
+    }
+}
+
+pub struct Neighbor {
+    hw_addr: Option<HwAddr>,
+    state: NeighborState,
+    // Last time the entry changed its state.
+    updated: Instant,
+    // Last time the entry was used to send a packet.
+    used: Instant,
+    // Last time the neighbor was confirmed to be reachable.
+    confirmed: Option<Instant>,
+    probes: u32,
+    next_probe: Instant,
+    pending: VecDeque<PendingPacket>,
+}
+
+// What the link layer has to do after a timer run.
+pub struct TimerOutput {
+    pub actions: Vec<NeighborAction>,
+    // Packets dropped because their next hop couldn't be resolved.
+    pub unreachable: Vec<PendingPacket>,
+    // Packets whose next hop got resolved outside of the ARP path(ex: a static entry was added).
+    pub resolved: Vec<PendingPacket>,
+}
+
+// Things the table needs the link layer to do on its behalf.
+#[derive(Debug, PartialEq)]
+pub enum NeighborAction {
+    // Broadcast an ARP request for the address.
+    Solicit(ProtocolAddr),
+    // Send an ARP request for the address straight to the hw address we already know.
+    Probe(ProtocolAddr, HwAddr),
+}
+
+pub struct NeighborTable {
+    entries: HashMap<ProtocolAddr, Neighbor>,
+    config: NeighborConfig,
+    reachable_time: Duration,
+}
+
+impl Neighbor {
+    fn new(hw_addr: Option<HwAddr>, state: NeighborState, now: Instant) -> Self {
+        Neighbor {
+            hw_addr,
+            state,
+            updated: now,
+            used: now,
+            confirmed: None,
+            probes: 0,
+            next_probe: now,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn set_state(&mut self, state: NeighborState, now: Instant) {
+        self.state = state;
+        self.updated = now;
+    }
+
+    // Whether the entry can be used to send packets right away.
+    fn is_valid(&self) -> bool {
+        match self.state {
+            NeighborState::Reachable
+            | NeighborState::Stale
+            | NeighborState::Delay
+            | NeighborState::Probe
+            | NeighborState::Permanent => true,
+            NeighborState::Incomplete | NeighborState::Failed => false,
+        }
+    }
+}
+
+impl NeighborTable {
+    pub fn new(config: NeighborConfig) -> Self {
+        NeighborTable {
+            entries: HashMap::new(),
+            reachable_time: Self::random_reachable_time(&config),
+            config,
+        }
+    }
+
+    pub fn set_config(&mut self, config: NeighborConfig) {
+        self.reachable_time = Self::random_reachable_time(&config);
+        self.config = config;
+    }
+
+    pub fn config(&self) -> NeighborConfig {
+        self.config
+    }
+
+    // Like linux, the actual reachable time is randomized between 0.5 and 1.5 times the configured base value,
+    // so that neighbors don't all get re-probed at the very same time.
+    fn random_reachable_time(config: &NeighborConfig) -> Duration {
+        config
+            .base_reachable_time
+            .mul_f64(rand::thread_rng().gen_range(0.5, 1.5))
+    }
+
+    // Looks up the hw address to send a packet to `protocol_addr`. Unknown addresses get an INCOMPLETE entry,
+    // which is resolved by the timers. Using a STALE entry kicks off its re-validation.
+    pub fn lookup(&mut self, protocol_addr: ProtocolAddr, now: Instant) -> Option<HwAddr> {
+        let entry = self
+            .entries
+            .entry(protocol_addr)
+            .or_insert_with(|| Neighbor::new(None, NeighborState::Incomplete, now));
+        entry.used = now;
+        if entry.state == NeighborState::Stale {
+            entry.set_state(NeighborState::Delay, now);
+        }
+        if entry.is_valid() {
+            entry.hw_addr
+        } else {
+            None
+        }
+    }
+
+    // Parks a packet till the address of its next hop gets resolved. When the queue is full the oldest packet is
+    // dropped to make room, same as linux.
+    pub fn enqueue(&mut self, protocol_addr: ProtocolAddr, packet: PendingPacket, now: Instant) {
+        let unres_qlen = self.config.unres_qlen;
+        let entry = self
+            .entries
+            .entry(protocol_addr)
+            .or_insert_with(|| Neighbor::new(None, NeighborState::Incomplete, now));
+        if unres_qlen == 0 {
+            return;
+        }
+        if entry.pending.len() >= unres_qlen {
+            entry.pending.pop_front();
+        }
+        entry.pending.push_back(packet);
+    }
+
+    // Records the hw address of a neighbor. `confirmed` must only be set when we know for sure that the neighbor is
+    // reachable(ex: it replied to our request), every other hint only makes the entry STALE.
+    // Returns the packets that were waiting for the address, they can be sent out now.
+    pub fn update(
+        &mut self,
+        protocol_addr: ProtocolAddr,
+        hw_addr: HwAddr,
+        confirmed: bool,
+        now: Instant,
+    ) -> Vec<PendingPacket> {
+        let entry = self
+            .entries
+            .entry(protocol_addr)
+            .or_insert_with(|| Neighbor::new(None, NeighborState::Incomplete, now));
+        if entry.state == NeighborState::Permanent {
+            return Vec::new();
+        }
+        let addr_changed = entry.hw_addr != Some(hw_addr);
+        entry.hw_addr = Some(hw_addr);
+        entry.probes = 0;
+        if confirmed {
+            entry.confirmed = Some(now);
+            entry.set_state(NeighborState::Reachable, now);
+        } else if addr_changed || !entry.is_valid() {
+            entry.set_state(NeighborState::Stale, now);
+        }
+        entry.pending.drain(..).collect()
+    }
+
+    // Whether we are waiting on a reply from `protocol_addr`, ie. we have sent it requests which it hasn't answered yet.
+    pub fn awaiting_reply(&self, protocol_addr: &ProtocolAddr) -> bool {
+        match self.entries.get(protocol_addr) {
+            Some(entry) => match entry.state {
+                NeighborState::Incomplete | NeighborState::Probe => entry.probes > 0,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    // Whether `hw_addr` contradicts what we know for sure about `protocol_addr`: a static entry, or one which was
+    // confirmed recently.
+    pub fn conflicts(&self, protocol_addr: &ProtocolAddr, hw_addr: &HwAddr) -> bool {
+        match self.entries.get(protocol_addr) {
+            Some(entry) => match entry.state {
+                NeighborState::Reachable | NeighborState::Permanent => {
+                    matches!(entry.hw_addr, Some(known) if known != *hw_addr)
+                }
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    // Pins `protocol_addr` to `hw_addr`. Packets waiting for the address are sent on the next timer run.
+    pub fn add_permanent(&mut self, protocol_addr: ProtocolAddr, hw_addr: HwAddr, now: Instant) {
+        let entry = self
+            .entries
+            .entry(protocol_addr)
+            .or_insert_with(|| Neighbor::new(None, NeighborState::Permanent, now));
+        entry.hw_addr = Some(hw_addr);
+        entry.probes = 0;
+        entry.set_state(NeighborState::Permanent, now);
+    }
+
+    pub fn remove(&mut self, protocol_addr: &ProtocolAddr) -> bool {
+        self.entries.remove(protocol_addr).is_some()
+    }
+
+    // Removes all the dynamic entries, static entries have to be removed explicitly.
+    pub fn flush(&mut self) {
+        self.entries
+            .retain(|_, entry| entry.state == NeighborState::Permanent);
+    }
+
+    pub fn entries(&self, now: Instant) -> Vec<NeighborEntry> {
+        let mut entries: Vec<NeighborEntry> = self
+            .entries
+            .iter()
+            .map(|(protocol_addr, entry)| NeighborEntry {
+                addr: Ipv4Addr::from(*protocol_addr),
+                hw_addr: entry.hw_addr,
+                state: entry.state,
+                age: now.duration_since(entry.updated),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.addr);
+        entries
+    }
+
+    fn confirmed_within(entry: &Neighbor, now: Instant, period: Duration) -> bool {
+        match entry.confirmed {
+            Some(confirmed) => now.duration_since(confirmed) <= period,
+            None => false,
+        }
+    }
+
+    // Drives the state machine of the neighbors `on_link` picks, the ones on the caller's link.
+    pub fn run_timers(
+        &mut self,
+        now: Instant,
+        on_link: impl Fn(&ProtocolAddr) -> bool,
+    ) -> TimerOutput {
+        let config = self.config;
+        let reachable_time = self.reachable_time;
+        let mut actions = Vec::new();
+        let mut unreachable = Vec::new();
+        let mut resolved = Vec::new();
+
+        for (protocol_addr, entry) in self.entries.iter_mut() {
+            if !on_link(protocol_addr) {
+                continue;
+            }
+            if entry.is_valid() && !entry.pending.is_empty() {
+                resolved.extend(entry.pending.drain(..));
+            }
+            match entry.state {
+                NeighborState::Reachable => {
+                    if !Self::confirmed_within(entry, now, reachable_time) {
+                        entry.set_state(NeighborState::Stale, now);
+                    }
+                }
+                NeighborState::Delay => {
+                    if Self::confirmed_within(entry, now, config.delay_first_probe_time) {
+                        entry.set_state(NeighborState::Reachable, now);
+                    } else if now.duration_since(entry.updated) >= config.delay_first_probe_time {
+                        entry.set_state(NeighborState::Probe, now);
+                        entry.probes = 0;
+                        entry.next_probe = now;
+                    }
+                }
+                NeighborState::Incomplete | NeighborState::Probe => {
+                    let max_probes = if entry.state == NeighborState::Incomplete {
+                        config.mcast_probes
+                    } else {
+                        config.ucast_probes
+                    };
+                    if now < entry.next_probe {
+                        continue;
+                    }
+                    if entry.probes >= max_probes {
+                        entry.set_state(NeighborState::Failed, now);
+                        unreachable.extend(entry.pending.drain(..));
+                        continue;
+                    }
+                    entry.probes += 1;
+                    entry.next_probe = now + Self::retrans_backoff(entry, config.retrans_time);
+                    match (entry.state, entry.hw_addr) {
+                        (NeighborState::Probe, Some(hw_addr)) => {
+                            actions.push(NeighborAction::Probe(*protocol_addr, hw_addr))
+                        }
+                        _ => actions.push(NeighborAction::Solicit(*protocol_addr)),
+                    }
+                }
+                NeighborState::Stale | NeighborState::Failed | NeighborState::Permanent => {}
+            }
+        }
+
+        // Garbage collect failed entries and the stale ones nobody has used in a while.
+        self.entries.retain(|_, entry| match entry.state {
+            NeighborState::Failed => false,
+            NeighborState::Stale => now.duration_since(entry.used) <= config.gc_stale_time,
+            _ => true,
+        });
+        TimerOutput {
+            actions,
+            unreachable,
+            resolved,
+        }
+    }
+
+    // Broadcast solicitations back off exponentially(1x, 2x, 4x.. the retrans time) so that we don't flood the segment
+    // asking for a host that doesn't exist. Unicast probes are always paced by the retrans time.
+    fn retrans_backoff(entry: &Neighbor, retrans_time: Duration) -> Duration {
+        if entry.state == NeighborState::Incomplete {
+            let factor = (1u32 << (entry.probes - 1).min(31)).min(MAX_RETRANS_BACKOFF);
+            retrans_time * factor
+        } else {
+            retrans_time
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PEER_IP: ProtocolAddr = [10, 0, 0, 1];
+    const PEER_HW: HwAddr = [0x02, 0, 0, 0, 0, 1];
+    const PEER_NEW_HW: HwAddr = [0x02, 0, 0, 0, 0, 2];
+
+    fn table() -> NeighborTable {
+        let mut table = NeighborTable::new(NeighborConfig::default());
+        // Take the randomness out of the picture.
+        table.reachable_time = Duration::from_secs(30);
+        table
+    }
+
+    fn secs(now: Instant, secs: u64) -> Instant {
+        now + Duration::from_secs(secs)
+    }
+
+    struct Packet;
+
+    impl LinkLayerWritable for Packet {
+        fn spa(&self) -> ProtocolAddr {
+            [10, 0, 0, 2]
+        }
+        fn tpa(&self) -> ProtocolAddr {
+            PEER_IP
+        }
+        fn ether_type(&self) -> [u8; 2] {
+            [0x08, 0x00]
+        }
+        fn data(&self) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    fn packet() -> PendingPacket {
+        Box::new(Packet)
+    }
+
+    #[test]
+    fn test_resolution() {
+        let mut table = table();
+        let now = Instant::now();
+
+        assert_eq!(table.lookup(PEER_IP, now), None);
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Incomplete
+        );
+        // Left to the link the address is on
+        assert_eq!(table.run_timers(now, |_| false).actions, vec![]);
+        assert_eq!(
+            table.run_timers(now, |_| true).actions,
+            vec![NeighborAction::Solicit(PEER_IP)]
+        );
+        // Retransmits are paced by `retrans_time`
+        assert_eq!(table.run_timers(now, |_| true).actions, vec![]);
+
+        table.update(PEER_IP, PEER_HW, true, now);
+        assert_eq!(table.lookup(PEER_IP, now), Some(PEER_HW));
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Reachable
+        );
+    }
+
+    #[test]
+    fn test_failed_resolution_is_evicted() {
+        let mut table = table();
+        let now = Instant::now();
+        table.lookup(PEER_IP, now);
+        table.enqueue(PEER_IP, packet(), now);
+        // Solicitations back off exponentially
+        for i in &[0, 1, 3] {
+            assert_eq!(
+                table.run_timers(secs(now, *i), |_| true).actions,
+                vec![NeighborAction::Solicit(PEER_IP)]
+            );
+        }
+        let output = table.run_timers(secs(now, 6), |_| true);
+        assert_eq!(output.actions, vec![]);
+        assert_eq!(output.unreachable.len(), 0);
+
+        let output = table.run_timers(secs(now, 7), |_| true);
+        assert_eq!(output.actions, vec![]);
+        assert_eq!(output.unreachable.len(), 1);
+        assert!(!table.entries.contains_key(&PEER_IP));
+    }
+
+    #[test]
+    fn test_reprobing() {
+        let mut table = table();
+        let now = Instant::now();
+        table.update(PEER_IP, PEER_HW, true, now);
+
+        table.run_timers(secs(now, 31), |_| true);
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Stale
+        );
+
+        // Stale entries are still usable, but using them triggers a re-validation
+        assert_eq!(table.lookup(PEER_IP, secs(now, 40)), Some(PEER_HW));
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Delay
+        );
+
+        table.run_timers(secs(now, 45), |_| true);
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Probe
+        );
+        assert_eq!(
+            table.run_timers(secs(now, 45), |_| true).actions,
+            vec![NeighborAction::Probe(PEER_IP, PEER_HW)]
+        );
+
+        table.update(PEER_IP, PEER_HW, true, secs(now, 46));
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Reachable
+        );
+    }
+
+    #[test]
+    fn test_unconfirmed_neighbor_is_evicted() {
+        let mut table = table();
+        let now = Instant::now();
+        table.update(PEER_IP, PEER_HW, false, now);
+        table.lookup(PEER_IP, now);
+        table.run_timers(secs(now, 5), |_| true);
+        for i in 0..3 {
+            assert_eq!(
+                table.run_timers(secs(now, 6 + i), |_| true).actions,
+                vec![NeighborAction::Probe(PEER_IP, PEER_HW)]
+            );
+        }
+        table.run_timers(secs(now, 9), |_| true);
+        assert!(!table.entries.contains_key(&PEER_IP));
+    }
+
+    #[test]
+    fn test_changed_hw_addr() {
+        let mut table = table();
+        let now = Instant::now();
+        table.update(PEER_IP, PEER_HW, true, now);
+        table.update(PEER_IP, PEER_NEW_HW, false, now);
+        assert_eq!(
+            table.entries.get(&PEER_IP).unwrap().state,
+            NeighborState::Stale
+        );
+        assert_eq!(table.lookup(PEER_IP, now), Some(PEER_NEW_HW));
+    }
+
+    #[test]
+    fn test_stale_gc() {
+        let mut table = table();
+        let now = Instant::now();
+        table.update(PEER_IP, PEER_HW, false, now);
+        table.run_timers(secs(now, 30), |_| true);
+        assert!(table.entries.contains_key(&PEER_IP));
+        table.run_timers(secs(now, 61), |_| true);
+        assert!(!table.entries.contains_key(&PEER_IP));
+    }
+
+    #[test]
+    fn test_pending_queue() {
+        let mut table = table();
+        table.config.unres_qlen = 2;
+        let now = Instant::now();
+
+        assert_eq!(table.lookup(PEER_IP, now), None);
+        for _ in 0..3 {
+            table.enqueue(PEER_IP, packet(), now);
+        }
+        // The queue is bounded
+        assert_eq!(table.entries.get(&PEER_IP).unwrap().pending.len(), 2);
+
+        // Resolving the address releases the queued packets
+        assert_eq!(table.update(PEER_IP, PEER_HW, true, now).len(), 2);
+        assert_eq!(table.entries.get(&PEER_IP).unwrap().pending.len(), 0);
+    }
+
+    #[test]
+    fn test_permanent_entries() {
+        let mut table = table();
+        let now = Instant::now();
+        table.lookup(PEER_IP, now);
+        table.enqueue(PEER_IP, packet(), now);
+        table.add_permanent(PEER_IP, PEER_HW, now);
+
+        // The packets waiting for the address are released on the next timer run
+        let output = table.run_timers(now, |_| true);
+        assert_eq!(output.actions, vec![]);
+        assert_eq!(output.resolved.len(), 1);
+
+        // Received ARP doesn't override static entries
+        table.update(PEER_IP, PEER_NEW_HW, true, now);
+        assert_eq!(table.lookup(PEER_IP, now), Some(PEER_HW));
+
+        // Nor do they ever age out or get flushed
+        table.run_timers(secs(now, 3600), |_| true);
+        table.flush();
+        assert_eq!(
+            table.entries(secs(now, 3600)),
+            vec![NeighborEntry {
+                addr: Ipv4Addr::from(PEER_IP),
+                hw_addr: Some(PEER_HW),
+                state: NeighborState::Permanent,
+                age: Duration::from_secs(3600),
+            }]
+        );
+        assert_eq!(
+            table.entries(now)[0].to_string(),
+            "10.0.0.1 lladdr 02:00:00:00:00:01 PERMANENT age 0s"
+        );
+
+        assert!(table.remove(&PEER_IP));
+        assert!(table.entries(now).is_empty());
+    }
+}
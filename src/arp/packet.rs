@@ -0,0 +1,152 @@
+// Ethernet/IPv4 ARP packet
+// Reference: https://tools.ietf.org/html/rfc826
+
+use crate::ethernet::{self, HwAddr, ProtocolAddr};
+use std::convert::TryInto;
+
+const ETHERNET_HW_TYPE: u16 = 1;
+const HW_ADDR_LEN: u8 = 6;
+const PROTOCOL_ADDR_LEN: u8 = 4;
+// Length of an ethernet/IPv4 ARP packet
+pub const ARP_PACKET_LEN: usize = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArpOp {
+    Request,
+    Reply,
+}
+
+impl ArpOp {
+    fn from_u16(op: u16) -> Option<ArpOp> {
+        match op {
+            1 => Some(ArpOp::Request),
+            2 => Some(ArpOp::Reply),
+            _ => None,
+        }
+    }
+
+    fn value(&self) -> u16 {
+        match self {
+            ArpOp::Request => 1,
+            ArpOp::Reply => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArpPacket {
+    pub op: ArpOp,
+    pub sha: HwAddr,
+    pub spa: ProtocolAddr,
+    pub tha: HwAddr,
+    pub tpa: ProtocolAddr,
+}
+
+impl ArpPacket {
+    // Only ethernet/IPv4 packets are accepted. Anything after the first ARP_PACKET_LEN bytes(ex: ethernet padding)
+    // is ignored.
+    pub fn parse(data: &[u8]) -> Result<ArpPacket, &'static str> {
+        if data.len() < ARP_PACKET_LEN {
+            return Err("Truncated ARP packet");
+        }
+        let hw_type = u16::from_be_bytes([data[0], data[1]]);
+        let protocol_type = u16::from_be_bytes([data[2], data[3]]);
+        if hw_type != ETHERNET_HW_TYPE || protocol_type != ethernet::ETH_IPV4 as u16 {
+            return Err("Unsupported ARP hw/protocol type");
+        }
+        if data[4] != HW_ADDR_LEN || data[5] != PROTOCOL_ADDR_LEN {
+            return Err("Unsupported ARP hw/protocol address length");
+        }
+        let op = match ArpOp::from_u16(u16::from_be_bytes([data[6], data[7]])) {
+            Some(op) => op,
+            None => return Err("Unknown ARP opcode"),
+        };
+        Ok(ArpPacket {
+            op,
+            sha: data[8..14].try_into().unwrap(),
+            spa: data[14..18].try_into().unwrap(),
+            tha: data[18..24].try_into().unwrap(),
+            tpa: data[24..28].try_into().unwrap(),
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ARP_PACKET_LEN);
+        bytes.extend_from_slice(&ETHERNET_HW_TYPE.to_be_bytes());
+        bytes.extend_from_slice(&(ethernet::ETH_IPV4 as u16).to_be_bytes());
+        bytes.push(HW_ADDR_LEN);
+        bytes.push(PROTOCOL_ADDR_LEN);
+        bytes.extend_from_slice(&self.op.value().to_be_bytes());
+        bytes.extend_from_slice(&self.sha);
+        bytes.extend_from_slice(&self.spa);
+        bytes.extend_from_slice(&self.tha);
+        bytes.extend_from_slice(&self.tpa);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Who has 192.168.1.1? Tell 192.168.1.10, as it comes off the wire: padded up to the minimum ethernet frame size.
+    const WIRE_REQUEST: &str =
+        "0001080006040001c8d719c3d2a7c0a8010a000000000000c0a801010000000000000000000000000000";
+    // The gateway's reply to it.
+    const WIRE_REPLY: &str = "00010800060400022c3033a1b2c3c0a80101c8d719c3d2a7c0a8010a";
+
+    #[test]
+    fn test_parse_request() {
+        let packet = ArpPacket::parse(&hex::decode(WIRE_REQUEST).unwrap()).unwrap();
+        assert_eq!(
+            packet,
+            ArpPacket {
+                op: ArpOp::Request,
+                sha: [0xc8, 0xd7, 0x19, 0xc3, 0xd2, 0xa7],
+                spa: [192, 168, 1, 10],
+                tha: [0; 6],
+                tpa: [192, 168, 1, 1],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let packet = ArpPacket::parse(&hex::decode(WIRE_REPLY).unwrap()).unwrap();
+        assert_eq!(packet.op, ArpOp::Reply);
+        assert_eq!(packet.sha, [0x2c, 0x30, 0x33, 0xa1, 0xb2, 0xc3]);
+        assert_eq!(packet.spa, [192, 168, 1, 1]);
+        assert_eq!(packet.tha, [0xc8, 0xd7, 0x19, 0xc3, 0xd2, 0xa7]);
+        assert_eq!(packet.tpa, [192, 168, 1, 10]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let request = hex::decode(WIRE_REQUEST).unwrap();
+        let packet = ArpPacket::parse(&request).unwrap();
+        assert_eq!(packet.to_bytes(), request[..ARP_PACKET_LEN].to_vec());
+
+        let reply = hex::decode(WIRE_REPLY).unwrap();
+        assert_eq!(ArpPacket::parse(&reply).unwrap().to_bytes(), reply);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        let request = hex::decode(WIRE_REQUEST).unwrap();
+        assert!(ArpPacket::parse(&request[..ARP_PACKET_LEN - 1]).is_err());
+
+        let with_byte = |index: usize, value: u8| {
+            let mut packet = request.clone();
+            packet[index] = value;
+            ArpPacket::parse(&packet)
+        };
+        // Token ring hw type
+        assert!(with_byte(1, 6).is_err());
+        // IPv6 protocol type
+        assert!(with_byte(2, 0x86).is_err());
+        assert!(with_byte(4, 8).is_err());
+        assert!(with_byte(5, 16).is_err());
+        // RARP request
+        assert!(with_byte(7, 3).is_err());
+    }
+}
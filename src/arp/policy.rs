@@ -0,0 +1,198 @@
+// ARP validation and anti-spoofing policy, along with the counters of what got dropped and why.
+
+use lazy_static::lazy_static;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+lazy_static! {
+    static ref POLICY: RwLock<ArpPolicy> = RwLock::new(ArpPolicy::default());
+    static ref LIMITERS: Mutex<Limiters> = Mutex::new(Limiters {
+        requests: TokenBucket::new(),
+        replies: TokenBucket::new(),
+    });
+    static ref STATS: Mutex<ArpStats> = Mutex::new(ArpStats::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArpPolicy {
+    // Accept replies nobody asked for, ex: a reply for an address we aren't resolving. Off by default, since that is
+    // the easiest way to poison the neighbor table.
+    pub accept_unsolicited_replies: bool,
+    // Learn the sender's address from the requests directed to us.
+    pub learn_from_requests: bool,
+    // Max ARP requests processed per second, 0 disables the limit.
+    pub max_requests_per_sec: u32,
+    // Max ARP replies sent per second, 0 disables the limit.
+    pub max_replies_per_sec: u32,
+}
+
+impl Default for ArpPolicy {
+    fn default() -> Self {
+        ArpPolicy {
+            accept_unsolicited_replies: false,
+            learn_from_requests: true,
+            max_requests_per_sec: 100,
+            max_replies_per_sec: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    // Truncated packet, unknown opcode, or a hw/protocol type other than ethernet/IPv4.
+    Malformed,
+    // The sender hw address doesn't match the ethernet source address.
+    Spoofed,
+    // The sender claims an address which a static or recently confirmed neighbor entry has at another hw address.
+    Conflicting,
+    Unsolicited,
+    RateLimitedRequest,
+    RateLimitedReply,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ArpStats {
+    pub malformed: u64,
+    pub spoofed: u64,
+    pub conflicting: u64,
+    pub unsolicited: u64,
+    pub rate_limited_requests: u64,
+    pub rate_limited_replies: u64,
+}
+
+impl fmt::Display for ArpStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "malformed {} spoofed {} conflicting {} unsolicited {} rate_limited_requests {} rate_limited_replies {}",
+            self.malformed,
+            self.spoofed,
+            self.conflicting,
+            self.unsolicited,
+            self.rate_limited_requests,
+            self.rate_limited_replies
+        )
+    }
+}
+
+struct Limiters {
+    requests: TokenBucket,
+    replies: TokenBucket,
+}
+
+// Allows up to `rate` events per second, with bursts of up to a second's worth of events.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        TokenBucket {
+            tokens: 0.0,
+            last_refill: None,
+        }
+    }
+
+    fn allow(&mut self, rate: u32, now: Instant) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let rate = rate as f64;
+        self.tokens = match self.last_refill {
+            Some(last_refill) => {
+                let refill = now.duration_since(last_refill).as_secs_f64() * rate;
+                (self.tokens + refill).min(rate)
+            }
+            None => rate,
+        };
+        self.last_refill = Some(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub fn policy() -> ArpPolicy {
+    *POLICY.read().unwrap()
+}
+
+pub fn set_policy(policy: ArpPolicy) {
+    *POLICY.write().unwrap() = policy;
+}
+
+pub fn stats() -> ArpStats {
+    *STATS.lock().unwrap()
+}
+
+pub fn record_drop(reason: DropReason) {
+    let mut stats = STATS.lock().unwrap();
+    match reason {
+        DropReason::Malformed => stats.malformed += 1,
+        DropReason::Spoofed => stats.spoofed += 1,
+        DropReason::Conflicting => stats.conflicting += 1,
+        DropReason::Unsolicited => stats.unsolicited += 1,
+        DropReason::RateLimitedRequest => stats.rate_limited_requests += 1,
+        DropReason::RateLimitedReply => stats.rate_limited_replies += 1,
+    }
+}
+
+// Whether another request can be processed right now.
+pub fn allow_request() -> bool {
+    let rate = policy().max_requests_per_sec;
+    let allowed = LIMITERS
+        .lock()
+        .unwrap()
+        .requests
+        .allow(rate, Instant::now());
+    if !allowed {
+        record_drop(DropReason::RateLimitedRequest);
+    }
+    allowed
+}
+
+// Whether another reply can be sent right now.
+pub fn allow_reply() -> bool {
+    let rate = policy().max_replies_per_sec;
+    let allowed = LIMITERS.lock().unwrap().replies.allow(rate, Instant::now());
+    if !allowed {
+        record_drop(DropReason::RateLimitedReply);
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new();
+        let now = Instant::now();
+
+        // A full second's worth of burst
+        for _ in 0..10 {
+            assert!(bucket.allow(10, now));
+        }
+        assert!(!bucket.allow(10, now));
+
+        // Refills at `rate` per second
+        assert!(bucket.allow(10, now + Duration::from_millis(100)));
+        assert!(!bucket.allow(10, now + Duration::from_millis(100)));
+
+        // Never accumulates more than a second's worth
+        let later = now + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert!(bucket.allow(10, later));
+        }
+        assert!(!bucket.allow(10, later));
+
+        // Zero disables the limit
+        assert!(bucket.allow(0, later));
+    }
+}
@@ -0,0 +1,37 @@
+// Proxy ARP: answer ARP requests on behalf of hosts which aren't on the link, ex: hosts behind the stack.
+// Reference: https://tools.ietf.org/html/rfc1027
+
+use crate::ethernet::ProtocolAddr;
+use crate::net_util::Cidr;
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref PROXIED_SUBNETS: RwLock<Vec<Cidr>> = RwLock::new(Vec::new());
+}
+
+pub fn add(subnet: Cidr) {
+    let mut subnets = PROXIED_SUBNETS.write().unwrap();
+    if !subnets.iter().any(|proxied| proxied.same_network(&subnet)) {
+        subnets.push(subnet);
+    }
+}
+
+pub fn remove(subnet: &Cidr) -> bool {
+    let mut subnets = PROXIED_SUBNETS.write().unwrap();
+    let len = subnets.len();
+    subnets.retain(|proxied| !proxied.same_network(subnet));
+    subnets.len() != len
+}
+
+pub fn list() -> Vec<Cidr> {
+    PROXIED_SUBNETS.read().unwrap().clone()
+}
+
+pub fn is_proxied(addr: &ProtocolAddr) -> bool {
+    PROXIED_SUBNETS
+        .read()
+        .unwrap()
+        .iter()
+        .any(|subnet| subnet.contains(addr))
+}
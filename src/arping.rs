@@ -0,0 +1,19 @@
+// arping API: L2 reachability checks and duplicate address detection for IPv4 addresses.
+
+pub use crate::arp::arping::{ArpingOptions, ArpingReply, ArpingResult};
+
+use crate::arp::arping;
+use std::net::Ipv4Addr;
+
+// Sends `options.count` ARP requests for `target`(ex: "10.0.0.1") and collects the replies. Blocks till the last
+// request times out, or till the first reply when `options.duplicate_detection` is set.
+pub fn arping(target: &str, options: ArpingOptions) -> Result<ArpingResult, String> {
+    let target_addr = target
+        .trim()
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("Invalid address {}", target))?;
+    if options.count == 0 {
+        return Err("Count must be at least 1".to_string());
+    }
+    arping::arping(target_addr.octets(), options)
+}
@@ -0,0 +1,298 @@
+// A minimal learning bridge, modelled after the linux software bridge.
+// Reference: https://wiki.linuxfoundation.org/networking/bridge
+
+use crate::ethernet::{EthernetFrame, HwAddr};
+use crate::tap::tap_device::MTU;
+use libc::{c_void, size_t};
+use nix::errno;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Same default as the linux bridge(`ageing_time`).
+pub const DEFAULT_AGEING_TIME: Duration = Duration::from_secs(300);
+
+// Minimum length of a frame we can make any forwarding decision on(dst + src + ether_type).
+const ETH_HEADER_LEN: usize = 14;
+
+pub type PortId = usize;
+
+#[derive(Debug, Clone, Copy)]
+struct FdbEntry {
+    port: PortId,
+    last_seen: Instant,
+    // Local entries belong to the stack itself, they never age out and can't be moved by learning.
+    is_local: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Egress {
+    Port(PortId),
+    Flood,
+    Drop,
+}
+
+// The MAC learning table(forwarding database in linux parlance).
+pub struct ForwardingDatabase {
+    entries: HashMap<HwAddr, FdbEntry>,
+    ageing_time: Duration,
+    last_sweep: Instant,
+}
+
+impl ForwardingDatabase {
+    pub fn new(ageing_time: Duration) -> Self {
+        ForwardingDatabase {
+            entries: HashMap::new(),
+            ageing_time,
+            last_sweep: Instant::now(),
+        }
+    }
+
+    pub fn add_local(&mut self, addr: HwAddr, port: PortId) {
+        self.entries.insert(
+            addr,
+            FdbEntry {
+                port,
+                last_seen: Instant::now(),
+                is_local: true,
+            },
+        );
+    }
+
+    pub fn learn(&mut self, addr: HwAddr, port: PortId) {
+        // Group addresses are never valid source addresses, don't learn them.
+        if addr[0] & 0x01 == 0x01 {
+            return;
+        }
+        match self.entries.get_mut(&addr) {
+            Some(entry) if entry.is_local => {}
+            Some(entry) => {
+                entry.port = port;
+                entry.last_seen = Instant::now();
+            }
+            None => {
+                self.entries.insert(
+                    addr,
+                    FdbEntry {
+                        port,
+                        last_seen: Instant::now(),
+                        is_local: false,
+                    },
+                );
+            }
+        }
+        if self.last_sweep.elapsed() > Duration::from_secs(1) {
+            self.age();
+        }
+    }
+
+    pub fn lookup(&mut self, addr: &HwAddr) -> Option<PortId> {
+        match self.entries.get(addr) {
+            Some(entry) if self.expired(entry) => {
+                self.entries.remove(addr);
+                None
+            }
+            Some(entry) => Some(entry.port),
+            None => None,
+        }
+    }
+
+    // Removes all the learnt entries which haven't been seen for `ageing_time`.
+    pub fn age(&mut self) {
+        let ageing_time = self.ageing_time;
+        self.entries
+            .retain(|_, entry| entry.is_local || entry.last_seen.elapsed() <= ageing_time);
+        self.last_sweep = Instant::now();
+    }
+
+    fn expired(&self, entry: &FdbEntry) -> bool {
+        !entry.is_local && entry.last_seen.elapsed() > self.ageing_time
+    }
+
+    // Learns the frame's source address and decides where the frame has to go.
+    pub fn forwarding_decision(&mut self, frame: &EthernetFrame, ingress: PortId) -> Egress {
+        self.learn(frame.src(), ingress);
+        if frame.is_multicast() {
+            return Egress::Flood;
+        }
+        match self.lookup(&frame.dst()) {
+            // The destination lives on the segment the frame came from, nothing to do.
+            Some(port) if port == ingress => Egress::Drop,
+            Some(port) => Egress::Port(port),
+            None => Egress::Flood,
+        }
+    }
+}
+
+pub struct Bridge {
+    ports: Vec<i32>,
+    fdb: Arc<Mutex<ForwardingDatabase>>,
+}
+
+impl Bridge {
+    pub fn new(ageing_time: Duration) -> Self {
+        Bridge {
+            ports: Vec::new(),
+            fdb: Arc::new(Mutex::new(ForwardingDatabase::new(ageing_time))),
+        }
+    }
+
+    // Attaches a device(ex: a tap device's fd) to the bridge.
+    pub fn add_port(&mut self, fd: i32) -> PortId {
+        self.ports.push(fd);
+        self.ports.len() - 1
+    }
+
+    // Attaches the local stack to the bridge. Frames destined to the stack's hw address are only ever
+    // delivered to this port, from where the stack's `Ethernet::process_frame` picks them up.
+    pub fn add_local_port(&mut self, fd: i32, stack_addr: HwAddr) -> PortId {
+        let port = self.add_port(fd);
+        self.fdb.lock().unwrap().add_local(stack_addr, port);
+        port
+    }
+
+    // TODO: Implement graceful thread shutdown for the port loops.
+    pub fn start(&self) {
+        for (port, fd) in self.ports.iter().enumerate() {
+            let fd = *fd;
+            let ports = self.ports.clone();
+            let fdb = Arc::clone(&self.fdb);
+            thread::spawn(move || Self::port_loop(port, fd, ports, fdb));
+        }
+    }
+
+    fn port_loop(port: PortId, fd: i32, ports: Vec<i32>, fdb: Arc<Mutex<ForwardingDatabase>>) {
+        let mut buffer: Vec<u8> = vec![0; MTU as usize];
+        loop {
+            let read = match read_from_port(fd, &mut buffer) {
+                Ok(read) => read,
+                Err(err) => {
+                    eprintln!("bridge: port {} read failed: {}", port, err);
+                    return;
+                }
+            };
+            if read < ETH_HEADER_LEN {
+                continue;
+            }
+            let frame = EthernetFrame::from_bytes(buffer[0..read].to_vec());
+            let egress = fdb.lock().unwrap().forwarding_decision(&frame, port);
+            match egress {
+                Egress::Port(out_port) => {
+                    let _ = write_to_port(ports[out_port], frame.bytes());
+                }
+                Egress::Flood => {
+                    for (out_port, out_fd) in ports.iter().enumerate() {
+                        if out_port != port {
+                            let _ = write_to_port(*out_fd, frame.bytes());
+                        }
+                    }
+                }
+                Egress::Drop => {}
+            }
+        }
+    }
+}
+
+fn read_from_port(fd: i32, buffer: &mut [u8]) -> Result<usize, &'static str> {
+    let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
+    let res = unsafe { libc::read(fd, buffer_ptr, buffer.len() as size_t) };
+    if res < 0 {
+        Err(errno::Errno::last().desc())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+fn write_to_port(fd: i32, frame: &[u8]) -> Result<usize, &'static str> {
+    let frame_ptr = frame.as_ptr() as *const c_void;
+    let res = unsafe { libc::write(fd, frame_ptr, frame.len() as size_t) };
+    if res < 0 {
+        Err(errno::Errno::last().desc())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(dst: HwAddr, src: HwAddr) -> EthernetFrame {
+        let mut data = Vec::new();
+        data.extend_from_slice(&dst);
+        data.extend_from_slice(&src);
+        data.extend_from_slice(&[0x08, 0x00]);
+        EthernetFrame::from_bytes(data)
+    }
+
+    const HOST_A: HwAddr = [0x02, 0, 0, 0, 0, 0xa];
+    const HOST_B: HwAddr = [0x02, 0, 0, 0, 0, 0xb];
+    const STACK: HwAddr = [0x02, 0, 0, 0, 0, 0xc];
+    const BROADCAST: HwAddr = [0xff; 6];
+
+    #[test]
+    fn test_forwarding_decision() {
+        let mut fdb = ForwardingDatabase::new(DEFAULT_AGEING_TIME);
+        fdb.add_local(STACK, 2);
+
+        // Unknown unicast and broadcast are flooded
+        assert_eq!(
+            fdb.forwarding_decision(&frame(HOST_B, HOST_A), 0),
+            Egress::Flood
+        );
+        assert_eq!(
+            fdb.forwarding_decision(&frame(BROADCAST, HOST_B), 1),
+            Egress::Flood
+        );
+
+        // Both hosts are learnt by now
+        assert_eq!(
+            fdb.forwarding_decision(&frame(HOST_B, HOST_A), 0),
+            Egress::Port(1)
+        );
+        assert_eq!(
+            fdb.forwarding_decision(&frame(HOST_A, HOST_B), 1),
+            Egress::Port(0)
+        );
+
+        // Frames for the stack always go to the local port
+        assert_eq!(
+            fdb.forwarding_decision(&frame(STACK, HOST_A), 0),
+            Egress::Port(2)
+        );
+
+        // Frames to a host on the ingress segment are filtered
+        assert_eq!(
+            fdb.forwarding_decision(&frame(HOST_A, HOST_A), 0),
+            Egress::Drop
+        );
+    }
+
+    #[test]
+    fn test_local_entries_are_not_moved() {
+        let mut fdb = ForwardingDatabase::new(DEFAULT_AGEING_TIME);
+        fdb.add_local(STACK, 2);
+        // A host spoofing the stack's address must not steal its traffic.
+        fdb.learn(STACK, 0);
+        assert_eq!(fdb.lookup(&STACK), Some(2));
+        // Group addresses are never learnt
+        fdb.learn(BROADCAST, 0);
+        assert_eq!(fdb.lookup(&BROADCAST), None);
+    }
+
+    #[test]
+    fn test_ageing() {
+        let mut fdb = ForwardingDatabase::new(Duration::from_millis(10));
+        fdb.add_local(STACK, 2);
+        fdb.learn(HOST_A, 0);
+        assert_eq!(fdb.lookup(&HOST_A), Some(0));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(fdb.lookup(&HOST_A), None);
+        fdb.learn(HOST_B, 1);
+        fdb.age();
+        assert_eq!(fdb.entries.len(), 2);
+        assert_eq!(fdb.lookup(&STACK), Some(2));
+    }
+}
@@ -0,0 +1,498 @@
+// Control commands, modelled after iproute2. Every command returns the text to show to the user.
+//
+// neigh [show]                            Lists the neighbor table
+// neigh add <addr> lladdr <hw_addr>       Adds a static neighbor entry
+// neigh del <addr>                        Deletes a neighbor entry
+// neigh flush                             Removes all the dynamic neighbor entries
+// neigh proxy [show]                      Lists the proxy ARP subnets
+// neigh proxy add|del <subnet>            Adds/removes a proxy ARP subnet
+// arp policy [show]                       Shows the ARP policy
+// arp policy set <knob> <value>           Changes an ARP policy knob, ex: `arp policy set accept_unsolicited_replies on`
+// arp stats                               Shows the counters of dropped ARP packets
+// ip stats                                Shows the counters of dropped IPv4 packets
+// ip link [show]                          Lists the interfaces with their MTU and state
+// ip link set [<interface>] mtu <mtu>     Changes an interface's MTU, eth0's by default
+// ip link set <interface> up|down         Brings an interface administratively up or down
+// ip addr [show]                          Lists the interfaces' addresses
+// ip addr add <addr> [dev <interface>] [brd <broadcast>] [label <label>]
+//                                         Assigns an address, ex: `ip addr add 10.0.0.3/24 label eth0:1`
+// ip addr del <addr> [dev <interface>]    Removes an address
+// ip route [show]                         Lists the routing table
+// ip route add <route>                    Adds a route, ex: `ip route add 10.0.1.0/24 via 10.0.0.3 metric 10`
+// ip route del <destination>              Deletes the routes to a prefix, or `default`
+// ip route get <addr>                     Shows the route to an address
+// ip forward [show]                       Shows the forwarding(router mode) settings
+// ip forward set <knob> <on|off>          Changes a forwarding setting, ex: `ip forward set forwarding on`
+// ip nat [show]                           Lists the NAT rules
+// ip nat add masquerade <prefix> [dev <interface>]
+//                                         Translates the packets forwarded from a prefix to the interface's address
+// ip nat del <prefix>                     Deletes a NAT rule
+// ip nat mappings                         Lists the flows being translated
+// ip filter [show]                        Lists the packet filter rules
+// ip filter add <hook> <rule>             Appends a rule to a hook(prerouting, input, forward or output), ex:
+//                                         `ip filter add input proto udp dport 9000 drop`
+// ip filter del <handle>                  Deletes a packet filter rule
+// ip filter flush [hook]                  Deletes the rules of a hook, or all of them
+// ip conntrack [show]                     Lists the tracked connections
+// ip conntrack flush                      Forgets every tracked connection
+// arping [-D] [-c <count>] [-s <source>] <addr>
+//                                         Sends ARP requests for an address, `-D` for duplicate address detection
+// dhcp [show]                             Shows the DHCP lease
+// dhcp start                              Replaces the link's addresses with one leased from a DHCP server
+// dhcp leases                             Lists the leases handed out by the DHCP server
+// dhcp reserve <lladdr> <addr>            Has the DHCP server always hand an address to a host
+// dhcp unreserve <lladdr>                 Deletes a DHCP reservation
+
+use crate::arping::{self, ArpingOptions};
+use crate::dhcp;
+use crate::ethernet;
+use crate::ip;
+use crate::neighbor;
+use crate::net_util;
+
+pub fn execute(command: &str) -> Result<String, String> {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    match args.split_first() {
+        Some((&"neigh", args)) => neigh(args),
+        Some((&"arp", args)) => arp(args),
+        Some((&"arping", args)) => arping(args),
+        Some((&"ip", args)) => ip(args),
+        Some((&"dhcp", args)) => dhcp(args),
+        Some((cmd, _)) => Err(format!("Unknown command {}", cmd)),
+        None => Ok(String::new()),
+    }
+}
+
+fn neigh(args: &[&str]) -> Result<String, String> {
+    match args {
+        [] | ["show"] | ["list"] => Ok(lines(neighbor::list())),
+        ["add", addr, "lladdr", hw_addr] => {
+            neighbor::add(addr, hw_addr)?;
+            Ok(String::new())
+        }
+        ["del", addr] => {
+            neighbor::delete(addr)?;
+            Ok(String::new())
+        }
+        ["flush"] => {
+            neighbor::flush();
+            Ok(String::new())
+        }
+        ["proxy"] | ["proxy", "show"] => Ok(lines(neighbor::proxies())),
+        ["proxy", "add", subnet] => {
+            neighbor::add_proxy(subnet)?;
+            Ok(String::new())
+        }
+        ["proxy", "del", subnet] => {
+            neighbor::remove_proxy(subnet)?;
+            Ok(String::new())
+        }
+        _ => Err(
+            "Usage: neigh [show | add <addr> lladdr <hw_addr> | del <addr> | flush | proxy [show | add <subnet> | del <subnet>]]"
+                .to_string(),
+        ),
+    }
+}
+
+fn arp(args: &[&str]) -> Result<String, String> {
+    match args {
+        ["policy"] | ["policy", "show"] => {
+            let policy = neighbor::arp_policy();
+            Ok(format!(
+                "accept_unsolicited_replies {}\nlearn_from_requests {}\nmax_requests_per_sec {}\nmax_replies_per_sec {}",
+                on_off(policy.accept_unsolicited_replies),
+                on_off(policy.learn_from_requests),
+                policy.max_requests_per_sec,
+                policy.max_replies_per_sec
+            ))
+        }
+        ["policy", "set", knob, value] => {
+            let mut policy = neighbor::arp_policy();
+            match *knob {
+                "accept_unsolicited_replies" => {
+                    policy.accept_unsolicited_replies = parse_on_off(value)?
+                }
+                "learn_from_requests" => policy.learn_from_requests = parse_on_off(value)?,
+                "max_requests_per_sec" => policy.max_requests_per_sec = parse_number(value)?,
+                "max_replies_per_sec" => policy.max_replies_per_sec = parse_number(value)?,
+                _ => return Err(format!("Unknown ARP policy knob {}", knob)),
+            }
+            neighbor::set_arp_policy(policy);
+            Ok(String::new())
+        }
+        ["stats"] => Ok(neighbor::arp_stats().to_string()),
+        _ => Err("Usage: arp [policy [show | set <knob> <value>] | stats]".to_string()),
+    }
+}
+
+fn ip(args: &[&str]) -> Result<String, String> {
+    match args {
+        ["stats"] => Ok(ip::stats().to_string()),
+        ["link"] | ["link", "show"] => {
+            let mut links = Vec::new();
+            for interface in ip::interfaces() {
+                let state = if ip::link_up(&interface)? { "UP" } else { "DOWN" };
+                links.push(format!("{} mtu {} state {}", interface, ip::mtu(&interface)?, state));
+            }
+            Ok(links.join("\n"))
+        }
+        ["link", "set", "mtu", mtu] => {
+            ip::set_mtu(ethernet::INTERFACE_NAME, parse_number(mtu)?)?;
+            Ok(String::new())
+        }
+        ["link", "set", interface, "mtu", mtu] => {
+            ip::set_mtu(interface, parse_number(mtu)?)?;
+            Ok(String::new())
+        }
+        ["link", "set", interface, state @ "up"] | ["link", "set", interface, state @ "down"] => {
+            ip::set_link_up(interface, *state == "up")?;
+            Ok(String::new())
+        }
+        ["addr"] | ["addr", "show"] => Ok(lines(ip::addresses())),
+        ["addr", "add", addr @ ..] if !addr.is_empty() => {
+            ip::add_address(&addr.join(" "))?;
+            Ok(String::new())
+        }
+        ["addr", "del", addr @ ..] if !addr.is_empty() => {
+            ip::delete_address(&addr.join(" "))?;
+            Ok(String::new())
+        }
+        ["route"] | ["route", "show"] => Ok(lines(ip::routes())),
+        ["route", "add", route @ ..] if !route.is_empty() => {
+            ip::add_route(&route.join(" "))?;
+            Ok(String::new())
+        }
+        ["route", "del", destination] => {
+            ip::delete_route(destination)?;
+            Ok(String::new())
+        }
+        ["route", "get", addr] => Ok(ip::route_get(addr)?.to_string()),
+        ["forward"] | ["forward", "show"] => {
+            let config = ip::forwarding();
+            Ok(format!(
+                "forwarding {}\nsend_redirects {}",
+                on_off(config.enabled),
+                on_off(config.send_redirects)
+            ))
+        }
+        ["forward", "set", knob, value] => {
+            let mut config = ip::forwarding();
+            match *knob {
+                "forwarding" => config.enabled = parse_on_off(value)?,
+                "send_redirects" => config.send_redirects = parse_on_off(value)?,
+                _ => return Err(format!("Unknown forwarding knob {}", knob)),
+            }
+            ip::set_forwarding(config);
+            Ok(String::new())
+        }
+        ["nat"] | ["nat", "show"] => Ok(lines(ip::nat_rules())),
+        ["nat", "add", "masquerade", inside] => {
+            ip::add_masquerade(inside, ethernet::INTERFACE_NAME)?;
+            Ok(String::new())
+        }
+        ["nat", "add", "masquerade", inside, "dev", interface] => {
+            ip::add_masquerade(inside, interface)?;
+            Ok(String::new())
+        }
+        ["nat", "del", inside] => {
+            ip::delete_masquerade(inside)?;
+            Ok(String::new())
+        }
+        ["nat", "mappings"] => Ok(lines(ip::nat_mappings())),
+        ["filter"] | ["filter", "show"] => Ok(lines(ip::filters())),
+        ["filter", "add", hook, rule @ ..] if !rule.is_empty() => {
+            let handle = ip::add_filter(hook, &rule.join(" "))?;
+            Ok(format!("handle {}", handle))
+        }
+        ["filter", "del", handle] => {
+            ip::delete_filter(parse_number(handle)?)?;
+            Ok(String::new())
+        }
+        ["filter", "flush"] => {
+            ip::flush_filters(None)?;
+            Ok(String::new())
+        }
+        ["filter", "flush", hook] => {
+            ip::flush_filters(Some(hook))?;
+            Ok(String::new())
+        }
+        ["conntrack"] | ["conntrack", "show"] => Ok(lines(ip::connections())),
+        ["conntrack", "flush"] => {
+            ip::flush_connections();
+            Ok(String::new())
+        }
+        _ => Err(
+            "Usage: ip [stats | link [show | set [<interface>] mtu <mtu> | set <interface> up|down] | addr [show | add <addr> | del <addr>] | route [show | add <route> | del <destination> | get <addr>] | forward [show | set <knob> <on|off>] | nat [show | add masquerade <prefix> [dev <interface>] | del <prefix> | mappings] | filter [show | add <hook> <rule> | del <handle> | flush [hook]] | conntrack [show | flush]]"
+                .to_string(),
+        ),
+    }
+}
+
+fn arping(args: &[&str]) -> Result<String, String> {
+    let usage = "Usage: arping [-D] [-c <count>] [-s <source>] <addr>";
+    let mut options = ArpingOptions::default();
+    let mut target = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-D" => options.duplicate_detection = true,
+            "-c" => options.count = parse_number(args.next().ok_or(usage)?)?,
+            "-s" => {
+                let source = args.next().ok_or(usage)?;
+                options.source = Some(
+                    source
+                        .parse()
+                        .map_err(|_| format!("Invalid address {}", source))?,
+                );
+            }
+            addr if target.is_none() && !addr.starts_with('-') => target = Some(addr),
+            _ => return Err(usage.to_string()),
+        }
+    }
+    let target = target.ok_or(usage)?;
+
+    let result = arping::arping(target, options)?;
+    let mut output = vec![format!("ARPING {}", result.target)];
+    for reply in &result.replies {
+        output.push(format!(
+            "Unicast reply from {} [{}]  {:.3}ms",
+            result.target,
+            net_util::hw_addr_to_string(&reply.hw_addr),
+            reply.latency.as_secs_f64() * 1000.0
+        ));
+    }
+    output.push(format!(
+        "Sent {} probes, Received {} response(s)",
+        result.sent,
+        result.replies.len()
+    ));
+    if options.duplicate_detection && !result.replies.is_empty() {
+        return Err(format!(
+            "{} is in use by {}",
+            result.target,
+            result
+                .responders()
+                .iter()
+                .map(net_util::hw_addr_to_string)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+    if result.has_duplicates() {
+        output.push(format!(
+            "Duplicate address: {} is claimed by {} hosts",
+            result.target,
+            result.responders().len()
+        ));
+    }
+    Ok(output.join("\n"))
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Expected on or off, got {}", value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number {}", value))
+}
+
+fn dhcp(args: &[&str]) -> Result<String, String> {
+    match args {
+        [] | ["show"] => Ok(dhcp::client::lease().map_or(String::new(), |lease| lease.to_string())),
+        ["start"] => dhcp::client::start().map(|lease| lease.to_string()),
+        ["leases"] => Ok(lines(dhcp::server::leases())),
+        ["reserve", hw_addr, addr] => {
+            dhcp::server::add_reservation(hw_addr, addr)?;
+            Ok(String::new())
+        }
+        ["unreserve", hw_addr] => {
+            dhcp::server::remove_reservation(hw_addr)?;
+            Ok(String::new())
+        }
+        _ => Err(
+            "Usage: dhcp [show | start | leases | reserve <lladdr> <addr> | unreserve <lladdr>]"
+                .to_string(),
+        ),
+    }
+}
+
+fn lines<T: std::fmt::Display>(items: Vec<T>) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_neigh_commands() {
+        execute("neigh add 10.0.5.1 lladdr 02:00:00:00:05:01").unwrap();
+        let table = execute("neigh show").unwrap();
+        assert!(table.contains("10.0.5.1 lladdr 02:00:00:00:05:01 PERMANENT"));
+
+        execute("neigh del 10.0.5.1").unwrap();
+        assert!(!execute("neigh").unwrap().contains("10.0.5.1"));
+        assert!(execute("neigh del 10.0.5.1").is_err());
+
+        assert!(execute("neigh add 10.0.5.1 lladdr 02:00").is_err());
+        assert!(execute("neigh add 10.0.5.1").is_err());
+        assert!(execute("bogus").is_err());
+    }
+
+    #[test]
+    fn test_arp_commands() {
+        // The policy is shared by the whole process, only ever set it to its default here
+        execute("arp policy set max_replies_per_sec 50").unwrap();
+        assert!(execute("arp policy")
+            .unwrap()
+            .contains("max_replies_per_sec 50"));
+
+        assert!(execute("arp policy set accept_unsolicited_replies maybe").is_err());
+        assert!(execute("arp policy set bogus on").is_err());
+        assert!(execute("arp stats").unwrap().starts_with("malformed"));
+    }
+
+    #[test]
+    fn test_ip_link_commands() {
+        execute("ip link set mtu 1400").unwrap();
+        assert_eq!(
+            execute("ip link").unwrap(),
+            "lo mtu 65535 state UP\neth0 mtu 1400 state UP"
+        );
+        assert!(execute("ip link set mtu 40").is_err());
+        assert!(execute("ip link set mtu 9000").is_err());
+        assert!(execute("ip link set lo mtu 1400").is_err());
+        assert!(execute("ip link set eth1 mtu 1400").is_err());
+        execute("ip link set eth0 mtu 1500").unwrap();
+
+        execute("ip link set eth0 up").unwrap();
+        execute("ip link set lo up").unwrap();
+        assert!(execute("ip link set lo down").is_err());
+        assert!(execute("ip link set eth1 down").is_err());
+    }
+
+    #[test]
+    fn test_ip_addr_commands() {
+        execute("ip addr add 192.168.79.2/24 dev eth0 label eth0:7").unwrap();
+        assert!(execute("ip addr")
+            .unwrap()
+            .contains("192.168.79.2/24 brd 192.168.79.255 dev eth0 label eth0:7"));
+        assert!(execute("ip route")
+            .unwrap()
+            .contains("192.168.79.0/24 dev eth0 src 192.168.79.2"));
+        assert!(execute("ip addr add 192.168.79.2/24").is_err());
+        execute("ip addr del 192.168.79.2/24").unwrap();
+        assert!(execute("ip addr del 192.168.79.2 dev eth0").is_err());
+    }
+
+    #[test]
+    fn test_ip_route_commands() {
+        execute("ip addr add 192.168.87.2/24").unwrap();
+        execute("ip route add 10.0.7.0/24 via 192.168.87.1 metric 5").unwrap();
+        assert!(execute("ip route")
+            .unwrap()
+            .contains("10.0.7.0/24 via 192.168.87.1 dev eth0 metric 5"));
+        assert_eq!(
+            execute("ip route get 10.0.7.9").unwrap(),
+            "10.0.7.0/24 via 192.168.87.1 dev eth0 metric 5"
+        );
+        execute("ip route del 10.0.7.0/24").unwrap();
+        assert!(execute("ip route del 10.0.7.0/24").is_err());
+
+        assert!(execute("ip route add").is_err());
+        assert!(execute("ip route add 10.0.8.0/24 via 192.168.0.1").is_err());
+        assert!(execute("ip route get 10.0.7").is_err());
+    }
+
+    #[test]
+    fn test_ip_forward_commands() {
+        execute("ip forward set send_redirects off").unwrap();
+        assert_eq!(
+            execute("ip forward").unwrap(),
+            "forwarding off\nsend_redirects off"
+        );
+        execute("ip forward set send_redirects on").unwrap();
+        assert!(execute("ip forward set forwarding maybe").is_err());
+        assert!(execute("ip forward set bogus on").is_err());
+    }
+
+    #[test]
+    fn test_ip_nat_commands() {
+        execute("ip nat add masquerade 10.0.6.0/24").unwrap();
+        assert!(execute("ip nat")
+            .unwrap()
+            .contains("masquerade 10.0.6.0/24 dev eth0"));
+        assert!(execute("ip nat add masquerade 10.0.6.0/24").is_err());
+        assert!(execute("ip nat add masquerade 10.0.9.0/24 dev eth9").is_err());
+        execute("ip nat del 10.0.6.0/24").unwrap();
+        assert!(execute("ip nat del 10.0.6.0/24").is_err());
+    }
+
+    #[test]
+    fn test_ip_filter_commands() {
+        let handle = execute("ip filter add input proto udp dport 9000 drop").unwrap();
+        assert!(execute("ip filter")
+            .unwrap()
+            .contains(&format!("input proto udp dport 9000 drop {}", handle)));
+        assert!(execute("ip filter add inbound drop").is_err());
+        assert!(execute("ip filter add input dport 9000").is_err());
+        let handle = handle.trim_start_matches("handle ");
+        execute(&format!("ip filter del {}", handle)).unwrap();
+        assert!(execute(&format!("ip filter del {}", handle)).is_err());
+        assert!(execute("ip filter flush input").is_ok());
+        assert!(execute("ip filter flush inbound").is_err());
+    }
+
+    #[test]
+    fn test_ip_conntrack_commands() {
+        execute("ip conntrack flush").unwrap();
+        assert_eq!(execute("ip conntrack").unwrap(), "");
+        assert!(execute("ip conntrack list").is_err());
+    }
+
+    #[test]
+    fn test_arping_usage() {
+        assert!(execute("arping").is_err());
+        assert!(execute("arping -c").is_err());
+        assert!(execute("arping -c many 10.0.0.1").is_err());
+        assert!(execute("arping -s 10.0.0 10.0.0.1").is_err());
+        assert!(execute("arping 10.0.0.1 10.0.0.3").is_err());
+        assert!(execute("arping -c 0 10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_dhcp_commands() {
+        assert_eq!(execute("dhcp").unwrap(), "");
+        // The client needs the link
+        assert_eq!(
+            execute("dhcp start").unwrap_err(),
+            "The stack is not running"
+        );
+        assert!(execute("dhcp renew").is_err());
+
+        assert_eq!(execute("dhcp leases").unwrap(), "");
+        execute("dhcp reserve 02:00:00:00:79:01 192.168.79.50").unwrap();
+        execute("dhcp unreserve 02:00:00:00:79:01").unwrap();
+        assert!(execute("dhcp unreserve 02:00:00:00:79:01").is_err());
+        assert!(execute("dhcp reserve 02:00:00:00:79 192.168.79.50").is_err());
+        assert!(execute("dhcp reserve 02:00:00:00:79:01 192.168.79").is_err());
+    }
+}
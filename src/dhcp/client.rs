@@ -0,0 +1,599 @@
+// DHCP client
+// Reference: https://tools.ietf.org/html/rfc2131#section-4.4
+//
+// Gets a lease for the link, applies it(address, default route and DNS servers) and keeps renewing it from a thread
+// of its own. The protocol itself is a state machine driven by the messages received and by its timers, which takes
+// the time from its caller so that it can be tested without waiting on the clock.
+
+use super::packet::{self, DhcpMessage, MessageType, CLIENT_PORT, SERVER_PORT};
+use crate::arp::acd;
+use crate::ethernet::{self, HwAddr, ProtocolAddr};
+use crate::ipv4::address::{self, InterfaceAddr};
+use crate::ipv4::route::{self, Route};
+use crate::net_util::Cidr;
+use crate::resolv;
+use crate::udp_socket::{self, UdpSocketIdentifier};
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Retransmission delays while getting a lease, doubled on every attempt(RFC 2131 4.1).
+const RETRANSMIT_MIN: Duration = Duration::from_secs(4);
+const RETRANSMIT_MAX: Duration = Duration::from_secs(64);
+// Shortest wait between two requests while renewing or rebinding(RFC 2131 4.4.5).
+const RENEW_RETRANSMIT_MIN: Duration = Duration::from_secs(60);
+// Requests sent for an offer before going back to discovering.
+const MAX_REQUESTS: u32 = 4;
+// How long to wait after declining an address before starting over(RFC 2131 3.1 5).
+const DECLINE_WAIT: Duration = Duration::from_secs(10);
+// How often the client thread wakes up to run the timers.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How long `start` waits for the first lease.
+pub const START_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref LEASE: RwLock<Option<Lease>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub addr: Cidr,
+    pub server: ProtocolAddr,
+    pub router: Option<ProtocolAddr>,
+    pub dns_servers: Vec<ProtocolAddr>,
+    pub lease_time: Duration,
+    // T1 and T2, after which the lease is renewed with its server, then with any server.
+    pub renewal_time: Duration,
+    pub rebinding_time: Duration,
+    acquired: Instant,
+}
+
+impl Lease {
+    // T1 and T2 default to half and seven eighths of the lease time(RFC 2131 4.4.5). A mask-less lease gets a /32.
+    fn from_ack(ack: &DhcpMessage, server: ProtocolAddr, now: Instant) -> Option<Lease> {
+        let lease_time = ack.lease_time?;
+        let prefix_len = if ack.subnet_mask.is_some() {
+            ack.prefix_len()?
+        } else {
+            32
+        };
+        Some(Lease {
+            addr: Cidr::new(ack.yiaddr, prefix_len).ok()?,
+            server: ack.server_id.unwrap_or(server),
+            router: ack.routers.first().copied(),
+            dns_servers: ack.dns_servers.clone(),
+            lease_time: Duration::from_secs(lease_time as u64),
+            renewal_time: Duration::from_secs(ack.renewal_time.unwrap_or(lease_time / 2) as u64),
+            rebinding_time: Duration::from_secs(
+                ack.rebinding_time.unwrap_or(lease_time / 8 * 7) as u64
+            ),
+            acquired: now,
+        })
+    }
+
+    fn renew_at(&self) -> Instant {
+        self.acquired + self.renewal_time
+    }
+
+    fn rebind_at(&self) -> Instant {
+        self.acquired + self.rebinding_time
+    }
+
+    fn expires_at(&self) -> Instant {
+        self.acquired + self.lease_time
+    }
+}
+
+impl fmt::Display for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if let Some(router) = self.router {
+            write!(f, " router {}", Ipv4Addr::from(router))?;
+        }
+        for dns_server in &self.dns_servers {
+            write!(f, " dns {}", Ipv4Addr::from(*dns_server))?;
+        }
+        let valid = self.expires_at().saturating_duration_since(Instant::now());
+        write!(
+            f,
+            " server {} valid_lft {}s",
+            Ipv4Addr::from(self.server),
+            valid.as_secs()
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    // Sends a message to a server, or to the limited broadcast address.
+    Send(DhcpMessage, ProtocolAddr),
+    Configure(Lease),
+    Deconfigure(Lease),
+}
+
+struct Client {
+    hw_addr: HwAddr,
+    state: State,
+    xid: u32,
+    // The address offered, and the server offering it.
+    offer: Option<(ProtocolAddr, ProtocolAddr)>,
+    lease: Option<Lease>,
+    // When the last message is sent again, or when to start over in Init.
+    timeout: Instant,
+    attempts: u32,
+}
+
+impl Client {
+    fn new(hw_addr: HwAddr, now: Instant) -> Self {
+        Client {
+            hw_addr,
+            state: State::Init,
+            xid: 0,
+            offer: None,
+            lease: None,
+            timeout: now,
+            attempts: 0,
+        }
+    }
+
+    // Runs the timers: retransmissions, and the renewal, rebinding and expiry of the lease.
+    fn poll(&mut self, now: Instant) -> Vec<Action> {
+        match (self.state, self.lease.clone()) {
+            (State::Init, _) if now >= self.timeout => {
+                self.xid = rand::random();
+                self.state = State::Selecting;
+                self.attempts = 0;
+                self.retransmit(now)
+            }
+            (State::Selecting, _) if now >= self.timeout => self.retransmit(now),
+            (State::Requesting, _) if now >= self.timeout => {
+                if self.attempts >= MAX_REQUESTS {
+                    self.restart(now)
+                } else {
+                    self.retransmit(now)
+                }
+            }
+            (State::Bound, Some(lease)) if now >= lease.renew_at() => {
+                self.xid = rand::random();
+                self.state = State::Renewing;
+                self.retransmit(now)
+            }
+            (State::Renewing, Some(lease)) if now >= lease.rebind_at() => {
+                self.state = State::Rebinding;
+                self.retransmit(now)
+            }
+            (State::Rebinding, Some(lease)) if now >= lease.expires_at() => self.restart(now),
+            (State::Renewing, _) | (State::Rebinding, _) if now >= self.timeout => {
+                self.retransmit(now)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn receive(&mut self, message: &DhcpMessage, now: Instant) -> Vec<Action> {
+        if message.xid != self.xid || message.chaddr != self.hw_addr {
+            return Vec::new();
+        }
+        match (self.state, message.message_type) {
+            // The first offer wins.
+            (State::Selecting, MessageType::Offer) => match message.server_id {
+                Some(server) => {
+                    self.offer = Some((message.yiaddr, server));
+                    self.state = State::Requesting;
+                    self.attempts = 0;
+                    self.retransmit(now)
+                }
+                None => Vec::new(),
+            },
+            (State::Requesting, MessageType::Ack)
+            | (State::Renewing, MessageType::Ack)
+            | (State::Rebinding, MessageType::Ack) => {
+                let server = match (&self.offer, &self.lease) {
+                    (Some((_, server)), _) | (None, Some(Lease { server, .. })) => *server,
+                    (None, None) => return Vec::new(),
+                };
+                let lease = match Lease::from_ack(message, server, now) {
+                    Some(lease) => lease,
+                    None => return Vec::new(),
+                };
+                let mut actions = Vec::new();
+                if let Some(previous) = self.lease.replace(lease.clone()) {
+                    if previous.addr != lease.addr {
+                        actions.push(Action::Deconfigure(previous));
+                    }
+                }
+                self.state = State::Bound;
+                self.offer = None;
+                actions.push(Action::Configure(lease));
+                actions
+            }
+            (State::Requesting, MessageType::Nak)
+            | (State::Renewing, MessageType::Nak)
+            | (State::Rebinding, MessageType::Nak) => self.restart(now),
+            _ => Vec::new(),
+        }
+    }
+
+    // The leased address turned out to be in use by another host(RFC 2131 4.4.1).
+    fn decline(&mut self, now: Instant) -> Vec<Action> {
+        let lease = match self.lease.take() {
+            Some(lease) => lease,
+            None => return Vec::new(),
+        };
+        let decline = DhcpMessage {
+            requested_addr: Some(lease.addr.addr()),
+            server_id: Some(lease.server),
+            ..DhcpMessage::new(MessageType::Decline, self.xid, self.hw_addr)
+        };
+        self.state = State::Init;
+        self.offer = None;
+        self.timeout = now + DECLINE_WAIT;
+        vec![Action::Send(decline, address::LIMITED_BROADCAST)]
+    }
+
+    // Gives up the lease, if any, and starts over with a discover.
+    fn restart(&mut self, now: Instant) -> Vec<Action> {
+        self.state = State::Init;
+        self.offer = None;
+        self.timeout = now;
+        let mut actions: Vec<Action> = self
+            .lease
+            .take()
+            .map(Action::Deconfigure)
+            .into_iter()
+            .collect();
+        actions.extend(self.poll(now));
+        actions
+    }
+
+    // Sends the message of the current state, again when it went unanswered.
+    fn retransmit(&mut self, now: Instant) -> Vec<Action> {
+        let mut message = DhcpMessage {
+            parameter_request_list: packet::default_parameter_request_list(),
+            ..DhcpMessage::new(MessageType::Request, self.xid, self.hw_addr)
+        };
+        let mut dst = address::LIMITED_BROADCAST;
+        match (self.state, &self.offer, &self.lease) {
+            (State::Selecting, _, _) => {
+                message.message_type = MessageType::Discover;
+                message.broadcast = true;
+            }
+            (State::Requesting, Some((addr, server)), _) => {
+                message.requested_addr = Some(*addr);
+                message.server_id = Some(*server);
+                message.broadcast = true;
+            }
+            (State::Renewing, _, Some(lease)) => {
+                message.ciaddr = lease.addr.addr();
+                dst = lease.server;
+            }
+            (State::Rebinding, _, Some(lease)) => message.ciaddr = lease.addr.addr(),
+            _ => return Vec::new(),
+        }
+        self.timeout = match (self.state, &self.lease) {
+            (State::Renewing, Some(lease)) => now + renew_retransmit_delay(now, lease.rebind_at()),
+            (State::Rebinding, Some(lease)) => {
+                now + renew_retransmit_delay(now, lease.expires_at())
+            }
+            _ => now + retransmit_delay(self.attempts),
+        };
+        self.attempts += 1;
+        vec![Action::Send(message, dst)]
+    }
+}
+
+// 4s, 8s, 16s... up to 64s, give or take a second.
+fn retransmit_delay(attempts: u32) -> Duration {
+    let delay = std::cmp::min(
+        RETRANSMIT_MIN * 2u32.pow(std::cmp::min(attempts, 4)),
+        RETRANSMIT_MAX,
+    );
+    let jitter = rand::thread_rng().gen_range(0, 2000);
+    delay + Duration::from_millis(jitter) - Duration::from_secs(1)
+}
+
+// Half the time left till `deadline`, but no less than a minute(RFC 2131 4.4.5).
+fn renew_retransmit_delay(now: Instant, deadline: Instant) -> Duration {
+    std::cmp::max(
+        deadline.saturating_duration_since(now) / 2,
+        RENEW_RETRANSMIT_MIN,
+    )
+}
+
+// The current lease, None till the client gets one.
+pub fn lease() -> Option<Lease> {
+    LEASE.read().unwrap().clone()
+}
+
+// Starts the client on the running stack. The addresses the link started with make way for the leased one. Blocks
+// till the first lease is bound, or for START_TIMEOUT, the client keeps trying in the background either way.
+pub fn start() -> Result<Lease, String> {
+    let (writer, hw_addr) =
+        ethernet::link(ethernet::INTERFACE_NAME).ok_or("The stack is not running")?;
+    let socket =
+        udp_socket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_PORT)).map_err(|err| err.to_string())?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    for entry in address::list() {
+        if entry.interface == ethernet::INTERFACE_NAME {
+            address::remove(&entry.interface, &entry.addr.addr())?;
+        }
+    }
+
+    let (bound_tx, bound_rx) = channel();
+    thread::spawn(move || {
+        let mut client = Client::new(hw_addr, Instant::now());
+        let runner = Runner {
+            socket,
+            writer,
+            hw_addr,
+            bound: bound_tx,
+        };
+        loop {
+            let actions = client.poll(Instant::now());
+            runner.perform(&mut client, actions);
+            let mut buf = Vec::with_capacity(ethernet::mtu(ethernet::INTERFACE_NAME));
+            // Errors(ex: the link went down) only mean there is nothing to read this time around.
+            if runner.socket.recv_from(&mut buf).is_ok() {
+                if let Ok(message) = DhcpMessage::parse(&buf) {
+                    let actions = client.receive(&message, Instant::now());
+                    runner.perform(&mut client, actions);
+                }
+            }
+        }
+    });
+    bound_rx
+        .recv_timeout(START_TIMEOUT)
+        .map_err(|_| "No DHCP lease yet, still trying".to_string())
+}
+
+struct Runner {
+    socket: UdpSocketIdentifier,
+    writer: ethernet::ChannelWriter,
+    hw_addr: HwAddr,
+    bound: Sender<Lease>,
+}
+
+impl Runner {
+    fn perform(&self, client: &mut Client, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(message, dst) => {
+                    if self
+                        .socket
+                        .connect((Ipv4Addr::from(dst), SERVER_PORT))
+                        .is_ok()
+                    {
+                        let _ = self.socket.send(&message.to_bytes());
+                    }
+                }
+                Action::Configure(lease) => {
+                    // Make sure nobody else is using a new address before taking it(RFC 2131 4.4.1).
+                    let addr = lease.addr.addr();
+                    if !address::is_assigned(ethernet::INTERFACE_NAME, &addr)
+                        && acd::claim(addr, self.hw_addr, &self.writer).is_err()
+                    {
+                        let actions = client.decline(Instant::now());
+                        self.perform(client, actions);
+                        continue;
+                    }
+                    configure(&lease);
+                    let _ = self.bound.send(lease);
+                }
+                Action::Deconfigure(lease) => deconfigure(&lease),
+            }
+        }
+    }
+}
+
+fn default_route(router: ProtocolAddr) -> Route {
+    Route {
+        destination: Cidr::new([0, 0, 0, 0], 0).unwrap(),
+        gateway: Some(router),
+        interface: ethernet::INTERFACE_NAME.to_string(),
+        metric: 0,
+        src: None,
+    }
+}
+
+// The leased address goes to the link, and the lease's router replaces the default route.
+fn configure(lease: &Lease) {
+    let addr = lease.addr.addr();
+    if !address::is_assigned(ethernet::INTERFACE_NAME, &addr) {
+        let _ = address::add(InterfaceAddr::new(ethernet::INTERFACE_NAME, lease.addr));
+    }
+    if let Some(router) = lease.router {
+        let default = default_route(router);
+        if !route::list().contains(&default) {
+            route::remove(&default.destination);
+            let _ = route::add(default);
+        }
+    }
+    resolv::set_nameservers(
+        lease
+            .dns_servers
+            .iter()
+            .map(|addr| Ipv4Addr::from(*addr))
+            .collect(),
+    );
+    *LEASE.write().unwrap() = Some(lease.clone());
+}
+
+fn deconfigure(lease: &Lease) {
+    if let Some(router) = lease.router {
+        let default = default_route(router);
+        if route::list().contains(&default) {
+            route::remove(&default.destination);
+        }
+    }
+    let _ = address::remove(ethernet::INTERFACE_NAME, &lease.addr.addr());
+    resolv::set_nameservers(Vec::new());
+    *LEASE.write().unwrap() = None;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HW_ADDR: HwAddr = [2, 0, 0, 0, 0, 1];
+    const SERVER: ProtocolAddr = [10, 0, 0, 1];
+
+    fn reply(client: &Client, message_type: MessageType) -> DhcpMessage {
+        DhcpMessage {
+            yiaddr: [10, 0, 0, 50],
+            subnet_mask: Some([255, 255, 255, 0]),
+            routers: vec![SERVER],
+            dns_servers: vec![SERVER],
+            lease_time: Some(3600),
+            server_id: Some(SERVER),
+            ..DhcpMessage::new(message_type, client.xid, HW_ADDR)
+        }
+    }
+
+    fn sent(actions: &[Action]) -> Vec<(MessageType, ProtocolAddr)> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Send(message, dst) => Some((message.message_type, *dst)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Discover, offer, request and ack.
+    fn bound_client(now: Instant) -> Client {
+        let mut client = Client::new(HW_ADDR, now);
+        let actions = client.poll(now);
+        assert_eq!(
+            sent(&actions),
+            vec![(MessageType::Discover, address::LIMITED_BROADCAST)]
+        );
+        let actions = client.receive(&reply(&client, MessageType::Offer), now);
+        match &actions[..] {
+            [Action::Send(request, dst)] => {
+                assert_eq!(request.message_type, MessageType::Request);
+                assert_eq!(request.requested_addr, Some([10, 0, 0, 50]));
+                assert_eq!(request.server_id, Some(SERVER));
+                assert_eq!(*dst, address::LIMITED_BROADCAST);
+            }
+            _ => panic!("Expected a request, got {:?}", actions),
+        }
+        let actions = client.receive(&reply(&client, MessageType::Ack), now);
+        match &actions[..] {
+            [Action::Configure(lease)] => {
+                assert_eq!(lease.addr, Cidr::parse("10.0.0.50/24").unwrap());
+                assert_eq!(lease.router, Some(SERVER));
+                assert_eq!(lease.renewal_time, Duration::from_secs(1800));
+                assert_eq!(lease.rebinding_time, Duration::from_secs(3150));
+            }
+            _ => panic!("Expected the lease to be configured, got {:?}", actions),
+        }
+        assert_eq!(client.state, State::Bound);
+        client
+    }
+
+    #[test]
+    fn test_acquire_and_renew() {
+        let now = Instant::now();
+        let mut client = bound_client(now);
+        assert!(client.poll(now + Duration::from_secs(1799)).is_empty());
+
+        // Renewed with the server, unicast from the leased address
+        let renew_at = now + Duration::from_secs(1800);
+        let actions = client.poll(renew_at);
+        match &actions[..] {
+            [Action::Send(request, dst)] => {
+                assert_eq!(request.ciaddr, [10, 0, 0, 50]);
+                assert_eq!(request.requested_addr, None);
+                assert_eq!(*dst, SERVER);
+            }
+            _ => panic!("Expected a renewal, got {:?}", actions),
+        }
+        // Sent again no sooner than a minute later
+        assert!(client.poll(renew_at + Duration::from_secs(59)).is_empty());
+        assert_eq!(
+            sent(&client.poll(renew_at + Duration::from_secs(60 * 12))),
+            vec![(MessageType::Request, SERVER)]
+        );
+
+        // The ack extends the lease, without reconfiguring the address
+        let actions = client.receive(&reply(&client, MessageType::Ack), renew_at);
+        assert!(matches!(&actions[..], [Action::Configure(_)]));
+        assert!(client.poll(renew_at + Duration::from_secs(1799)).is_empty());
+    }
+
+    #[test]
+    fn test_rebind_and_expire() {
+        let now = Instant::now();
+        let mut client = bound_client(now);
+        client.poll(now + Duration::from_secs(1800));
+        // Nobody answers, any server will do from T2 on
+        assert_eq!(
+            sent(&client.poll(now + Duration::from_secs(3150))),
+            vec![(MessageType::Request, address::LIMITED_BROADCAST)]
+        );
+        assert_eq!(client.state, State::Rebinding);
+
+        // The lease runs out, and the client starts over
+        let actions = client.poll(now + Duration::from_secs(3600));
+        assert!(matches!(actions[0], Action::Deconfigure(_)));
+        assert_eq!(
+            sent(&actions[1..]),
+            vec![(MessageType::Discover, address::LIMITED_BROADCAST)]
+        );
+        assert_eq!(client.state, State::Selecting);
+    }
+
+    #[test]
+    fn test_nak_and_decline() {
+        let now = Instant::now();
+        let mut client = bound_client(now);
+        client.poll(now + Duration::from_secs(1800));
+        let actions = client.receive(&reply(&client, MessageType::Nak), now);
+        assert!(matches!(actions[0], Action::Deconfigure(_)));
+        assert_eq!(client.state, State::Selecting);
+
+        // Replies to somebody else's transaction are ignored
+        let mut other = reply(&client, MessageType::Offer);
+        other.xid += 1;
+        assert!(client.receive(&other, now).is_empty());
+
+        let mut client = bound_client(now);
+        assert_eq!(
+            sent(&client.decline(now)),
+            vec![(MessageType::Decline, address::LIMITED_BROADCAST)]
+        );
+        // Waits a bit before asking for another address
+        assert!(client.poll(now + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            sent(&client.poll(now + DECLINE_WAIT)),
+            vec![(MessageType::Discover, address::LIMITED_BROADCAST)]
+        );
+    }
+
+    #[test]
+    fn test_discover_retransmissions() {
+        let now = Instant::now();
+        let mut client = Client::new(HW_ADDR, now);
+        client.poll(now);
+        assert!(client.poll(now + Duration::from_secs(2)).is_empty());
+        assert_eq!(sent(&client.poll(now + Duration::from_secs(5))).len(), 1);
+        // The second wait is twice as long
+        assert!(client.poll(now + Duration::from_secs(11)).is_empty());
+        assert_eq!(sent(&client.poll(now + Duration::from_secs(14))).len(), 1);
+    }
+}
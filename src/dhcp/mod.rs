@@ -0,0 +1,5 @@
+pub mod client;
+mod packet;
+pub mod server;
+
+pub use client::Lease;
@@ -0,0 +1,376 @@
+// DHCP message format and options
+// Reference: https://tools.ietf.org/html/rfc2131#section-2, https://tools.ietf.org/html/rfc2132
+
+use crate::ethernet::{HwAddr, ProtocolAddr};
+use std::convert::TryInto;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const ETHERNET_HW_TYPE: u8 = 1;
+const HW_ADDR_LEN: u8 = 6;
+// Server replies go to the broadcast address, for the clients which can't take unicasts before they're configured.
+const BROADCAST_FLAG: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Fixed part of the message, up to the magic cookie.
+const FIXED_LEN: usize = 236;
+// Smallest message the BOOTP relay agents take(RFC 1542 2.1).
+const MIN_MESSAGE_LEN: usize = 300;
+
+// Options(RFC 2132)
+const PAD: u8 = 0;
+const SUBNET_MASK: u8 = 1;
+const ROUTER: u8 = 3;
+const DNS_SERVER: u8 = 6;
+const REQUESTED_ADDR: u8 = 50;
+const LEASE_TIME: u8 = 51;
+const MESSAGE_TYPE: u8 = 53;
+const SERVER_ID: u8 = 54;
+const PARAMETER_REQUEST_LIST: u8 = 55;
+const RENEWAL_TIME: u8 = 58;
+const REBINDING_TIME: u8 = 59;
+const END: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<MessageType> {
+        match value {
+            1 => Some(MessageType::Discover),
+            2 => Some(MessageType::Offer),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Decline),
+            5 => Some(MessageType::Ack),
+            6 => Some(MessageType::Nak),
+            7 => Some(MessageType::Release),
+            8 => Some(MessageType::Inform),
+            _ => None,
+        }
+    }
+
+    fn value(&self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+        }
+    }
+
+    // Messages sent by the servers, the others come from the clients.
+    fn is_reply(&self) -> bool {
+        matches!(
+            self,
+            MessageType::Offer | MessageType::Ack | MessageType::Nak
+        )
+    }
+}
+
+// A DHCP message, along with the options the stack knows about. The others are skipped when parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhcpMessage {
+    pub message_type: MessageType,
+    pub xid: u32,
+    pub secs: u16,
+    pub broadcast: bool,
+    // Client's current address, only set when it is bound, renewing or rebinding.
+    pub ciaddr: ProtocolAddr,
+    // 'your' address, the one the server offers or hands out.
+    pub yiaddr: ProtocolAddr,
+    pub siaddr: ProtocolAddr,
+    // Relay agent's address.
+    pub giaddr: ProtocolAddr,
+    pub chaddr: HwAddr,
+    pub subnet_mask: Option<ProtocolAddr>,
+    pub routers: Vec<ProtocolAddr>,
+    pub dns_servers: Vec<ProtocolAddr>,
+    pub requested_addr: Option<ProtocolAddr>,
+    // Lease times, in seconds.
+    pub lease_time: Option<u32>,
+    pub renewal_time: Option<u32>,
+    pub rebinding_time: Option<u32>,
+    pub server_id: Option<ProtocolAddr>,
+    pub parameter_request_list: Vec<u8>,
+}
+
+impl DhcpMessage {
+    pub fn new(message_type: MessageType, xid: u32, chaddr: HwAddr) -> Self {
+        DhcpMessage {
+            message_type,
+            xid,
+            secs: 0,
+            broadcast: false,
+            ciaddr: [0; 4],
+            yiaddr: [0; 4],
+            siaddr: [0; 4],
+            giaddr: [0; 4],
+            chaddr,
+            subnet_mask: None,
+            routers: Vec::new(),
+            dns_servers: Vec::new(),
+            requested_addr: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+            server_id: None,
+            parameter_request_list: Vec::new(),
+        }
+    }
+
+    // The prefix length the subnet mask stands for, None for a mask with holes in it.
+    pub fn prefix_len(&self) -> Option<u8> {
+        let mask = u32::from_be_bytes(self.subnet_mask?);
+        if mask.count_ones() + mask.trailing_zeros() != 32 {
+            return None;
+        }
+        Some(mask.count_ones() as u8)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DhcpMessage, &'static str> {
+        if data.len() < FIXED_LEN + MAGIC_COOKIE.len() {
+            return Err("Truncated DHCP message");
+        }
+        if data[1] != ETHERNET_HW_TYPE || data[2] != HW_ADDR_LEN {
+            return Err("Unsupported DHCP hw type");
+        }
+        if data[FIXED_LEN..FIXED_LEN + 4] != MAGIC_COOKIE {
+            return Err("Missing DHCP magic cookie");
+        }
+        let addr = |offset: usize| -> ProtocolAddr { data[offset..offset + 4].try_into().unwrap() };
+        let mut message = DhcpMessage {
+            xid: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            secs: u16::from_be_bytes([data[8], data[9]]),
+            broadcast: u16::from_be_bytes([data[10], data[11]]) & BROADCAST_FLAG != 0,
+            ciaddr: addr(12),
+            yiaddr: addr(16),
+            siaddr: addr(20),
+            giaddr: addr(24),
+            chaddr: data[28..34].try_into().unwrap(),
+            ..DhcpMessage::new(MessageType::Discover, 0, [0; 6])
+        };
+
+        let mut message_type = None;
+        let mut options = &data[FIXED_LEN + 4..];
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                PAD => {
+                    options = rest;
+                    continue;
+                }
+                END => break,
+                _ => {}
+            }
+            let len = *rest.first().ok_or("Truncated DHCP option")? as usize;
+            if rest.len() < len + 1 {
+                return Err("Truncated DHCP option");
+            }
+            let value = &rest[1..len + 1];
+            options = &rest[len + 1..];
+            match code {
+                MESSAGE_TYPE => {
+                    message_type = value.first().and_then(|value| MessageType::from_u8(*value))
+                }
+                SUBNET_MASK => message.subnet_mask = Some(parse_addr(value)?),
+                ROUTER => message.routers = parse_addrs(value)?,
+                DNS_SERVER => message.dns_servers = parse_addrs(value)?,
+                REQUESTED_ADDR => message.requested_addr = Some(parse_addr(value)?),
+                LEASE_TIME => message.lease_time = Some(parse_u32(value)?),
+                SERVER_ID => message.server_id = Some(parse_addr(value)?),
+                PARAMETER_REQUEST_LIST => message.parameter_request_list = value.to_vec(),
+                RENEWAL_TIME => message.renewal_time = Some(parse_u32(value)?),
+                REBINDING_TIME => message.rebinding_time = Some(parse_u32(value)?),
+                _ => {}
+            }
+        }
+
+        message.message_type = message_type.ok_or("Missing DHCP message type")?;
+        let op = if message.message_type.is_reply() {
+            BOOTREPLY
+        } else {
+            BOOTREQUEST
+        };
+        if data[0] != op {
+            return Err("DHCP message type doesn't match the op");
+        }
+        Ok(message)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0; FIXED_LEN];
+        data[0] = if self.message_type.is_reply() {
+            BOOTREPLY
+        } else {
+            BOOTREQUEST
+        };
+        data[1] = ETHERNET_HW_TYPE;
+        data[2] = HW_ADDR_LEN;
+        data[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        data[8..10].copy_from_slice(&self.secs.to_be_bytes());
+        if self.broadcast {
+            data[10..12].copy_from_slice(&BROADCAST_FLAG.to_be_bytes());
+        }
+        data[12..16].copy_from_slice(&self.ciaddr);
+        data[16..20].copy_from_slice(&self.yiaddr);
+        data[20..24].copy_from_slice(&self.siaddr);
+        data[24..28].copy_from_slice(&self.giaddr);
+        data[28..34].copy_from_slice(&self.chaddr);
+        data.extend_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut data, MESSAGE_TYPE, &[self.message_type.value()]);
+        if let Some(mask) = self.subnet_mask {
+            push_option(&mut data, SUBNET_MASK, &mask);
+        }
+        if !self.routers.is_empty() {
+            push_option(&mut data, ROUTER, &self.routers.concat());
+        }
+        if !self.dns_servers.is_empty() {
+            push_option(&mut data, DNS_SERVER, &self.dns_servers.concat());
+        }
+        if let Some(addr) = self.requested_addr {
+            push_option(&mut data, REQUESTED_ADDR, &addr);
+        }
+        if let Some(lease_time) = self.lease_time {
+            push_option(&mut data, LEASE_TIME, &lease_time.to_be_bytes());
+        }
+        if let Some(server_id) = self.server_id {
+            push_option(&mut data, SERVER_ID, &server_id);
+        }
+        if !self.parameter_request_list.is_empty() {
+            push_option(
+                &mut data,
+                PARAMETER_REQUEST_LIST,
+                &self.parameter_request_list,
+            );
+        }
+        if let Some(renewal_time) = self.renewal_time {
+            push_option(&mut data, RENEWAL_TIME, &renewal_time.to_be_bytes());
+        }
+        if let Some(rebinding_time) = self.rebinding_time {
+            push_option(&mut data, REBINDING_TIME, &rebinding_time.to_be_bytes());
+        }
+        data.push(END);
+        if data.len() < MIN_MESSAGE_LEN {
+            data.resize(MIN_MESSAGE_LEN, PAD);
+        }
+        data
+    }
+}
+
+// The options a client asks for: subnet mask, router, DNS servers and the lease times.
+pub fn default_parameter_request_list() -> Vec<u8> {
+    vec![
+        SUBNET_MASK,
+        ROUTER,
+        DNS_SERVER,
+        LEASE_TIME,
+        RENEWAL_TIME,
+        REBINDING_TIME,
+    ]
+}
+
+// Lists longer than 255 bytes would need splitting(RFC 3396), we never send any.
+fn push_option(data: &mut Vec<u8>, code: u8, value: &[u8]) {
+    data.push(code);
+    data.push(value.len() as u8);
+    data.extend_from_slice(value);
+}
+
+fn parse_addr(value: &[u8]) -> Result<ProtocolAddr, &'static str> {
+    value.try_into().map_err(|_| "Invalid DHCP address option")
+}
+
+fn parse_addrs(value: &[u8]) -> Result<Vec<ProtocolAddr>, &'static str> {
+    let addrs = value.chunks_exact(4);
+    if value.is_empty() || !addrs.remainder().is_empty() {
+        return Err("Invalid DHCP address list option");
+    }
+    Ok(addrs.map(|addr| addr.try_into().unwrap()).collect())
+}
+
+fn parse_u32(value: &[u8]) -> Result<u32, &'static str> {
+    value
+        .try_into()
+        .map(u32::from_be_bytes)
+        .map_err(|_| "Invalid DHCP time option")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ack() -> DhcpMessage {
+        DhcpMessage {
+            yiaddr: [10, 0, 0, 50],
+            siaddr: [10, 0, 0, 1],
+            broadcast: true,
+            subnet_mask: Some([255, 255, 255, 0]),
+            routers: vec![[10, 0, 0, 1]],
+            dns_servers: vec![[10, 0, 0, 1], [8, 8, 8, 8]],
+            lease_time: Some(3600),
+            renewal_time: Some(1800),
+            rebinding_time: Some(3150),
+            server_id: Some([10, 0, 0, 1]),
+            ..DhcpMessage::new(MessageType::Ack, 0x1234_5678, [2, 0, 0, 0, 0, 1])
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let ack = ack();
+        let bytes = ack.to_bytes();
+        assert_eq!(bytes.len(), MIN_MESSAGE_LEN);
+        assert_eq!(bytes[0], BOOTREPLY);
+        assert_eq!(DhcpMessage::parse(&bytes).unwrap(), ack);
+        assert_eq!(ack.prefix_len(), Some(24));
+
+        let discover = DhcpMessage {
+            parameter_request_list: default_parameter_request_list(),
+            ..DhcpMessage::new(MessageType::Discover, 7, [2, 0, 0, 0, 0, 1])
+        };
+        let bytes = discover.to_bytes();
+        assert_eq!(bytes[0], BOOTREQUEST);
+        assert_eq!(DhcpMessage::parse(&bytes).unwrap(), discover);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_messages() {
+        let bytes = ack().to_bytes();
+        assert!(DhcpMessage::parse(&bytes[..FIXED_LEN]).is_err());
+
+        let mut no_cookie = bytes.clone();
+        no_cookie[FIXED_LEN] = 0;
+        assert!(DhcpMessage::parse(&no_cookie).is_err());
+
+        // An ACK sent as a request
+        let mut wrong_op = bytes.clone();
+        wrong_op[0] = BOOTREQUEST;
+        assert!(DhcpMessage::parse(&wrong_op).is_err());
+
+        // The message type is the first option, its length runs past the end of the message.
+        let mut truncated = bytes[..FIXED_LEN + 6].to_vec();
+        truncated[FIXED_LEN + 5] = 4;
+        assert!(DhcpMessage::parse(&truncated).is_err());
+
+        let holes = DhcpMessage {
+            subnet_mask: Some([255, 0, 255, 0]),
+            ..ack()
+        };
+        assert_eq!(holes.prefix_len(), None);
+    }
+}
@@ -0,0 +1,661 @@
+// DHCP server
+// Reference: https://tools.ietf.org/html/rfc2131#section-4.3
+//
+// Hands out addresses from a pool to the hosts on the link(ex: VMs or containers behind the tap), along with the
+// router and DNS servers options. Leases are kept in a file so that the hosts get the same address back after a
+// restart, and hosts can be pinned to an address with a reservation. Relay agents aren't supported.
+
+use super::packet::{DhcpMessage, MessageType, CLIENT_PORT, SERVER_PORT};
+use crate::arp::neighbor::NEIGHBOR_TABLE;
+use crate::ethernet::{self, HwAddr, ProtocolAddr};
+use crate::ipv4::address::{self, InterfaceAddr};
+use crate::net_util::{self, Cidr};
+use crate::udp_socket;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How long an offered address is set aside for the client to request it.
+const OFFER_HOLD: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref SERVER: Mutex<Server> = Mutex::new(Server::new(ServerConfig::default()));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    // First and last address of the pool, in the subnet of one of the link's addresses.
+    pub pool_start: Ipv4Addr,
+    pub pool_end: Ipv4Addr,
+    pub lease_time: Duration,
+    // Router option, the server's own address(the stack being the hosts' gateway) when None.
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    // Where the leases are kept across restarts, they're only kept in memory when None.
+    pub lease_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            pool_start: Ipv4Addr::new(10, 0, 0, 100),
+            pool_end: Ipv4Addr::new(10, 0, 0, 199),
+            lease_time: Duration::from_secs(12 * 60 * 60),
+            router: None,
+            dns_servers: Vec::new(),
+            lease_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaseState {
+    // Set aside for a client which has yet to request it.
+    Offered,
+    Bound,
+    // A client found out that another host is using the address, nobody gets it till the lease runs out.
+    Declined,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerLease {
+    pub addr: Ipv4Addr,
+    pub hw_addr: HwAddr,
+    pub state: LeaseState,
+    pub expires: SystemTime,
+}
+
+impl fmt::Display for ServerLease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            LeaseState::Offered => "offered",
+            LeaseState::Bound => "bound",
+            LeaseState::Declined => "declined",
+        };
+        let valid = self
+            .expires
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        write!(
+            f,
+            "{} lladdr {} {} valid_lft {}s",
+            self.addr,
+            net_util::hw_addr_to_string(&self.hw_addr),
+            state,
+            valid.as_secs()
+        )
+    }
+}
+
+// A reply, and where it goes.
+#[derive(Debug, PartialEq)]
+struct Reply {
+    message: DhcpMessage,
+    dst: ProtocolAddr,
+}
+
+struct Server {
+    config: ServerConfig,
+    leases: HashMap<ProtocolAddr, ServerLease>,
+    reservations: HashMap<HwAddr, ProtocolAddr>,
+}
+
+impl Server {
+    fn new(config: ServerConfig) -> Self {
+        Server {
+            config,
+            leases: HashMap::new(),
+            reservations: HashMap::new(),
+        }
+    }
+
+    fn in_pool(&self, addr: &ProtocolAddr) -> bool {
+        let addr = Ipv4Addr::from(*addr);
+        self.config.pool_start <= addr && addr <= self.config.pool_end
+    }
+
+    // Whether `addr` can go to the client with `hw_addr`: it's in the pool or reserved for the client, and nobody
+    // else holds it.
+    fn assignable(&self, addr: &ProtocolAddr, hw_addr: &HwAddr, subnet: &Cidr) -> bool {
+        let reserved_for_client = self.reservations.get(hw_addr) == Some(addr);
+        let reserved_for_other = self
+            .reservations
+            .iter()
+            .any(|(other, reserved)| reserved == addr && other != hw_addr);
+        let leased_to_other = matches!(
+            self.leases.get(addr),
+            Some(lease) if lease.hw_addr != *hw_addr || lease.state == LeaseState::Declined
+        );
+        (self.in_pool(addr) || reserved_for_client)
+            && subnet.contains(addr)
+            && *addr != subnet.addr()
+            && *addr != subnet.network()
+            && *addr != subnet.broadcast()
+            && !reserved_for_other
+            && !leased_to_other
+            && !used_by_other_host(addr, hw_addr)
+    }
+
+    // The reserved address, then the one the client already holds or asks for, then the first free one in the pool.
+    fn pick_addr(&self, request: &DhcpMessage, subnet: &Cidr) -> Option<ProtocolAddr> {
+        let hw_addr = &request.chaddr;
+        let held = self
+            .leases
+            .values()
+            .find(|lease| lease.hw_addr == *hw_addr && lease.state != LeaseState::Declined)
+            .map(|lease| lease.addr.octets());
+        let start = u32::from(self.config.pool_start);
+        let end = u32::from(self.config.pool_end);
+        let pool = (start..=end).map(|addr| Ipv4Addr::from(addr).octets());
+        self.reservations
+            .get(hw_addr)
+            .copied()
+            .into_iter()
+            .chain(held)
+            .chain(request.requested_addr)
+            .chain(pool)
+            .find(|addr| self.assignable(addr, hw_addr, subnet))
+    }
+
+    fn expire(&mut self, now: SystemTime) {
+        self.leases.retain(|_, lease| lease.expires > now);
+    }
+
+    fn grant(
+        &mut self,
+        addr: ProtocolAddr,
+        hw_addr: HwAddr,
+        state: LeaseState,
+        expires: SystemTime,
+    ) {
+        // A client only ever holds one address.
+        self.leases
+            .retain(|other, lease| lease.hw_addr != hw_addr || *other == addr);
+        self.leases.insert(
+            addr,
+            ServerLease {
+                addr: Ipv4Addr::from(addr),
+                hw_addr,
+                state,
+                expires,
+            },
+        );
+    }
+
+    // `subnet` is the server's own address on the link, along with the prefix length of the pool's subnet.
+    fn handle(&mut self, request: &DhcpMessage, subnet: &Cidr, now: SystemTime) -> Option<Reply> {
+        self.expire(now);
+        let hw_addr = request.chaddr;
+        match request.message_type {
+            MessageType::Discover => {
+                let addr = self.pick_addr(request, subnet)?;
+                if self.leases.get(&addr).map(|lease| lease.state) != Some(LeaseState::Bound) {
+                    self.grant(addr, hw_addr, LeaseState::Offered, now + OFFER_HOLD);
+                }
+                Some(self.reply(request, MessageType::Offer, addr, subnet))
+            }
+            MessageType::Request => {
+                // The client went with another server's offer.
+                if matches!(request.server_id, Some(server_id) if server_id != subnet.addr()) {
+                    self.leases.retain(|_, lease| {
+                        lease.hw_addr != hw_addr || lease.state != LeaseState::Offered
+                    });
+                    return None;
+                }
+                // Renewing or rebinding clients ask for the address they have, the others for the one offered.
+                let addr = if request.ciaddr != [0, 0, 0, 0] {
+                    request.ciaddr
+                } else {
+                    request.requested_addr?
+                };
+                if !self.assignable(&addr, &hw_addr, subnet) {
+                    return Some(self.reply(request, MessageType::Nak, [0, 0, 0, 0], subnet));
+                }
+                self.grant(
+                    addr,
+                    hw_addr,
+                    LeaseState::Bound,
+                    now + self.config.lease_time,
+                );
+                Some(self.reply(request, MessageType::Ack, addr, subnet))
+            }
+            MessageType::Decline => {
+                let addr = request.requested_addr?;
+                // The address belongs to nobody till the lease runs out. Not granted, that would drop the other
+                // declined addresses.
+                if self.leases.get(&addr)?.hw_addr == hw_addr {
+                    self.leases.insert(
+                        addr,
+                        ServerLease {
+                            addr: Ipv4Addr::from(addr),
+                            hw_addr: [0; 6],
+                            state: LeaseState::Declined,
+                            expires: now + self.config.lease_time,
+                        },
+                    );
+                }
+                None
+            }
+            MessageType::Release => {
+                if self.leases.get(&request.ciaddr)?.hw_addr == hw_addr {
+                    self.leases.remove(&request.ciaddr);
+                }
+                None
+            }
+            // The client has an address already, and only wants the other parameters(RFC 2131 3.4).
+            MessageType::Inform if request.ciaddr != [0, 0, 0, 0] => {
+                Some(self.reply(request, MessageType::Ack, [0, 0, 0, 0], subnet))
+            }
+            _ => None,
+        }
+    }
+
+    fn reply(
+        &self,
+        request: &DhcpMessage,
+        message_type: MessageType,
+        yiaddr: ProtocolAddr,
+        subnet: &Cidr,
+    ) -> Reply {
+        let mut message = DhcpMessage {
+            yiaddr,
+            broadcast: request.broadcast,
+            server_id: Some(subnet.addr()),
+            ..DhcpMessage::new(message_type, request.xid, request.chaddr)
+        };
+        if message_type != MessageType::Nak {
+            message.subnet_mask = Some(subnet.netmask().to_be_bytes());
+            let router = self
+                .config
+                .router
+                .map_or(subnet.addr(), |router| router.octets());
+            message.routers = vec![router];
+            message.dns_servers = self
+                .config
+                .dns_servers
+                .iter()
+                .map(|addr| addr.octets())
+                .collect();
+            if yiaddr != [0, 0, 0, 0] {
+                message.lease_time = Some(self.config.lease_time.as_secs() as u32);
+            }
+        }
+        // Where the reply goes(RFC 2131 4.1): naks are broadcast, configured clients get a unicast, and the others
+        // too unless they asked for a broadcast.
+        let dst = if message_type == MessageType::Nak || request.broadcast {
+            address::LIMITED_BROADCAST
+        } else if request.ciaddr != [0, 0, 0, 0] {
+            request.ciaddr
+        } else {
+            yiaddr
+        };
+        Reply { message, dst }
+    }
+
+    // One lease per line: `<addr> <hw_addr> <expiry, in seconds since the epoch>`. Only bound leases are kept.
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut lines = Vec::new();
+        for lease in self.leases.values() {
+            if lease.state == LeaseState::Bound {
+                let expires = lease.expires.duration_since(UNIX_EPOCH).unwrap_or_default();
+                lines.push(format!(
+                    "{} {} {}\n",
+                    lease.addr,
+                    net_util::hw_addr_to_string(&lease.hw_addr),
+                    expires.as_secs()
+                ));
+            }
+        }
+        // Written aside first, so that a crash never leaves half a file behind.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, lines.concat())?;
+        fs::rename(&tmp, path)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+        };
+        for line in contents.lines() {
+            let invalid = || format!("Invalid lease {}", line);
+            match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [addr, hw_addr, expires] => {
+                    let addr = addr.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+                    let hw_addr = net_util::parse_hw_addr(hw_addr)?;
+                    let expires = expires.parse::<u64>().map_err(|_| invalid())?;
+                    self.grant(
+                        addr.octets(),
+                        hw_addr,
+                        LeaseState::Bound,
+                        UNIX_EPOCH + Duration::from_secs(expires),
+                    );
+                }
+                [] => {}
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether the neighbor table knows of another host using `addr`, ex: one which was configured by hand.
+fn used_by_other_host(addr: &ProtocolAddr, hw_addr: &HwAddr) -> bool {
+    NEIGHBOR_TABLE
+        .lock()
+        .unwrap()
+        .entries(Instant::now())
+        .iter()
+        .any(|entry| {
+            entry.addr.octets() == *addr
+                && matches!(entry.hw_addr, Some(other) if other != *hw_addr)
+        })
+}
+
+// The link's address in the pool's subnet, the server answers from it.
+fn server_addr(config: &ServerConfig) -> Option<Cidr> {
+    let pool_start = config.pool_start.octets();
+    address::list()
+        .into_iter()
+        .filter(|entry| entry.interface == ethernet::INTERFACE_NAME && !entry.secondary)
+        .map(|entry: InterfaceAddr| entry.addr)
+        .find(|addr| addr.contains(&pool_start))
+}
+
+// Starts serving the link with `config`, picking up the leases left in its lease file.
+pub fn start(config: ServerConfig) -> Result<(), String> {
+    let subnet = server_addr(&config).ok_or_else(|| {
+        format!(
+            "No address on {} in the subnet of {}",
+            ethernet::INTERFACE_NAME,
+            config.pool_start
+        )
+    })?;
+    if config.pool_start > config.pool_end || !subnet.contains(&config.pool_end.octets()) {
+        return Err(format!(
+            "Invalid pool {}-{}",
+            config.pool_start, config.pool_end
+        ));
+    }
+    {
+        let mut server = SERVER.lock().unwrap();
+        server.leases.clear();
+        if let Some(path) = &config.lease_file {
+            server.load(path)?;
+        }
+        server.config = config;
+    }
+    let socket =
+        udp_socket::bind((Ipv4Addr::UNSPECIFIED, SERVER_PORT)).map_err(|err| err.to_string())?;
+
+    thread::spawn(move || loop {
+        let mut buf = Vec::with_capacity(ethernet::mtu(ethernet::INTERFACE_NAME));
+        let request = match socket.recv_from(&mut buf) {
+            Ok(_) => match DhcpMessage::parse(&buf) {
+                Ok(request) => request,
+                Err(_) => continue,
+            },
+            Err(_) => continue,
+        };
+        let mut server = SERVER.lock().unwrap();
+        let subnet = match server_addr(&server.config) {
+            Some(subnet) => subnet,
+            None => continue,
+        };
+        let reply = match server.handle(&request, &subnet, SystemTime::now()) {
+            Some(reply) => reply,
+            None => continue,
+        };
+        if let Some(path) = &server.config.lease_file {
+            if let Err(err) = server.save(path) {
+                eprintln!("Failed to save the DHCP leases: {}", err);
+            }
+        }
+        drop(server);
+
+        // The client can't answer ARP requests for its new address yet, the neighbor table learns it from the
+        // request instead.
+        if reply.dst == reply.message.yiaddr {
+            NEIGHBOR_TABLE
+                .lock()
+                .unwrap()
+                .update(reply.dst, request.chaddr, false, Instant::now());
+        }
+        if socket
+            .connect((Ipv4Addr::from(reply.dst), CLIENT_PORT))
+            .is_ok()
+        {
+            let _ = socket.send(&reply.message.to_bytes());
+        }
+    });
+    Ok(())
+}
+
+// Hands `addr` to the host with `hw_addr`, and to nobody else. ex: add_reservation("02:42:ac:11:00:02", "10.0.0.50")
+pub fn add_reservation(hw_addr: &str, addr: &str) -> Result<(), String> {
+    let hw_addr = net_util::parse_hw_addr(hw_addr)?;
+    let addr = addr
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("Invalid address {}", addr))?;
+    SERVER
+        .lock()
+        .unwrap()
+        .reservations
+        .insert(hw_addr, addr.octets());
+    Ok(())
+}
+
+pub fn remove_reservation(hw_addr: &str) -> Result<(), String> {
+    let hw_addr = net_util::parse_hw_addr(hw_addr)?;
+    match SERVER.lock().unwrap().reservations.remove(&hw_addr) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "No reservation for {}",
+            net_util::hw_addr_to_string(&hw_addr)
+        )),
+    }
+}
+
+pub fn leases() -> Vec<ServerLease> {
+    let mut server = SERVER.lock().unwrap();
+    server.expire(SystemTime::now());
+    let mut leases: Vec<ServerLease> = server.leases.values().cloned().collect();
+    leases.sort_by_key(|lease| lease.addr);
+    leases
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT: HwAddr = [2, 0, 0, 0, 0, 1];
+    const OTHER_CLIENT: HwAddr = [2, 0, 0, 0, 0, 2];
+
+    fn test_server() -> (Server, Cidr) {
+        let config = ServerConfig {
+            pool_start: Ipv4Addr::new(192, 168, 90, 10),
+            pool_end: Ipv4Addr::new(192, 168, 90, 11),
+            dns_servers: vec![Ipv4Addr::new(192, 168, 90, 1)],
+            ..ServerConfig::default()
+        };
+        (Server::new(config), Cidr::parse("192.168.90.1/24").unwrap())
+    }
+
+    fn request(message_type: MessageType, hw_addr: HwAddr) -> DhcpMessage {
+        DhcpMessage {
+            broadcast: true,
+            ..DhcpMessage::new(message_type, 42, hw_addr)
+        }
+    }
+
+    // Discover, offer, request and ack. Returns the address leased.
+    fn lease(server: &mut Server, subnet: &Cidr, hw_addr: HwAddr, now: SystemTime) -> ProtocolAddr {
+        let offer = server
+            .handle(&request(MessageType::Discover, hw_addr), subnet, now)
+            .unwrap();
+        assert_eq!(offer.message.message_type, MessageType::Offer);
+        assert_eq!(offer.dst, address::LIMITED_BROADCAST);
+        let request = DhcpMessage {
+            requested_addr: Some(offer.message.yiaddr),
+            server_id: offer.message.server_id,
+            ..request(MessageType::Request, hw_addr)
+        };
+        let ack = server.handle(&request, subnet, now).unwrap();
+        assert_eq!(ack.message.message_type, MessageType::Ack);
+        ack.message.yiaddr
+    }
+
+    #[test]
+    fn test_lease_renew_and_release() {
+        let (mut server, subnet) = test_server();
+        let now = SystemTime::now();
+        let offer = server
+            .handle(&request(MessageType::Discover, CLIENT), &subnet, now)
+            .unwrap()
+            .message;
+        assert_eq!(offer.yiaddr, [192, 168, 90, 10]);
+        assert_eq!(offer.subnet_mask, Some([255, 255, 255, 0]));
+        assert_eq!(offer.routers, vec![[192, 168, 90, 1]]);
+        assert_eq!(offer.dns_servers, vec![[192, 168, 90, 1]]);
+        assert_eq!(offer.server_id, Some([192, 168, 90, 1]));
+        assert_eq!(lease(&mut server, &subnet, CLIENT, now), [192, 168, 90, 10]);
+        assert_eq!(server.leases[&[192, 168, 90, 10]].state, LeaseState::Bound);
+
+        // Renewals are unicast back to the client
+        let renew = DhcpMessage {
+            ciaddr: [192, 168, 90, 10],
+            broadcast: false,
+            ..request(MessageType::Request, CLIENT)
+        };
+        let ack = server.handle(&renew, &subnet, now).unwrap();
+        assert_eq!(ack.message.message_type, MessageType::Ack);
+        assert_eq!(ack.dst, [192, 168, 90, 10]);
+        // Another host can't take over the address
+        let stolen = DhcpMessage {
+            ciaddr: [192, 168, 90, 10],
+            ..request(MessageType::Request, OTHER_CLIENT)
+        };
+        let nak = server.handle(&stolen, &subnet, now).unwrap();
+        assert_eq!(nak.message.message_type, MessageType::Nak);
+
+        let release = DhcpMessage {
+            ciaddr: [192, 168, 90, 10],
+            ..request(MessageType::Release, CLIENT)
+        };
+        assert!(server.handle(&release, &subnet, now).is_none());
+        assert!(server.leases.is_empty());
+    }
+
+    #[test]
+    fn test_pool_exhaustion_and_expiry() {
+        let (mut server, subnet) = test_server();
+        let now = SystemTime::now();
+        lease(&mut server, &subnet, CLIENT, now);
+        lease(&mut server, &subnet, OTHER_CLIENT, now);
+        let third = request(MessageType::Discover, [2, 0, 0, 0, 0, 3]);
+        assert!(server.handle(&third, &subnet, now).is_none());
+
+        // Until the leases run out
+        let later = now + server.config.lease_time;
+        assert!(server.handle(&third, &subnet, later).is_some());
+    }
+
+    #[test]
+    fn test_reservations_and_declines() {
+        let (mut server, subnet) = test_server();
+        let now = SystemTime::now();
+        server.reservations.insert(OTHER_CLIENT, [192, 168, 90, 50]);
+        server
+            .reservations
+            .insert([2, 0, 0, 0, 0, 3], [192, 168, 90, 10]);
+        // Reserved addresses may be out of the pool, and are never handed to anybody else
+        assert_eq!(
+            lease(&mut server, &subnet, OTHER_CLIENT, now),
+            [192, 168, 90, 50]
+        );
+        assert_eq!(lease(&mut server, &subnet, CLIENT, now), [192, 168, 90, 11]);
+
+        let decline = DhcpMessage {
+            requested_addr: Some([192, 168, 90, 11]),
+            ..request(MessageType::Decline, CLIENT)
+        };
+        assert!(server.handle(&decline, &subnet, now).is_none());
+        assert_eq!(
+            server.leases[&[192, 168, 90, 11]].state,
+            LeaseState::Declined
+        );
+        assert!(server
+            .handle(&request(MessageType::Discover, CLIENT), &subnet, now)
+            .is_none());
+
+        // The client went with another server
+        let (mut server, subnet) = test_server();
+        server.handle(&request(MessageType::Discover, CLIENT), &subnet, now);
+        let elsewhere = DhcpMessage {
+            requested_addr: Some([192, 168, 80, 10]),
+            server_id: Some([192, 168, 80, 1]),
+            ..request(MessageType::Request, CLIENT)
+        };
+        assert!(server.handle(&elsewhere, &subnet, now).is_none());
+        assert!(server.leases.is_empty());
+    }
+
+    #[test]
+    fn test_several_declines() {
+        let (mut server, subnet) = test_server();
+        let now = SystemTime::now();
+        let decline = |hw_addr: HwAddr, addr: ProtocolAddr| DhcpMessage {
+            requested_addr: Some(addr),
+            ..request(MessageType::Decline, hw_addr)
+        };
+        assert_eq!(lease(&mut server, &subnet, CLIENT, now), [192, 168, 90, 10]);
+        assert_eq!(
+            lease(&mut server, &subnet, OTHER_CLIENT, now),
+            [192, 168, 90, 11]
+        );
+        assert!(server
+            .handle(&decline(CLIENT, [192, 168, 90, 10]), &subnet, now)
+            .is_none());
+        assert!(server
+            .handle(&decline(OTHER_CLIENT, [192, 168, 90, 11]), &subnet, now)
+            .is_none());
+
+        // Both addresses stay out of the pool
+        for addr in &[[192, 168, 90, 10], [192, 168, 90, 11]] {
+            assert_eq!(server.leases[addr].state, LeaseState::Declined);
+        }
+        for hw_addr in &[CLIENT, OTHER_CLIENT, [2, 0, 0, 0, 0, 3]] {
+            assert!(server
+                .handle(&request(MessageType::Discover, *hw_addr), &subnet, now)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_lease_file() {
+        let (mut server, subnet) = test_server();
+        let now = SystemTime::now();
+        lease(&mut server, &subnet, CLIENT, now);
+        let path = std::env::temp_dir().join(format!("user_net_leases_{}", std::process::id()));
+        server.save(&path).unwrap();
+
+        let (mut restarted, subnet) = test_server();
+        restarted.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lease = &restarted.leases[&[192, 168, 90, 10]];
+        assert_eq!(lease.hw_addr, CLIENT);
+        assert_eq!(lease.state, LeaseState::Bound);
+        // The client gets its address back
+        let offer = restarted
+            .handle(&request(MessageType::Discover, CLIENT), &subnet, now)
+            .unwrap();
+        assert_eq!(offer.message.yiaddr, [192, 168, 90, 10]);
+    }
+}
@@ -1,7 +1,14 @@
+use crate::arp::neighbor::{NeighborAction, NEIGHBOR_TABLE};
+use crate::events::{self, StackEvent};
 use crate::net_util;
 use crate::tap::tap_device::MTU;
 use crate::{
-    ipv4::{initialize_ipv4_stack, IPstackWriter, IPv4},
+    ipv4::{
+        address,
+        filter::{self, Hook},
+        icmp::ICMP,
+        initialize_ipv4_stack, route, udp_socket, IPstackWriter, IPv4,
+    },
     ARP,
 };
 use lazy_static::lazy_static;
@@ -11,13 +18,17 @@ use nix::sys::stat::fstat;
 use nix::sys::stat::SFlag;
 use rand::Rng;
 use std::collections::HashMap;
-use std::sync::mpsc::channel;
-use std::sync::RwLock;
-use std::{thread, time};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-lazy_static! {
-    static ref ARP_CACHE: RwLock<HashMap<ProtocolAddr, HwAddr>> = RwLock::new(HashMap::new());
-}
+// Largest IPv4 packet a frame can carry(RFC 894), and the smallest one every IPv4 host must handle(RFC 791).
+pub const DEFAULT_MTU: usize = 1500;
+pub const MIN_MTU: usize = 68;
+
+// How often the writer loop wakes up to run the neighbor table timers when there is nothing to write.
+const NEIGHBOR_TIMER_INTERVAL: Duration = Duration::from_millis(100);
 
 pub type HwAddr = [u8; 6];
 
@@ -25,11 +36,122 @@ pub type ChannelWriter = std::sync::mpsc::Sender<Box<dyn LinkLayerWritable + Sen
 
 pub type ProtocolAddr = [u8; 4];
 
+lazy_static! {
+    // The stack's interfaces by name. eth0 is there from the start, so that it can be configured before the stack runs.
+    static ref LINKS: Mutex<HashMap<String, Link>> =
+        Mutex::new(vec![(INTERFACE_NAME.to_string(), Link::new())].into_iter().collect());
+}
+
+struct Link {
+    mtu: usize,
+    // Administrative state of the link, nothing is sent or received while it's down.
+    up: bool,
+    // Writer and hw address of the running link, for the code which needs to put packets on the link on its own.
+    running: Option<(ChannelWriter, HwAddr)>,
+}
+
+impl Link {
+    fn new() -> Self {
+        Link {
+            mtu: DEFAULT_MTU,
+            up: true,
+            running: None,
+        }
+    }
+}
+
+// Names of the ethernet interfaces, sorted.
+pub fn interfaces() -> Vec<String> {
+    let mut names: Vec<String> = LINKS.lock().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
+
+pub fn exists(interface: &str) -> bool {
+    LINKS.lock().unwrap().contains_key(interface)
+}
+
+// Largest IPv4 packet which can be sent on the link.
+pub fn mtu(interface: &str) -> usize {
+    LINKS
+        .lock()
+        .unwrap()
+        .get(interface)
+        .map_or(DEFAULT_MTU, |link| link.mtu)
+}
+
+pub fn set_mtu(interface: &str, mtu: usize) -> Result<(), String> {
+    // Frames are read into MTU sized buffers, along with their ethernet header.
+    let max_mtu = MTU as usize - ETH_HEADER_LEN;
+    if mtu < MIN_MTU || mtu > max_mtu {
+        return Err(format!("MTU must be between {} and {}", MIN_MTU, max_mtu));
+    }
+    let previous = match LINKS.lock().unwrap().get_mut(interface) {
+        Some(link) => std::mem::replace(&mut link.mtu, mtu),
+        None => return Err(format!("Unknown interface {}", interface)),
+    };
+    if previous != mtu {
+        events::publish(StackEvent::MtuChanged {
+            interface: interface.to_string(),
+            mtu,
+        });
+    }
+    Ok(())
+}
+
+pub fn is_up(interface: &str) -> bool {
+    matches!(LINKS.lock().unwrap().get(interface), Some(link) if link.up)
+}
+
+// Brings the link administratively up or down. Going down forgets the neighbors and fails the sockets bound to the
+// link's addresses, coming back up announces the addresses again.
+pub fn set_up(interface: &str, up: bool) -> Result<(), String> {
+    let was_up = match LINKS.lock().unwrap().get_mut(interface) {
+        Some(link) => std::mem::replace(&mut link.up, up),
+        None => return Err(format!("Unknown interface {}", interface)),
+    };
+    if was_up == up {
+        return Ok(());
+    }
+    let addrs = address::list()
+        .into_iter()
+        .filter(|entry| entry.interface == interface)
+        .map(|entry| entry.addr.addr());
+    if up {
+        addrs.for_each(|addr| address::announce(interface, addr));
+    } else {
+        NEIGHBOR_TABLE.lock().unwrap().flush();
+        addrs.for_each(|addr| udp_socket::report_addr_error(addr, "Network is down"));
+    }
+    events::publish(StackEvent::LinkChanged {
+        interface: interface.to_string(),
+        up,
+    });
+    Ok(())
+}
+
+// The running link's writer and hw address, None till its stack is started.
+pub fn link(interface: &str) -> Option<(ChannelWriter, HwAddr)> {
+    LINKS
+        .lock()
+        .unwrap()
+        .get(interface)
+        .and_then(|link| link.running.clone())
+}
+
 pub trait LinkLayerWritable {
     fn spa(&self) -> ProtocolAddr;
     fn tpa(&self) -> ProtocolAddr;
     fn ether_type(&self) -> [u8; 2];
     fn data(&self) -> Vec<u8>;
+    // The neighbor whose hw address the packet is sent to, `tpa` itself unless the packet goes through a gateway.
+    fn next_hop(&self) -> ProtocolAddr {
+        self.tpa()
+    }
+    // The destination hw address, when it is known without resolving `tpa`(ex: broadcasts).
+    fn dst_hw_addr(&self) -> Option<HwAddr> {
+        None
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -70,6 +192,7 @@ enum State {
 }
 
 pub struct Ethernet {
+    name: String,
     socket: i32,
     status: State,
     address: HwAddr,
@@ -83,13 +206,29 @@ pub struct EthernetFrame {
     data: Vec<u8>,
 }
 
-const BROADCAST_ADDR: [u8; 6] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+pub const BROADCAST_ADDR: HwAddr = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
 pub const ETH_IPV4: i32 = 0x800;
 pub const ETH_ARP: i32 = 0x806;
 pub const ETH_IPV6: i32 = 0x86DD;
-pub const IP_ADDR: ProtocolAddr = [10, 0, 0, 2];
+// Name of the stack's first interface, the one `start_stack` and DHCP use.
+pub const INTERFACE_NAME: &str = "eth0";
+// dst + src + ether type
+const ETH_HEADER_LEN: usize = 14;
 
 impl EthernetFrame {
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    // The I/G bit of the destination address is set for both broadcast and multicast frames.
+    pub fn is_multicast(&self) -> bool {
+        self.data[0] & 0x01 == 0x01
+    }
+
     // Builds a response eth frame from for a given eth frame. The src address of the given frame would be set as
     // dst address of the returned response.
     pub fn build_response_frame<T>(&self, payload: T) -> Self
@@ -130,15 +269,26 @@ impl Ethernet {
         self.address
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     fn set_socket_state(&mut self, to_state: State) {
         self.status = to_state
     }
 
-    pub fn bind(fd: i32) -> Result<Self, &'static str> {
+    // Binds the interface `name` to the socket, a new interface shows up with the default settings.
+    pub fn bind(fd: i32, name: &str) -> Result<Self, &'static str> {
         match Ethernet::socket_valid(fd) {
             Ok(_) => {
+                let mut links = LINKS.lock().unwrap();
+                let link = links.entry(name.to_string()).or_insert_with(Link::new);
+                if link.running.is_some() {
+                    return Err("Interface is already bound");
+                }
                 let (tx, rx) = channel::<Box<dyn LinkLayerWritable + Send>>();
                 let eth = Ethernet {
+                    name: name.to_string(),
                     socket: fd,
                     status: State::Ready,
                     address: rand::thread_rng().gen::<HwAddr>(),
@@ -152,26 +302,17 @@ impl Ethernet {
         }
     }
 
-    pub fn update_arp_cache(&self, protocol_addr: ProtocolAddr, hw_addr: HwAddr) {
-        let mut arp_cache_obj = ARP_CACHE.write().unwrap();
-        arp_cache_obj.insert(protocol_addr, hw_addr);
-    }
-
-    pub fn arp_cache_exists(&self, protocol_addr: &ProtocolAddr) -> bool {
-        if *protocol_addr == IP_ADDR {
-            true
-        } else {
-            let arp_cache_obj = ARP_CACHE.read().unwrap();
-            arp_cache_obj.contains_key(protocol_addr)
-        }
-    }
-
-    pub fn get_hw_addr_from_cache(&self, protocol_addr: &ProtocolAddr) -> HwAddr {
-        if *protocol_addr == IP_ADDR {
-            self.hw_address()
-        } else {
-            let arp_cache_obj = ARP_CACHE.read().unwrap();
-            *arp_cache_obj.get(protocol_addr).unwrap()
+    // `confirmed` should only be set when the neighbor has proven that it is reachable, ex: it replied to our request.
+    pub fn update_arp_cache(&self, protocol_addr: ProtocolAddr, hw_addr: HwAddr, confirmed: bool) {
+        let pending_packets = NEIGHBOR_TABLE.lock().unwrap().update(
+            protocol_addr,
+            hw_addr,
+            confirmed,
+            Instant::now(),
+        );
+        // The address is resolved now, hand the packets that were waiting for it back to the writer.
+        for packet in pending_packets {
+            self.eth_layer_write(packet).unwrap();
         }
     }
 
@@ -188,27 +329,85 @@ impl Ethernet {
     // TODO: Implement graceful thread shutdown by implementing Drop for ethernet.
     fn intialize_writer_loop(eth: Ethernet, rx: ChannelReceiver) {
         thread::spawn(move || loop {
-            let layer3_resp = rx.recv().unwrap();
-            eth.write_response(layer3_resp);
+            match rx.recv_timeout(NEIGHBOR_TIMER_INTERVAL) {
+                Ok(layer3_resp) if is_up(&eth.name) => eth.write_response(layer3_resp),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            eth.run_neighbor_timers();
         });
     }
 
     fn write_response(&self, layer_3_resp: std::boxed::Box<dyn LinkLayerWritable + Send>) {
-        let target_protocol_addr = layer_3_resp.tpa();
-        if self.arp_cache_exists(&target_protocol_addr) {
-            let dst_hw_addr = self.get_hw_addr_from_cache(&target_protocol_addr);
+        if let Some(dst_hw_addr) = layer_3_resp.dst_hw_addr() {
             let resp_eth_frame = self.make_response_frame(layer_3_resp, dst_hw_addr);
             self.write_frame(resp_eth_frame).unwrap();
-        } else {
-            // Make an ARP request, then re-insert the layer3 response to the eth layer writer chan
-            self.make_arp_req_for_addr(target_protocol_addr);
-            self.eth_layer_write(layer_3_resp).unwrap();
+            return;
+        }
+
+        let target_protocol_addr = layer_3_resp.next_hop();
+
+        let now = Instant::now();
+        let mut neighbor_table = NEIGHBOR_TABLE.lock().unwrap();
+        match neighbor_table.lookup(target_protocol_addr, now) {
+            Some(dst_hw_addr) => {
+                drop(neighbor_table);
+                let resp_eth_frame = self.make_response_frame(layer_3_resp, dst_hw_addr);
+                self.write_frame(resp_eth_frame).unwrap();
+            }
+            None => {
+                // Park the packet till the neighbor table timers get the address resolved.
+                neighbor_table.enqueue(target_protocol_addr, layer_3_resp, now);
+            }
         }
     }
 
-    fn make_arp_req_for_addr(&self, target_protocol_addr: ProtocolAddr) {
-        let arp_req = ARP::make_req_for_addr(target_protocol_addr, &self.address);
-        let eth_frame = self.make_response_frame(arp_req, BROADCAST_ADDR);
+    fn run_neighbor_timers(&self) {
+        // The neighbors are shared between the links, each link looks after the ones its routes lead to.
+        let on_link = |addr: &ProtocolAddr| {
+            route::lookup(addr).map_or(self.name == INTERFACE_NAME, |route| {
+                route.interface == self.name
+            })
+        };
+        let output = NEIGHBOR_TABLE
+            .lock()
+            .unwrap()
+            .run_timers(Instant::now(), on_link);
+        for packet in output.unreachable {
+            self.report_unreachable(packet);
+        }
+        for packet in output.resolved {
+            self.write_response(packet);
+        }
+        for action in output.actions {
+            match action {
+                NeighborAction::Solicit(target_protocol_addr) => {
+                    self.make_arp_req_for_addr(target_protocol_addr, BROADCAST_ADDR)
+                }
+                NeighborAction::Probe(target_protocol_addr, dst_hw_addr) => {
+                    self.make_arp_req_for_addr(target_protocol_addr, dst_hw_addr)
+                }
+            }
+        }
+    }
+
+    // Let the sender of a packet that couldn't be delivered know about it, with an ICMP host unreachable.
+    fn report_unreachable(&self, packet: std::boxed::Box<dyn LinkLayerWritable + Send>) {
+        if EtherType::from_bytes(u16::from_be_bytes(packet.ether_type())) != EtherType::IPv4 {
+            return;
+        }
+        if let Some(ipstack_writer) = self.l4_packet_write_chan.as_ref() {
+            ICMP::report_host_unreachable(&packet.data(), ipstack_writer);
+        }
+    }
+
+    // Asks from our address in the target's subnet, or from 0.0.0.0 when the link has no address left.
+    fn make_arp_req_for_addr(&self, target_protocol_addr: ProtocolAddr, dst_hw_addr: HwAddr) {
+        let sender_addr =
+            address::primary_addr(&self.name, &target_protocol_addr).unwrap_or([0, 0, 0, 0]);
+        let arp_req = ARP::make_req_for_addr(target_protocol_addr, sender_addr, &self.address);
+        let eth_frame = self.make_response_frame(arp_req, dst_hw_addr);
         self.write_frame(eth_frame).unwrap();
     }
 
@@ -225,24 +424,19 @@ impl Ethernet {
         EthernetFrame { data: resp_frame }
     }
 
+    // Packets to our own addresses never get here, they go through the loopback interface.
     pub fn write_frame(&self, eth_frame: EthernetFrame) -> Result<(), &'static str> {
-        // Loopback behaviour
-        if eth_frame.dst() == self.hw_address() {
-            self.process_frame(eth_frame);
-            Ok(())
-        } else {
-            let response_frame_data = eth_frame.data;
-            match self.write_to_socket(response_frame_data) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(err),
-            }
-        }
+        self.write_to_socket(eth_frame.data).map(|_| ())
     }
 
     pub fn hw_address(&self) -> HwAddr {
         self.address
     }
 
+    pub fn writer(&self) -> ChannelWriter {
+        self.l3_resp_writer_chan.clone()
+    }
+
     fn socket_valid(fd: i32) -> Result<(), &'static str> {
         // Validate that the given file descriptor is indeed a socket.
         // https://linux.die.net/man/2/fstat
@@ -266,9 +460,10 @@ impl Ethernet {
         let fd = self.socket;
 
         let l3_resp_recv_chan = self.l3_resp_recv_chan.take().unwrap();
-        let ipstack_writer = initialize_ipv4_stack(self.l3_resp_writer_chan.clone());
+        let ipstack_writer = initialize_ipv4_stack();
 
         let eth_for_writer_loop = Ethernet {
+            name: self.name.clone(),
             socket: self.socket,
             status: self.status,
             address: self.address,
@@ -277,6 +472,9 @@ impl Ethernet {
             l4_packet_write_chan: Some(ipstack_writer.clone()),
         };
         Self::intialize_writer_loop(eth_for_writer_loop, l3_resp_recv_chan);
+        if let Some(link) = LINKS.lock().unwrap().get_mut(&self.name) {
+            link.running = Some((self.l3_resp_writer_chan.clone(), self.address));
+        }
         self.l4_packet_write_chan = Some(ipstack_writer);
         let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
         loop {
@@ -287,6 +485,10 @@ impl Ethernet {
                     eprintln!("{}", err.desc());
                     panic!(err.desc());
                 } else {
+                    // Frames received while the link is down are dropped.
+                    if !is_up(&self.name) {
+                        continue;
+                    }
                     let raw_payload = buffer[0..res as usize].to_vec();
                     let eth_frame = EthernetFrame { data: raw_payload };
                     self.process_frame(eth_frame);
@@ -318,7 +520,24 @@ impl Ethernet {
                 ARP::process_packet(self, frame);
             }
             EtherType::IPv4 => {
-                IPv4::process_packet(self, frame, self.l4_packet_write_chan.as_ref().unwrap());
+                let ipv4_stack_writer = self.l4_packet_write_chan.as_ref().unwrap();
+                let frame = if filter::hooked(Hook::Prerouting) {
+                    let payload = match filter::pass(
+                        Hook::Prerouting,
+                        &self.name,
+                        frame.payload().to_vec(),
+                        ipv4_stack_writer,
+                    ) {
+                        Some(payload) => payload,
+                        None => return,
+                    };
+                    let mut data = frame.bytes()[..ETH_HEADER_LEN].to_vec();
+                    data.extend_from_slice(&payload);
+                    EthernetFrame::from_bytes(data)
+                } else {
+                    frame
+                };
+                IPv4::process_packet(self, frame, ipv4_stack_writer);
             }
             _ => {}
         };
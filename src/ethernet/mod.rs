@@ -1,7 +1,8 @@
 pub mod ethernet;
 
 pub use ethernet::EtherType;
-pub use ethernet::LinkLayerWritable;
+pub use ethernet::{exists, interfaces, is_up, link, mtu, set_mtu, set_up, LinkLayerWritable};
 pub use ethernet::{
-    ChannelWriter, Ethernet, EthernetFrame, HwAddr, ProtocolAddr, ETH_ARP, ETH_IPV4, IP_ADDR,
+    ChannelWriter, Ethernet, EthernetFrame, HwAddr, ProtocolAddr, BROADCAST_ADDR, ETH_ARP,
+    ETH_IPV4, INTERFACE_NAME, MIN_MTU,
 };
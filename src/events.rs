@@ -0,0 +1,36 @@
+// Stack events API
+
+use crate::ethernet::{HwAddr, ProtocolAddr};
+use crate::net_util::Cidr;
+use lazy_static::lazy_static;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<StackEvent>>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackEvent {
+    // Another host on the link(identified by `hw_addr`) is using one of our addresses.
+    AddressConflict { addr: ProtocolAddr, hw_addr: HwAddr },
+    // An address was assigned to, or removed from, an interface.
+    AddressAdded { interface: String, addr: Cidr },
+    AddressRemoved { interface: String, addr: Cidr },
+    // The interface was brought administratively up or down.
+    LinkChanged { interface: String, up: bool },
+    MtuChanged { interface: String, mtu: usize },
+}
+
+// Every subscriber gets its own copy of all the events published after it subscribed.
+pub fn subscribe() -> Receiver<StackEvent> {
+    let (tx, rx) = channel();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+pub(crate) fn publish(event: StackEvent) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    // Drop the subscribers which have gone away.
+    subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
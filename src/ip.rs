@@ -0,0 +1,183 @@
+// IPv4 layer API
+
+pub use crate::ipv4::address::InterfaceAddr;
+pub use crate::ipv4::conntrack::{Connection, State as ConnectionState};
+pub use crate::ipv4::filter::{FilterEntry, Hook, PacketInfo, Rule, Verdict};
+pub use crate::ipv4::forward::ForwardingConfig;
+pub use crate::ipv4::nat::{NatMapping, NatRule};
+pub use crate::ipv4::options::Ipv4Option;
+pub use crate::ipv4::route::Route;
+pub use crate::ipv4::stats::Ipv4Stats;
+
+use crate::ethernet;
+use crate::ipv4::{address, conntrack, filter, forward, nat, route, stats};
+use crate::loopback;
+use crate::net_util::Cidr;
+use std::net::Ipv4Addr;
+
+// Counters of the packets dropped because of a bad header, or which couldn't be forwarded.
+pub fn stats() -> Ipv4Stats {
+    stats::stats()
+}
+
+// Names of the interfaces, the loopback one included.
+pub fn interfaces() -> Vec<String> {
+    let mut interfaces = vec![loopback::INTERFACE_NAME.to_string()];
+    interfaces.extend(ethernet::interfaces());
+    interfaces
+}
+
+// Largest packet sent on an interface without fragmenting it, 1500 by default for the ethernet ones.
+pub fn mtu(interface: &str) -> Result<usize, String> {
+    match interface {
+        loopback::INTERFACE_NAME => Ok(loopback::MTU),
+        interface if ethernet::exists(interface) => Ok(ethernet::mtu(interface)),
+        _ => Err(format!("Unknown interface {}", interface)),
+    }
+}
+
+pub fn set_mtu(interface: &str, mtu: usize) -> Result<(), String> {
+    match interface {
+        loopback::INTERFACE_NAME => Err("The loopback MTU can't be changed".to_string()),
+        _ => ethernet::set_mtu(interface, mtu),
+    }
+}
+
+// Whether the interface is administratively up, it is unless taken down with `set_link_up`.
+pub fn link_up(interface: &str) -> Result<bool, String> {
+    match interface {
+        loopback::INTERFACE_NAME => Ok(true),
+        interface if ethernet::exists(interface) => Ok(ethernet::is_up(interface)),
+        _ => Err(format!("Unknown interface {}", interface)),
+    }
+}
+
+// Brings an interface up or down while the stack runs, ex: set_link_up("eth0", false). The loopback stays up.
+pub fn set_link_up(interface: &str, up: bool) -> Result<(), String> {
+    match interface {
+        loopback::INTERFACE_NAME if up => Ok(()),
+        loopback::INTERFACE_NAME => Err("The loopback interface can't be taken down".to_string()),
+        _ => ethernet::set_up(interface, up),
+    }
+}
+
+// Assigns an address, written the way `ip addr add` takes it, ex: add_address("10.0.0.3/24 label eth0:1"). The
+// route to its subnet comes along.
+pub fn add_address(addr: &str) -> Result<(), String> {
+    address::add(InterfaceAddr::parse(addr)?)
+}
+
+// Removes an address, ex: delete_address("10.0.0.3/24 dev eth0").
+pub fn delete_address(addr: &str) -> Result<(), String> {
+    let addr = InterfaceAddr::parse(addr)?;
+    address::remove(&addr.interface, &addr.addr.addr())
+}
+
+pub fn addresses() -> Vec<InterfaceAddr> {
+    address::list()
+}
+
+// Adds a route, written the way `ip route add` takes it, ex: add_route("default via 10.0.0.1") or
+// add_route("10.0.1.0/24 via 10.0.0.3 metric 10").
+pub fn add_route(route: &str) -> Result<(), String> {
+    route::add(Route::parse(route)?)
+}
+
+// Removes the routes to `destination`, ex: "10.0.1.0/24" or "default".
+pub fn delete_route(destination: &str) -> Result<(), String> {
+    if route::remove(&route::parse_destination(destination)?) {
+        Ok(())
+    } else {
+        Err(format!("No route to {}", destination))
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    route::list()
+}
+
+// The route packets to `addr` take(like `ip route get <addr>`).
+pub fn route_get(addr: &str) -> Result<Route, String> {
+    let protocol_addr = addr
+        .trim()
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("Invalid address {}", addr))?
+        .octets();
+    route::lookup(&protocol_addr).ok_or_else(|| format!("Network is unreachable: {}", addr))
+}
+
+pub fn forwarding() -> ForwardingConfig {
+    forward::config()
+}
+
+// Turns router mode on or off, ex: set_forwarding(ForwardingConfig { enabled: true, ..ip::forwarding() }).
+pub fn set_forwarding(config: ForwardingConfig) {
+    forward::set_config(config);
+}
+
+// Gives the packets forwarded from `inside`(ex: "10.0.1.0/24") the address of `interface` as their source, and
+// translates the replies back. Only forwarded packets are translated, see `set_forwarding`.
+pub fn add_masquerade(inside: &str, interface: &str) -> Result<(), String> {
+    nat::add_rule(NatRule {
+        inside: Cidr::parse(inside)?,
+        interface: interface.to_string(),
+    })
+}
+
+pub fn delete_masquerade(inside: &str) -> Result<(), String> {
+    if nat::remove_rule(&Cidr::parse(inside)?) {
+        Ok(())
+    } else {
+        Err(format!("{} is not translated", inside))
+    }
+}
+
+pub fn nat_rules() -> Vec<NatRule> {
+    nat::rules()
+}
+
+// The flows being translated right now.
+pub fn nat_mappings() -> Vec<NatMapping> {
+    nat::mappings()
+}
+
+// Appends a rule to a hook's chain, written the way `ip filter add` takes it, ex: add_filter("input",
+// "proto udp dport 9000 drop"). Returns the rule's handle, which deletes it.
+pub fn add_filter(hook: &str, rule: &str) -> Result<u32, String> {
+    Ok(filter::add_rule(Hook::parse(hook)?, Rule::parse(rule)?))
+}
+
+// Appends a closure to a hook's chain, for the filters the rules can't express. Returns its handle.
+pub fn add_filter_hook<F>(hook: Hook, closure: F) -> u32
+where
+    F: Fn(&PacketInfo, &[u8]) -> Verdict + Send + Sync + 'static,
+{
+    filter::add_closure(hook, Box::new(closure))
+}
+
+pub fn delete_filter(handle: u32) -> Result<(), String> {
+    if filter::remove(handle) {
+        Ok(())
+    } else {
+        Err(format!("No filter with handle {}", handle))
+    }
+}
+
+// Empties a hook's chain, or every chain when `hook` is None.
+pub fn flush_filters(hook: Option<&str>) -> Result<(), String> {
+    filter::flush(hook.map(Hook::parse).transpose()?);
+    Ok(())
+}
+
+pub fn filters() -> Vec<FilterEntry> {
+    filter::list()
+}
+
+// The flows connection tracking knows about(like `conntrack -L`).
+pub fn connections() -> Vec<Connection> {
+    conntrack::list()
+}
+
+pub fn flush_connections() {
+    conntrack::flush();
+}
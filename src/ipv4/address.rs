@@ -0,0 +1,310 @@
+// Addresses assigned to the interfaces
+// Reference: https://tools.ietf.org/html/rfc1122#section-3.3.6, https://tools.ietf.org/html/rfc3021(/31 subnets)
+//
+// Every address comes with its prefix length, and the subnet broadcast address. Addresses in a subnet the
+// interface already has an address in are secondary ones, like with linux.
+
+use crate::arp::acd;
+use crate::ethernet::{self, ProtocolAddr};
+use crate::events::{self, StackEvent};
+use crate::ipv4::route;
+use crate::ipv4::udp_socket;
+use crate::loopback;
+use crate::net_util::Cidr;
+use lazy_static::lazy_static;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+
+pub const LIMITED_BROADCAST: ProtocolAddr = [255, 255, 255, 255];
+
+lazy_static! {
+    // The link's addresses come from `start_stack`, DHCP or `ip addr add`.
+    static ref ADDRESSES: RwLock<Vec<InterfaceAddr>> = RwLock::new(vec![InterfaceAddr::new(
+        loopback::INTERFACE_NAME,
+        Cidr::new(loopback::ADDR, 8).unwrap()
+    )]);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceAddr {
+    pub interface: String,
+    // The address along with its prefix length, ex: 10.0.0.2/24
+    pub addr: Cidr,
+    // None for the loopback, and for the /31 and /32 prefixes which have no room for one.
+    pub broadcast: Option<ProtocolAddr>,
+    // Alias the address goes by, the interface's name by default(ex: eth0:1).
+    pub label: Option<String>,
+    pub secondary: bool,
+}
+
+impl InterfaceAddr {
+    // A primary address, with the subnet's broadcast address.
+    pub fn new(interface: &str, addr: Cidr) -> Self {
+        let broadcast = if interface == loopback::INTERFACE_NAME || addr.prefix_len() >= 31 {
+            None
+        } else {
+            Some(addr.broadcast())
+        };
+        InterfaceAddr {
+            interface: interface.to_string(),
+            addr,
+            broadcast,
+            label: None,
+            secondary: false,
+        }
+    }
+
+    // Parses an `ip addr add` style address: `<addr>[/<prefix_len>] [dev <interface>] [brd <broadcast>]
+    // [label <label>]`, ex: "10.0.0.3/24 dev eth0 label eth0:1". Addresses go to eth0 unless said otherwise.
+    pub fn parse(input: &str) -> Result<InterfaceAddr, String> {
+        let mut args = input.split_whitespace();
+        let addr = Cidr::parse(args.next().ok_or("Missing address")?)?;
+        let mut interface = ethernet::INTERFACE_NAME.to_string();
+        let mut broadcast = None;
+        let mut label = None;
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+            match arg {
+                "dev" => interface = value.to_string(),
+                "brd" => {
+                    let addr = value
+                        .parse::<Ipv4Addr>()
+                        .map_err(|_| format!("Invalid address {}", value))?;
+                    broadcast = Some(addr.octets())
+                }
+                "label" => label = Some(value.to_string()),
+                _ => return Err(format!("Unknown address attribute {}", arg)),
+            }
+        }
+        let mut interface_addr = InterfaceAddr::new(&interface, addr);
+        if broadcast.is_some() {
+            interface_addr.broadcast = broadcast;
+        }
+        interface_addr.label = label;
+        Ok(interface_addr)
+    }
+}
+
+impl fmt::Display for InterfaceAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if let Some(broadcast) = self.broadcast {
+            write!(f, " brd {}", Ipv4Addr::from(broadcast))?;
+        }
+        write!(f, " dev {}", self.interface)?;
+        if let Some(label) = &self.label {
+            write!(f, " label {}", label)?;
+        }
+        if self.secondary {
+            write!(f, " secondary")?;
+        }
+        Ok(())
+    }
+}
+
+fn known_interface(interface: &str) -> bool {
+    ethernet::exists(interface) || interface == loopback::INTERFACE_NAME
+}
+
+fn validate(addresses: &[InterfaceAddr], new: &InterfaceAddr) -> Result<(), String> {
+    if !known_interface(&new.interface) {
+        return Err(format!("Unknown interface {}", new.interface));
+    }
+    if let Some(label) = &new.label {
+        if !label.starts_with(&new.interface) {
+            return Err(format!(
+                "Label {} must start with the interface name {}",
+                label, new.interface
+            ));
+        }
+    }
+    let addr = new.addr.addr();
+    if addr == [0, 0, 0, 0] || addr == LIMITED_BROADCAST || addr[0] >= 224 {
+        return Err(format!("Invalid address {}", Ipv4Addr::from(addr)));
+    }
+    if addresses.iter().any(|other| other.addr.addr() == addr) {
+        return Err(format!("Address {} already assigned", Ipv4Addr::from(addr)));
+    }
+    Ok(())
+}
+
+// Assigns an address, along with the route to its subnet. The neighbors learn about it with a gratuitous ARP.
+pub fn add(mut new: InterfaceAddr) -> Result<(), String> {
+    let mut addresses = ADDRESSES.write().unwrap();
+    validate(&addresses, &new)?;
+    new.secondary = addresses
+        .iter()
+        .any(|other| other.interface == new.interface && other.addr.same_network(&new.addr));
+    if !new.secondary && new.interface != loopback::INTERFACE_NAME && new.addr.prefix_len() < 32 {
+        route::add_link_route(&new.interface, new.addr)?;
+    }
+    addresses.push(new.clone());
+    drop(addresses);
+
+    if ethernet::is_up(&new.interface) {
+        announce(&new.interface, new.addr.addr());
+    }
+    events::publish(StackEvent::AddressAdded {
+        interface: new.interface,
+        addr: new.addr,
+    });
+    Ok(())
+}
+
+// Removes the address `addr` from `interface`. Removing a primary address promotes the next secondary one in the
+// same subnet, the subnet's route goes away with the last of them. The sockets bound to the address are told about it.
+pub fn remove(interface: &str, addr: &ProtocolAddr) -> Result<(), String> {
+    let mut addresses = ADDRESSES.write().unwrap();
+    let index = addresses
+        .iter()
+        .position(|entry| entry.interface == interface && entry.addr.addr() == *addr)
+        .ok_or_else(|| {
+            format!(
+                "Address {} not assigned to {}",
+                Ipv4Addr::from(*addr),
+                interface
+            )
+        })?;
+    let removed = addresses.remove(index);
+    if !removed.secondary {
+        let promoted = addresses.iter_mut().find(|entry| {
+            entry.interface == removed.interface && entry.addr.same_network(&removed.addr)
+        });
+        match promoted {
+            Some(promoted) => {
+                promoted.secondary = false;
+                route::replace_link_route_src(&removed.addr, promoted.addr.addr());
+            }
+            None => route::remove_link_route(&removed.addr),
+        }
+    }
+    drop(addresses);
+
+    acd::release(addr);
+    udp_socket::report_addr_error(*addr, "Cannot assign requested address");
+    events::publish(StackEvent::AddressRemoved {
+        interface: removed.interface,
+        addr: removed.addr,
+    });
+    Ok(())
+}
+
+// Gratuitous ARP(RFC 5227 2.3) for an address of the link, so that the neighbors which have it cached for some other
+// host learn about us right away. Nothing to do till the stack is started.
+pub(crate) fn announce(interface: &str, addr: ProtocolAddr) {
+    if let Some((writer, hw_addr)) = ethernet::link(interface) {
+        let _ = acd::announce(addr, hw_addr, &writer);
+    }
+}
+
+pub fn list() -> Vec<InterfaceAddr> {
+    ADDRESSES.read().unwrap().clone()
+}
+
+// Whether `addr` is assigned to one of the interfaces.
+pub fn is_local(addr: &ProtocolAddr) -> bool {
+    ADDRESSES
+        .read()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.addr.addr() == *addr)
+}
+
+// Whether `addr` is assigned to `interface`.
+pub fn is_assigned(interface: &str, addr: &ProtocolAddr) -> bool {
+    ADDRESSES
+        .read()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.interface == interface && entry.addr.addr() == *addr)
+}
+
+// Whether `addr` is the limited broadcast address, or the broadcast address of one of the interfaces' subnets.
+pub fn is_broadcast(addr: &ProtocolAddr) -> bool {
+    *addr == LIMITED_BROADCAST
+        || ADDRESSES
+            .read()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.broadcast == Some(*addr))
+}
+
+// The interface's primary address in the subnet `dst` is in, or its first primary address otherwise.
+pub fn primary_addr(interface: &str, dst: &ProtocolAddr) -> Option<ProtocolAddr> {
+    let addresses = ADDRESSES.read().unwrap();
+    let mut primaries = addresses
+        .iter()
+        .filter(|entry| entry.interface == interface && !entry.secondary);
+    let first = primaries.clone().next().map(|entry| entry.addr.addr());
+    primaries
+        .find(|entry| entry.addr.contains(dst))
+        .map(|entry| entry.addr.addr())
+        .or(first)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for addr in &[
+            "10.0.0.3/24 brd 10.0.0.255 dev eth0 label eth0:1",
+            "10.1.0.1/31 dev eth0",
+            "127.0.0.2/8 dev lo",
+        ] {
+            assert_eq!(InterfaceAddr::parse(addr).unwrap().to_string(), *addr);
+        }
+        // A bare address is a /32
+        assert_eq!(
+            InterfaceAddr::parse("10.0.0.3").unwrap().to_string(),
+            "10.0.0.3/32 dev eth0"
+        );
+        assert!(InterfaceAddr::parse("10.0.0.3/24 brd").is_err());
+        assert!(InterfaceAddr::parse("10.0.0.3/24 scope global").is_err());
+    }
+
+    #[test]
+    fn test_secondary_addresses() {
+        let primary = [192, 168, 77, 2];
+        let secondary = [192, 168, 77, 3];
+        add(InterfaceAddr::parse("192.168.77.2/24").unwrap()).unwrap();
+        add(InterfaceAddr::parse("192.168.77.3/24 label eth0:1").unwrap()).unwrap();
+        assert!(is_local(&secondary) && is_assigned(ethernet::INTERFACE_NAME, &secondary));
+        assert!(is_broadcast(&[192, 168, 77, 255]));
+        assert!(list().iter().any(|entry| entry.to_string()
+            == "192.168.77.3/24 brd 192.168.77.255 dev eth0 label eth0:1 secondary"));
+        assert_eq!(route::source_addr(&[192, 168, 77, 9]), primary);
+
+        // The secondary address takes over
+        remove(ethernet::INTERFACE_NAME, &primary).unwrap();
+        assert!(!is_local(&primary));
+        assert_eq!(route::source_addr(&[192, 168, 77, 9]), secondary);
+        remove(ethernet::INTERFACE_NAME, &secondary).unwrap();
+        // Along with the subnet's route
+        let subnet = Cidr::parse("192.168.77.0/24").unwrap();
+        assert!(!route::list()
+            .iter()
+            .any(|route| route.destination.same_network(&subnet)));
+        assert!(!is_broadcast(&[192, 168, 77, 255]));
+        assert!(remove(ethernet::INTERFACE_NAME, &secondary).is_err());
+    }
+
+    #[test]
+    fn test_add_rejects_invalid_addresses() {
+        add(InterfaceAddr::parse("192.168.78.2/24").unwrap()).unwrap();
+        for addr in &[
+            "192.168.78.2/24",
+            "192.168.78.1/24 dev eth9",
+            "192.168.78.1/24 label wlan0:1",
+            "255.255.255.255/32",
+            "224.0.0.1/4",
+        ] {
+            assert!(add(InterfaceAddr::parse(addr).unwrap()).is_err());
+        }
+        remove(ethernet::INTERFACE_NAME, &[192, 168, 78, 2]).unwrap();
+    }
+}
@@ -0,0 +1,394 @@
+// Connection tracking, for the packet filter's stateful rules(ex: "state established,related accept").
+// Reference: https://people.netfilter.org/pablo/docs/login.pdf
+//
+// UDP flows and ICMP echo exchanges are tracked, TCP will be once there is TCP. A flow is NEW until a packet goes
+// the other way, ESTABLISHED from then on; ICMP errors quoting a packet of a tracked flow are RELATED to it.
+// Packets of the input, forward and output hooks are tracked once the hook's filter accepts them, so dropped
+// packets never start a flow.
+
+use crate::ethernet::ProtocolAddr;
+use crate::ipv4::icmp::icmp::{
+    DEST_UNREACHABLE, ECHO_REPLY, ECHO_REQ, ICMP_HEADER_LEN, PARAMETER_PROBLEM, REDIRECT,
+    SOURCE_QUENCH, TIME_EXCEEDED,
+};
+use crate::ipv4::{self, IPv4, ICMP_PROTO, UDP_PROTO};
+use crate::net_util;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Same as linux's nf_conntrack_udp_timeout, nf_conntrack_udp_timeout_stream and nf_conntrack_icmp_timeout.
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(30);
+pub const UDP_STREAM_TIMEOUT: Duration = Duration::from_secs(120);
+pub const ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+// Flows past the limit aren't tracked, their packets have no state.
+const MAX_CONNECTIONS: usize = 4096;
+
+// ICMP error types quoting the offending packet(RFC 792): destination unreachable, source quench, redirect, time
+// exceeded and parameter problem.
+const ICMP_ERRORS: [u8; 5] = [
+    DEST_UNREACHABLE,
+    SOURCE_QUENCH,
+    REDIRECT,
+    TIME_EXCEEDED,
+    PARAMETER_PROBLEM,
+];
+
+lazy_static! {
+    static ref CONNTRACK: Mutex<Conntrack> = Mutex::new(Conntrack::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    New,
+    Established,
+    Related,
+}
+
+impl State {
+    pub fn parse(state: &str) -> Result<State, String> {
+        match state {
+            "new" => Ok(State::New),
+            "established" => Ok(State::Established),
+            "related" => Ok(State::Related),
+            _ => Err(format!("Unknown connection state {}", state)),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            State::New => "new",
+            State::Established => "established",
+            State::Related => "related",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// One direction of a flow, ports being echo identifiers for ICMP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tuple {
+    pub proto: u8,
+    pub src: (ProtocolAddr, u16),
+    pub dst: (ProtocolAddr, u16),
+}
+
+impl Tuple {
+    fn reply(&self) -> Tuple {
+        Tuple {
+            proto: self.proto,
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    replied: bool,
+    last_seen: Instant,
+}
+
+impl Entry {
+    fn timeout(&self, proto: u8) -> Duration {
+        match (proto, self.replied) {
+            (ICMP_PROTO, _) => ICMP_TIMEOUT,
+            (_, true) => UDP_STREAM_TIMEOUT,
+            (_, false) => UDP_TIMEOUT,
+        }
+    }
+}
+
+// A tracked flow, as dumped to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    // Direction of the flow's first packet
+    pub orig: Tuple,
+    pub state: State,
+    pub expires: Duration,
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} expires {}s",
+            ipv4::flow_to_string(self.orig.proto, &[self.orig.src, self.orig.dst]),
+            self.state,
+            self.expires.as_secs()
+        )
+    }
+}
+
+#[derive(Default)]
+struct Conntrack {
+    // Flows by the tuple of their first packet
+    connections: HashMap<Tuple, Entry>,
+    // Original tuples by reply tuple
+    replies: HashMap<Tuple, Tuple>,
+}
+
+impl Conntrack {
+    fn track(&mut self, packet: &Packet, now: Instant) {
+        self.expire(now);
+        let (tuple, can_start) = match packet.tuple() {
+            Some(tuple) => tuple,
+            None => return,
+        };
+        if let Some(entry) = self.connections.get_mut(&tuple) {
+            entry.last_seen = now;
+        } else if let Some(orig) = self.replies.get(&tuple) {
+            let entry = self.connections.get_mut(orig).unwrap();
+            entry.replied = true;
+            entry.last_seen = now;
+        } else if can_start && self.connections.len() < MAX_CONNECTIONS {
+            self.connections.insert(
+                tuple,
+                Entry {
+                    replied: false,
+                    last_seen: now,
+                },
+            );
+            self.replies.insert(tuple.reply(), tuple);
+        }
+    }
+
+    // Untracked packets have no state.
+    fn state(&self, packet: &Packet) -> Option<State> {
+        if let Some(quoted) = packet.quoted_tuple() {
+            let tracked =
+                self.connections.contains_key(&quoted) || self.replies.contains_key(&quoted);
+            return if tracked { Some(State::Related) } else { None };
+        }
+        let (tuple, can_start) = packet.tuple()?;
+        match self.connections.get(&tuple) {
+            Some(entry) if entry.replied => Some(State::Established),
+            Some(_) => Some(State::New),
+            None if self.replies.contains_key(&tuple) => Some(State::Established),
+            // Not seen yet, ex: in the prerouting hook
+            None if can_start => Some(State::New),
+            None => None,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let replies = &mut self.replies;
+        self.connections.retain(|tuple, entry| {
+            let alive = now.duration_since(entry.last_seen) < entry.timeout(tuple.proto);
+            if !alive {
+                replies.remove(&tuple.reply());
+            }
+            alive
+        });
+    }
+}
+
+// The parts of a packet conntrack looks at.
+struct Packet<'a> {
+    src: ProtocolAddr,
+    dst: ProtocolAddr,
+    proto: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    // None for the fragments but the first one, which have no ports.
+    fn from_ipv4(packet: &'a IPv4) -> Option<Packet<'a>> {
+        if packet.fragment_offset() != 0 {
+            return None;
+        }
+        let proto = packet.proto();
+        if proto != ICMP_PROTO && proto != UDP_PROTO {
+            return None;
+        }
+        Some(Packet {
+            src: packet.src,
+            dst: packet.dst,
+            proto,
+            payload: packet.payload_bytes(),
+        })
+    }
+
+    // The packet's tuple, and whether the packet may start a flow: echo replies never do.
+    fn tuple(&self) -> Option<(Tuple, bool)> {
+        if self.payload.len() < 8 {
+            return None;
+        }
+        let (src_port, dst_port, can_start) = match self.proto {
+            UDP_PROTO => (
+                net_util::ntohs(&self.payload[0..2]),
+                net_util::ntohs(&self.payload[2..4]),
+                true,
+            ),
+            ICMP_PROTO if self.payload[0] == ECHO_REQ || self.payload[0] == ECHO_REPLY => {
+                let id = net_util::ntohs(&self.payload[4..6]);
+                (id, id, self.payload[0] == ECHO_REQ)
+            }
+            _ => return None,
+        };
+        let tuple = Tuple {
+            proto: self.proto,
+            src: (self.src, src_port),
+            dst: (self.dst, dst_port),
+        };
+        Some((tuple, can_start))
+    }
+
+    // Tuple of the packet an ICMP error quotes, None when the packet isn't an ICMP error.
+    fn quoted_tuple(&self) -> Option<Tuple> {
+        if self.proto != ICMP_PROTO || !ICMP_ERRORS.contains(self.payload.first()?) {
+            return None;
+        }
+        let quoted = self.payload.get(ICMP_HEADER_LEN..)?;
+        let header_len = net_util::get_bits(*quoted.first()?, 0..4) as usize * 4;
+        if quoted.len() < header_len + 8 || header_len < 20 {
+            return None;
+        }
+        let quoted = Packet {
+            src: quoted[12..16].try_into().unwrap(),
+            dst: quoted[16..20].try_into().unwrap(),
+            proto: quoted[9],
+            payload: &quoted[header_len..],
+        };
+        quoted.tuple().map(|(tuple, _)| tuple)
+    }
+}
+
+// Accounts `packet` to its flow, starting a new one when it's the first packet of the flow.
+pub fn track(packet: &IPv4) {
+    if let Some(packet) = Packet::from_ipv4(packet) {
+        CONNTRACK.lock().unwrap().track(&packet, Instant::now());
+    }
+}
+
+pub fn state(packet: &IPv4) -> Option<State> {
+    let packet = Packet::from_ipv4(packet)?;
+    CONNTRACK.lock().unwrap().state(&packet)
+}
+
+pub fn list() -> Vec<Connection> {
+    let now = Instant::now();
+    let mut conntrack = CONNTRACK.lock().unwrap();
+    conntrack.expire(now);
+    conntrack
+        .connections
+        .iter()
+        .map(|(tuple, entry)| Connection {
+            orig: *tuple,
+            state: if entry.replied {
+                State::Established
+            } else {
+                State::New
+            },
+            expires: entry.timeout(tuple.proto) - now.duration_since(entry.last_seen),
+        })
+        .collect()
+}
+
+pub fn flush() {
+    let mut conntrack = CONNTRACK.lock().unwrap();
+    conntrack.connections.clear();
+    conntrack.replies.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // UDP header from `src_port` to `dst_port`
+    fn udp(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut payload = src_port.to_be_bytes().to_vec();
+        payload.extend_from_slice(&dst_port.to_be_bytes());
+        payload.extend_from_slice(&[0, 8, 0, 0]);
+        payload
+    }
+
+    fn packet(src: ProtocolAddr, dst: ProtocolAddr, proto: u8, payload: &[u8]) -> Packet<'_> {
+        Packet {
+            src,
+            dst,
+            proto,
+            payload,
+        }
+    }
+
+    const HOST: ProtocolAddr = [10, 0, 0, 2];
+    const REMOTE: ProtocolAddr = [8, 8, 8, 8];
+
+    #[test]
+    fn test_udp_flow() {
+        let mut conntrack = Conntrack::default();
+        let now = Instant::now();
+        let request = udp(5000, 53);
+        let request = packet(HOST, REMOTE, UDP_PROTO, &request);
+        let reply = udp(53, 5000);
+        let reply = packet(REMOTE, HOST, UDP_PROTO, &reply);
+
+        assert_eq!(conntrack.state(&request), Some(State::New));
+        conntrack.track(&request, now);
+        assert_eq!(conntrack.state(&request), Some(State::New));
+        assert_eq!(conntrack.state(&reply), Some(State::Established));
+        conntrack.track(&reply, now);
+        assert_eq!(conntrack.state(&request), Some(State::Established));
+
+        // Replied flows last longer
+        conntrack.expire(now + UDP_TIMEOUT);
+        assert_eq!(conntrack.connections.len(), 1);
+        conntrack.expire(now + UDP_STREAM_TIMEOUT);
+        assert!(conntrack.connections.is_empty() && conntrack.replies.is_empty());
+    }
+
+    #[test]
+    fn test_icmp() {
+        let mut conntrack = Conntrack::default();
+        let now = Instant::now();
+        let echo = |msg_type: u8| vec![msg_type, 0, 0, 0, 0x12, 0x34, 0, 1];
+        let (request, reply) = (echo(ECHO_REQ), echo(ECHO_REPLY));
+        let reply = packet(REMOTE, HOST, ICMP_PROTO, &reply);
+        // Echo replies don't start flows
+        assert_eq!(conntrack.state(&reply), None);
+        conntrack.track(&reply, now);
+        assert!(conntrack.connections.is_empty());
+
+        conntrack.track(&packet(HOST, REMOTE, ICMP_PROTO, &request), now);
+        assert_eq!(conntrack.state(&reply), Some(State::Established));
+
+        // Port unreachable quoting a datagram of a tracked flow
+        let datagram = udp(5000, 53);
+        conntrack.track(&packet(HOST, REMOTE, UDP_PROTO, &datagram), now);
+        let mut error = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        error.extend_from_slice(&[0x45, 0, 0, 28, 0, 0, 0, 0, 64, UDP_PROTO, 0, 0]);
+        error.extend_from_slice(&HOST);
+        error.extend_from_slice(&REMOTE);
+        error.extend_from_slice(&datagram);
+        let error_packet = packet(REMOTE, HOST, ICMP_PROTO, &error);
+        assert_eq!(conntrack.state(&error_packet), Some(State::Related));
+        error[8 + 20..8 + 22].copy_from_slice(&5001u16.to_be_bytes());
+        assert_eq!(
+            conntrack.state(&packet(REMOTE, HOST, ICMP_PROTO, &error)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let connection = Connection {
+            orig: Tuple {
+                proto: UDP_PROTO,
+                src: (HOST, 5000),
+                dst: (REMOTE, 53),
+            },
+            state: State::Established,
+            expires: Duration::from_secs(120),
+        };
+        assert_eq!(
+            connection.to_string(),
+            "udp 10.0.0.2:5000 -> 8.8.8.8:53 established expires 120s"
+        );
+    }
+}
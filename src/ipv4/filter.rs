@@ -0,0 +1,602 @@
+// Packet filter, netfilter like hooks on the packets' way through the stack.
+// Reference: https://www.netfilter.org/documentation/HOWTO/netfilter-hacking-HOWTO-3.html
+//
+// prerouting: every packet received, before it's reassembled or routed
+// input:      packets delivered to the stack, once reassembled
+// forward:    packets routed on to another host
+// output:     packets sent by the stack
+//
+// The entries of a hook's chain are tried in order: the first one accepting, dropping or rejecting the packet
+// decides its fate, mangling entries change it and go on with the next entry. Packets making it through the
+// whole chain are accepted.
+
+use crate::ethernet::ProtocolAddr;
+use crate::ipv4::conntrack::{self, State};
+use crate::ipv4::icmp::ICMP;
+use crate::ipv4::stats::{self, DropReason};
+use crate::ipv4::{
+    proto_name, IPstackWriter, IPv4, ICMP_PROTO, MIN_HEADER_LEN, TCP_PROTO, UDP_PROTO,
+};
+use crate::net_util::{self, Cidr};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref FILTER: RwLock<Filter> = RwLock::new(Filter::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    Prerouting,
+    Input,
+    Forward,
+    Output,
+}
+
+impl Hook {
+    pub fn parse(hook: &str) -> Result<Hook, String> {
+        match hook {
+            "prerouting" => Ok(Hook::Prerouting),
+            "input" => Ok(Hook::Input),
+            "forward" => Ok(Hook::Forward),
+            "output" => Ok(Hook::Output),
+            _ => Err(format!("Unknown hook {}", hook)),
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Hook::Prerouting => "prerouting",
+            Hook::Input => "input",
+            Hook::Forward => "forward",
+            Hook::Output => "output",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+    // Drop the packet and let its sender know, with an ICMP port unreachable error(like iptables' REJECT).
+    Reject,
+    // Replace the packet with the given one and go on with the chain. The header checksum is computed again, the
+    // rest is up to the one mangling the packet.
+    Mangle(Vec<u8>),
+}
+
+// What the rules match on, taken from the packet's headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketInfo {
+    // Interface the packet came in through, or goes out of for the output hook.
+    pub interface: String,
+    pub src: ProtocolAddr,
+    pub dst: ProtocolAddr,
+    pub proto: u8,
+    // UDP and TCP ports, None for the other protocols and for the fragments but the first one.
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    // Connection tracking state, None for the packets which aren't tracked.
+    pub state: Option<State>,
+}
+
+impl PacketInfo {
+    // None when `packet` isn't a valid IPv4 packet, it's left to the IPv4 layer to drop it then.
+    fn from_bytes(interface: &str, bytes: &[u8]) -> Option<PacketInfo> {
+        let packet = IPv4::parse(bytes).ok()?;
+        let proto = bytes[9];
+        let payload = packet.payload_bytes();
+        let has_ports = (proto == UDP_PROTO || proto == TCP_PROTO)
+            && packet.fragment_offset() == 0
+            && payload.len() >= 4;
+        Some(PacketInfo {
+            interface: interface.to_string(),
+            src: packet.src,
+            dst: packet.dst,
+            proto,
+            src_port: if has_ports {
+                Some(net_util::ntohs(&payload[0..2]))
+            } else {
+                None
+            },
+            dst_port: if has_ports {
+                Some(net_util::ntohs(&payload[2..4]))
+            } else {
+                None
+            },
+            state: conntrack::state(&packet),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Accept,
+    Drop,
+    Reject,
+    // Mangling targets, set the type of service or the TTL of the packet.
+    SetTos(u8),
+    SetTtl(u8),
+}
+
+// A rule matching the packets on all of its criteria, the ones left to None match every packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub interface: Option<String>,
+    pub src: Option<Cidr>,
+    pub dst: Option<Cidr>,
+    pub proto: Option<u8>,
+    pub src_port: Option<RangeInclusive<u16>>,
+    pub dst_port: Option<RangeInclusive<u16>>,
+    // Matches the packets in any of the connection states.
+    pub states: Option<Vec<State>>,
+    pub target: Target,
+}
+
+impl Rule {
+    // A rule matching every packet.
+    pub fn new(target: Target) -> Self {
+        Rule {
+            interface: None,
+            src: None,
+            dst: None,
+            proto: None,
+            src_port: None,
+            dst_port: None,
+            states: None,
+            target,
+        }
+    }
+
+    // Parses a rule: `[dev <interface>] [src <prefix>] [dst <prefix>] [proto <udp|tcp|icmp|number>]
+    // [sport <port[-port]>] [dport <port[-port]>] [state <state[,state]>] <accept | drop | reject | tos <tos> |
+    // ttl <ttl>>`, ex: "proto udp dport 9000-9010 drop" or "state established,related accept".
+    pub fn parse(input: &str) -> Result<Rule, String> {
+        let mut rule = Rule::new(Target::Accept);
+        let mut target = None;
+        let mut args = input.split_whitespace();
+        while let Some(arg) = args.next() {
+            if target.is_some() {
+                return Err(format!("Unexpected {} after the rule's target", arg));
+            }
+            match arg {
+                "accept" => target = Some(Target::Accept),
+                "drop" => target = Some(Target::Drop),
+                "reject" => target = Some(Target::Reject),
+                _ => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("Missing value for {}", arg))?;
+                    match arg {
+                        "dev" => rule.interface = Some(value.to_string()),
+                        "src" => rule.src = Some(parse_prefix(value)?),
+                        "dst" => rule.dst = Some(parse_prefix(value)?),
+                        "proto" => rule.proto = Some(parse_proto(value)?),
+                        "sport" => rule.src_port = Some(parse_ports(value)?),
+                        "dport" => rule.dst_port = Some(parse_ports(value)?),
+                        "state" => {
+                            let states = value.split(',').map(State::parse);
+                            rule.states = Some(states.collect::<Result<_, _>>()?)
+                        }
+                        "tos" => target = Some(Target::SetTos(parse_u8(value)?)),
+                        "ttl" => target = Some(Target::SetTtl(parse_u8(value)?)),
+                        _ => return Err(format!("Unknown rule attribute {}", arg)),
+                    }
+                }
+            }
+        }
+        rule.target = target.ok_or("Missing rule target: accept, drop, reject, tos or ttl")?;
+        Ok(rule)
+    }
+
+    pub fn matches(&self, packet: &PacketInfo) -> bool {
+        let port_matches = |ports: &Option<RangeInclusive<u16>>, port: Option<u16>| match ports {
+            Some(ports) => port.map_or(false, |port| ports.contains(&port)),
+            None => true,
+        };
+        self.interface
+            .as_ref()
+            .map_or(true, |interface| *interface == packet.interface)
+            && self.src.map_or(true, |src| src.contains(&packet.src))
+            && self.dst.map_or(true, |dst| dst.contains(&packet.dst))
+            && self.proto.map_or(true, |proto| proto == packet.proto)
+            && port_matches(&self.src_port, packet.src_port)
+            && port_matches(&self.dst_port, packet.dst_port)
+            && self.states.as_ref().map_or(true, |states| {
+                packet.state.map_or(false, |state| states.contains(&state))
+            })
+    }
+
+    fn apply(&self, packet: &[u8]) -> Verdict {
+        match self.target {
+            Target::Accept => Verdict::Accept,
+            Target::Drop => Verdict::Drop,
+            Target::Reject => Verdict::Reject,
+            Target::SetTos(tos) => {
+                let mut mangled = packet.to_vec();
+                mangled[1] = tos;
+                Verdict::Mangle(mangled)
+            }
+            Target::SetTtl(ttl) => {
+                let mut mangled = packet.to_vec();
+                mangled[8] = ttl;
+                Verdict::Mangle(mangled)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ports = |ports: &RangeInclusive<u16>| {
+            if ports.start() == ports.end() {
+                ports.start().to_string()
+            } else {
+                format!("{}-{}", ports.start(), ports.end())
+            }
+        };
+        if let Some(interface) = &self.interface {
+            write!(f, "dev {} ", interface)?;
+        }
+        if let Some(src) = &self.src {
+            write!(f, "src {} ", src)?;
+        }
+        if let Some(dst) = &self.dst {
+            write!(f, "dst {} ", dst)?;
+        }
+        if let Some(proto) = self.proto {
+            write!(f, "proto {} ", proto_name(proto))?;
+        }
+        if let Some(src_port) = &self.src_port {
+            write!(f, "sport {} ", ports(src_port))?;
+        }
+        if let Some(dst_port) = &self.dst_port {
+            write!(f, "dport {} ", ports(dst_port))?;
+        }
+        if let Some(states) = &self.states {
+            let states: Vec<String> = states.iter().map(|state| state.to_string()).collect();
+            write!(f, "state {} ", states.join(","))?;
+        }
+        match self.target {
+            Target::Accept => write!(f, "accept"),
+            Target::Drop => write!(f, "drop"),
+            Target::Reject => write!(f, "reject"),
+            Target::SetTos(tos) => write!(f, "tos {}", tos),
+            Target::SetTtl(ttl) => write!(f, "ttl {}", ttl),
+        }
+    }
+}
+
+// Hooks written as closures, for what the rules can't express. They're given the packet's headers along with the
+// packet itself.
+pub type FilterFn = Box<dyn Fn(&PacketInfo, &[u8]) -> Verdict + Send + Sync>;
+
+enum Action {
+    Rule(Rule),
+    Closure(FilterFn),
+}
+
+struct Entry {
+    handle: u32,
+    action: Action,
+}
+
+// An entry of a hook's chain, as listed to the user. `rule` is None for the closures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterEntry {
+    pub hook: Hook,
+    pub handle: u32,
+    pub rule: Option<Rule>,
+}
+
+impl fmt::Display for FilterEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.rule {
+            Some(rule) => write!(f, "{} {} handle {}", self.hook, rule, self.handle),
+            None => write!(f, "{} closure handle {}", self.hook, self.handle),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Filter {
+    chains: HashMap<Hook, Vec<Entry>>,
+    next_handle: u32,
+}
+
+impl Filter {
+    // Appends an entry to the hook's chain and returns its handle, which deletes it later on.
+    fn add(&mut self, hook: Hook, action: Action) -> u32 {
+        self.next_handle += 1;
+        let handle = self.next_handle;
+        self.chains
+            .entry(hook)
+            .or_default()
+            .push(Entry { handle, action });
+        handle
+    }
+
+    fn remove(&mut self, handle: u32) -> bool {
+        self.chains.values_mut().any(|chain| {
+            let len = chain.len();
+            chain.retain(|entry| entry.handle != handle);
+            chain.len() != len
+        })
+    }
+
+    fn run(&self, hook: Hook, interface: &str, packet: &mut Vec<u8>) -> Verdict {
+        let chain = match self.chains.get(&hook) {
+            Some(chain) => chain,
+            None => return Verdict::Accept,
+        };
+        let mut info = match PacketInfo::from_bytes(interface, packet) {
+            Some(info) => info,
+            None => return Verdict::Accept,
+        };
+        for entry in chain {
+            let verdict = match &entry.action {
+                Action::Rule(rule) if rule.matches(&info) => rule.apply(packet),
+                Action::Rule(_) => continue,
+                Action::Closure(closure) => closure(&info, packet),
+            };
+            match verdict {
+                Verdict::Mangle(mut mangled) => {
+                    if mangled.len() >= MIN_HEADER_LEN {
+                        let header_len = net_util::get_bits(mangled[0], 0..4) as usize * 4;
+                        let header_len = std::cmp::min(header_len, mangled.len());
+                        let (checksum, _) =
+                            net_util::compute_ip_checksum(&mangled[..header_len], 10..12);
+                        mangled[10..12].copy_from_slice(&checksum.to_be_bytes());
+                    }
+                    // A mangled packet that isn't one anymore is dropped.
+                    info = match PacketInfo::from_bytes(interface, &mangled) {
+                        Some(info) => info,
+                        None => return Verdict::Drop,
+                    };
+                    *packet = mangled;
+                }
+                verdict => return verdict,
+            }
+        }
+        Verdict::Accept
+    }
+}
+
+pub fn add_rule(hook: Hook, rule: Rule) -> u32 {
+    FILTER.write().unwrap().add(hook, Action::Rule(rule))
+}
+
+// Closures run with the filter locked, they mustn't add or remove entries themselves.
+pub fn add_closure(hook: Hook, closure: FilterFn) -> u32 {
+    FILTER.write().unwrap().add(hook, Action::Closure(closure))
+}
+
+pub fn remove(handle: u32) -> bool {
+    FILTER.write().unwrap().remove(handle)
+}
+
+// Empties the hook's chain, or every chain when `hook` is None.
+pub fn flush(hook: Option<Hook>) {
+    let mut filter = FILTER.write().unwrap();
+    match hook {
+        Some(hook) => {
+            filter.chains.remove(&hook);
+        }
+        None => filter.chains.clear(),
+    }
+}
+
+pub fn list() -> Vec<FilterEntry> {
+    let filter = FILTER.read().unwrap();
+    let mut entries = Vec::new();
+    for hook in &[Hook::Prerouting, Hook::Input, Hook::Forward, Hook::Output] {
+        for entry in filter.chains.get(hook).into_iter().flatten() {
+            entries.push(FilterEntry {
+                hook: *hook,
+                handle: entry.handle,
+                rule: match &entry.action {
+                    Action::Rule(rule) => Some(rule.clone()),
+                    Action::Closure(_) => None,
+                },
+            });
+        }
+    }
+    entries
+}
+
+// Whether any entry is hooked on `hook`, the packets needn't be run through an empty chain.
+pub fn hooked(hook: Hook) -> bool {
+    FILTER
+        .read()
+        .unwrap()
+        .chains
+        .get(&hook)
+        .map_or(false, |chain| !chain.is_empty())
+}
+
+// Runs `packet` through the hook's chain, which may change it. The verdict is never Mangle.
+pub fn run(hook: Hook, interface: &str, packet: &mut Vec<u8>) -> Verdict {
+    FILTER.read().unwrap().run(hook, interface, packet)
+}
+
+// Runs `packet` through the hook's chain, dropping or rejecting it as the chain decides. None when the packet
+// doesn't make it through.
+pub(crate) fn pass(
+    hook: Hook,
+    interface: &str,
+    mut packet: Vec<u8>,
+    ipv4_stack_writer: &IPstackWriter,
+) -> Option<Vec<u8>> {
+    match run(hook, interface, &mut packet) {
+        Verdict::Drop => {}
+        Verdict::Reject => ICMP::report_port_unreachable(&packet, ipv4_stack_writer),
+        _ => return Some(packet),
+    }
+    stats::record_drop(DropReason::Filtered);
+    None
+}
+
+// An address stands for the /32 prefix.
+fn parse_prefix(prefix: &str) -> Result<Cidr, String> {
+    if prefix.contains('/') {
+        Cidr::parse(prefix)
+    } else {
+        let addr = prefix
+            .parse::<Ipv4Addr>()
+            .map_err(|_| format!("Invalid address {}", prefix))?;
+        Cidr::new(addr.octets(), 32)
+    }
+}
+
+fn parse_proto(proto: &str) -> Result<u8, String> {
+    match proto {
+        "icmp" => Ok(ICMP_PROTO),
+        "tcp" => Ok(TCP_PROTO),
+        "udp" => Ok(UDP_PROTO),
+        proto => parse_u8(proto),
+    }
+}
+
+fn parse_ports(ports: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("Invalid port {}", port))
+    };
+    let (first, last) = match ports.find('-') {
+        Some(dash) => (parse_port(&ports[..dash])?, parse_port(&ports[dash + 1..])?),
+        None => (parse_port(ports)?, parse_port(ports)?),
+    };
+    if first > last {
+        return Err(format!("Invalid port range {}", ports));
+    }
+    Ok(first..=last)
+}
+
+fn parse_u8(value: &str) -> Result<u8, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number {}", value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // UDP datagram from 10.0.0.1:5000 to 10.0.0.2:9000, with a valid header checksum.
+    fn udp_packet() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 28, 0, 1, 0, 0, 64, UDP_PROTO, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&[0x13, 0x88, 0x23, 0x28, 0, 8, 0, 0]);
+        let (checksum, _) = net_util::compute_ip_checksum(&packet[..20], 10..12);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_rule_parse() {
+        for rule in &[
+            "dev eth0 src 10.0.0.0/24 dst 10.0.0.2/32 proto udp sport 5000 dport 9000-9010 drop",
+            "proto icmp reject",
+            "proto 47 ttl 10",
+            "state established,related accept",
+            "accept",
+        ] {
+            assert_eq!(Rule::parse(rule).unwrap().to_string(), *rule);
+        }
+        assert!(Rule::parse("proto udp").is_err());
+        assert!(Rule::parse("drop proto udp").is_err());
+        assert!(Rule::parse("dport 9010-9000 drop").is_err());
+        assert!(Rule::parse("dport drop").is_err());
+        assert!(Rule::parse("state open accept").is_err());
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let info = PacketInfo::from_bytes("eth0", &udp_packet()).unwrap();
+        assert_eq!(info.src_port, Some(5000));
+        assert_eq!(info.dst_port, Some(9000));
+        for rule in &[
+            "proto udp dport 8990-9000 drop",
+            "src 10.0.0.1 drop",
+            "dev eth0 drop",
+        ] {
+            assert!(Rule::parse(rule).unwrap().matches(&info));
+        }
+        for rule in &[
+            "proto icmp drop",
+            "dport 9001 drop",
+            "dst 10.0.1.0/24 drop",
+            "dev eth1 drop",
+        ] {
+            assert!(!Rule::parse(rule).unwrap().matches(&info));
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut filter = Filter::default();
+        let mut packet = udp_packet();
+        assert_eq!(
+            filter.run(Hook::Input, "eth0", &mut packet),
+            Verdict::Accept
+        );
+
+        filter.add(Hook::Input, Action::Rule(Rule::parse("ttl 5").unwrap()));
+        let handle = filter.add(
+            Hook::Input,
+            Action::Rule(Rule::parse("proto udp dport 9000 reject").unwrap()),
+        );
+        filter.add(Hook::Input, Action::Rule(Rule::parse("drop").unwrap()));
+        assert_eq!(
+            filter.run(Hook::Input, "eth0", &mut packet),
+            Verdict::Reject
+        );
+        // Mangled along the way, checksum included
+        assert_eq!(packet[8], 5);
+        assert!(IPv4::parse(&packet).is_ok());
+        // Other hooks have chains of their own
+        assert_eq!(
+            filter.run(Hook::Output, "eth0", &mut packet),
+            Verdict::Accept
+        );
+
+        assert!(filter.remove(handle));
+        assert!(!filter.remove(handle));
+        assert_eq!(filter.run(Hook::Input, "eth0", &mut packet), Verdict::Drop);
+    }
+
+    #[test]
+    fn test_closure() {
+        let mut filter = Filter::default();
+        filter.add(
+            Hook::Forward,
+            Action::Closure(Box::new(|info, packet| {
+                if info.dst_port == Some(9000) {
+                    let mut mangled = packet.to_vec();
+                    mangled[16..20].copy_from_slice(&[10, 0, 0, 3]);
+                    Verdict::Mangle(mangled)
+                } else {
+                    Verdict::Accept
+                }
+            })),
+        );
+        filter.add(
+            Hook::Forward,
+            Action::Rule(Rule::parse("dst 10.0.0.3 drop").unwrap()),
+        );
+        let mut packet = udp_packet();
+        assert_eq!(
+            filter.run(Hook::Forward, "eth0", &mut packet),
+            Verdict::Drop
+        );
+        assert_eq!(packet[16..20], [10, 0, 0, 3]);
+    }
+}
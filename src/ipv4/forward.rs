@@ -0,0 +1,224 @@
+// IPv4 forwarding(router mode)
+// Reference: https://tools.ietf.org/html/rfc1812#section-5.2, https://tools.ietf.org/html/rfc1624(Incremental checksum)
+//
+// Packets are routed between the interfaces(see `start_routed_stack`), and between the hosts and gateways of a
+// single link too(a router on a stick). The inside hosts' packets are translated when NAT is configured.
+
+use crate::ethernet::{self, ProtocolAddr};
+use crate::ipv4::address;
+use crate::ipv4::conntrack;
+use crate::ipv4::filter::{self, Hook};
+use crate::ipv4::icmp::ICMP;
+use crate::ipv4::nat;
+use crate::ipv4::route;
+use crate::ipv4::stats::{self, DropReason};
+use crate::ipv4::{IPstackWriter, IPv4, RoutedPacket};
+use crate::net_util;
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref CONFIG: RwLock<ForwardingConfig> = RwLock::new(ForwardingConfig::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ForwardingConfig {
+    // Route the packets addressed to other hosts instead of delivering them. Off by default, like linux's ip_forward.
+    pub enabled: bool,
+    // Point the sender to a better first hop(ICMP redirect) when a packet leaves through the interface it came in.
+    pub send_redirects: bool,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        ForwardingConfig {
+            enabled: false,
+            send_redirects: true,
+        }
+    }
+}
+
+pub fn config() -> ForwardingConfig {
+    *CONFIG.read().unwrap()
+}
+
+pub fn set_config(config: ForwardingConfig) {
+    *CONFIG.write().unwrap() = config;
+}
+
+// Only unicast packets are routed(RFC 1812 5.3.4, 5.3.7): not the broadcast, multicast or unspecified addresses,
+// whether as a source or as a destination. Directed broadcasts to our own subnets aren't either(RFC 2644).
+pub fn forwardable(packet: &IPv4) -> bool {
+    let special =
+        |addr: &ProtocolAddr| address::is_broadcast(addr) || addr[0] >= 224 || *addr == [0; 4];
+    !special(&packet.src) && !special(&packet.dst)
+}
+
+// Sends `packet` on towards its destination, `bytes` being the packet as received.
+pub fn forward(
+    eth: &ethernet::Ethernet,
+    packet: IPv4,
+    bytes: &[u8],
+    ipv4_stack_writer: &IPstackWriter,
+) {
+    // The filter goes first, nothing gets sent on behalf of a packet it drops, ICMP errors and redirects included.
+    let mut forwarded =
+        match filter::pass(Hook::Forward, eth.name(), bytes.to_vec(), ipv4_stack_writer) {
+            Some(forwarded) => forwarded,
+            None => return,
+        };
+    let packet = if forwarded[..] == *bytes {
+        packet
+    } else {
+        match IPv4::parse(&forwarded) {
+            Ok(mangled) => mangled,
+            Err(reason) => return stats::record_drop(reason),
+        }
+    };
+    conntrack::track(&packet);
+    if packet.ttl() <= 1 {
+        stats::record_drop(DropReason::TtlExceeded);
+        return ICMP::report_ttl_exceeded(bytes, ipv4_stack_writer);
+    }
+    let route = match route::lookup(&packet.dst) {
+        Some(route) => route,
+        None => {
+            stats::record_drop(DropReason::NoRoute);
+            return ICMP::report_net_unreachable(bytes, ipv4_stack_writer);
+        }
+    };
+    let (egress, _) = match ethernet::link(&route.interface) {
+        Some(link) => link,
+        None => {
+            stats::record_drop(DropReason::NoRoute);
+            return ICMP::report_net_unreachable(bytes, ipv4_stack_writer);
+        }
+    };
+    let next_hop = route.next_hop(&packet.dst);
+    let mtu = ethernet::mtu(&route.interface);
+    if forwarded.len() > mtu && packet.dont_fragment() {
+        stats::record_drop(DropReason::FragmentationNeeded);
+        return ICMP::report_frag_needed(bytes, mtu, ipv4_stack_writer);
+    }
+
+    decrement_ttl(&mut forwarded);
+    let translated = match nat::translate_outbound(&mut forwarded, &route.interface) {
+        Ok(translated) => translated,
+        Err(reason) => return stats::record_drop(reason),
+    };
+    // A sender on the link the packet goes back out of could just as well have sent it to the next hop itself
+    // (RFC 1812 5.2.7.2), unless it's relying on us to translate its packets.
+    if config().send_redirects
+        && route.interface == eth.name()
+        && !translated
+        && next_hop != packet.src
+        && on_link(&packet.src, eth.name())
+    {
+        ICMP::send_redirect(bytes, next_hop, ipv4_stack_writer);
+    }
+
+    let fragments = match fragment(forwarded, mtu) {
+        Ok(fragments) => fragments,
+        Err(reason) => return stats::record_drop(reason),
+    };
+    for fragment in fragments {
+        egress
+            .send(Box::new(RoutedPacket::from_bytes(fragment, next_hop)))
+            .unwrap();
+    }
+}
+
+// Splits a serialized packet which doesn't fit in `mtu`, whatever protocol it carries.
+fn fragment(packet: Vec<u8>, mtu: usize) -> Result<Vec<Vec<u8>>, DropReason> {
+    if packet.len() <= mtu {
+        return Ok(vec![packet]);
+    }
+    Ok(IPv4::parse(&packet)?
+        .fragment(mtu)
+        .iter()
+        .map(IPv4::packet_to_bytes)
+        .collect())
+}
+
+fn on_link(addr: &ProtocolAddr, interface: &str) -> bool {
+    matches!(
+        route::lookup(addr),
+        Some(route) if route.gateway.is_none() && route.interface == interface
+    )
+}
+
+// Decrements the TTL of a serialized packet, patching the header checksum rather than computing it again:
+// HC' = ~(~HC + ~m + m')(RFC 1624 3), m being the 16 bit word holding the TTL.
+fn decrement_ttl(packet: &mut [u8]) {
+    let old_word = net_util::ntohs(&packet[8..10]);
+    packet[8] -= 1;
+    let new_word = net_util::ntohs(&packet[8..10]);
+    let checksum = net_util::ntohs(&packet[10..12]);
+    let mut sum = !checksum as u32 + !old_word as u32 + new_word as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decrement_ttl() {
+        for ttl in &[2u8, 64, 128, 255] {
+            let mut packet = vec![
+                0x45, 0, 0, 20, 0x12, 0x34, 0x40, 0, *ttl, 17, 0, 0, 10, 0, 0, 1, 8, 8, 8, 8,
+            ];
+            let (checksum, _) = net_util::compute_ip_checksum(&packet, 10..12);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            decrement_ttl(&mut packet);
+            assert_eq!(packet[8], ttl - 1);
+            let (expected, received) = net_util::compute_ip_checksum(&packet, 10..12);
+            assert_eq!(received, expected);
+        }
+    }
+
+    #[test]
+    fn test_fragment_unsupported_protocol() {
+        // GRE packet with 3000 bytes of payload
+        let mut packet = vec![
+            0x45, 0, 0x0b, 0xcc, 0x12, 0x34, 0, 0, 64, 47, 0, 0, 10, 0, 0, 1, 8, 8, 8, 8,
+        ];
+        let (checksum, _) = net_util::compute_ip_checksum(&packet, 10..12);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend((0..3000).map(|byte| byte as u8));
+
+        let fragments = fragment(packet.clone(), 1500).unwrap();
+        assert_eq!(fragments.len(), 3);
+        let mut payload = Vec::new();
+        for fragment in &fragments {
+            assert!(fragment.len() <= 1500);
+            let fragment = IPv4::parse(fragment).unwrap();
+            assert_eq!(fragment.proto(), 47);
+            payload.extend_from_slice(fragment.payload_bytes());
+        }
+        assert_eq!(payload, &packet[20..]);
+        // Packets which fit are left alone
+        assert_eq!(fragment(packet.clone(), 3020).unwrap(), vec![packet]);
+    }
+
+    #[test]
+    fn test_forwardable() {
+        let packet = |src: ProtocolAddr, dst: ProtocolAddr| {
+            let mut bytes = vec![
+                0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, src[0], src[1], src[2], src[3], dst[0],
+                dst[1], dst[2], dst[3],
+            ];
+            let (checksum, _) = net_util::compute_ip_checksum(&bytes, 10..12);
+            bytes[10..12].copy_from_slice(&checksum.to_be_bytes());
+            IPv4::parse(&bytes).unwrap()
+        };
+        assert!(forwardable(&packet([10, 0, 0, 1], [8, 8, 8, 8])));
+        assert!(!forwardable(&packet([10, 0, 0, 1], [255, 255, 255, 255])));
+        assert!(!forwardable(&packet([10, 0, 0, 1], [224, 0, 0, 1])));
+        assert!(!forwardable(&packet([0, 0, 0, 0], [8, 8, 8, 8])));
+    }
+}
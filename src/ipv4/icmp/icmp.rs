@@ -1,6 +1,9 @@
 use crate::ethernet;
+use crate::ipv4::address;
+use crate::ipv4::pmtu;
 use crate::ipv4::*;
 use crate::net_util;
+use std::convert::TryInto;
 
 pub struct ICMP {
     msg_type: u8,
@@ -10,10 +13,32 @@ pub struct ICMP {
     payload: Vec<u8>,
 }
 
-const ECHO_REPLY: u8 = 0u8;
-const ECHO_REQ: u8 = 8u8;
+pub const ECHO_REPLY: u8 = 0u8;
+pub const ECHO_REQ: u8 = 8u8;
+pub(crate) const DEST_UNREACHABLE: u8 = 3u8;
+pub(crate) const SOURCE_QUENCH: u8 = 4u8;
+pub(crate) const REDIRECT: u8 = 5u8;
+pub(crate) const TIME_EXCEEDED: u8 = 11u8;
+pub(crate) const PARAMETER_PROBLEM: u8 = 12u8;
 
-const ICMP: u8 = 1;
+// Destination unreachable codes
+pub const NET_UNREACHABLE: u8 = 0u8;
+pub const HOST_UNREACHABLE: u8 = 1u8;
+pub const PORT_UNREACHABLE: u8 = 3u8;
+// Fragmentation needed and DF set
+pub const FRAG_NEEDED: u8 = 4u8;
+
+// Redirect codes
+const REDIRECT_HOST: u8 = 1u8;
+
+// Time exceeded codes
+const TTL_EXCEEDED: u8 = 0u8;
+const REASSEMBLY_TIME_EXCEEDED: u8 = 1u8;
+
+// Bytes of the offending datagram's payload quoted in ICMP error messages(RFC 792)
+pub(crate) const ERROR_QUOTE_LEN: usize = 8;
+
+pub(crate) const ICMP_HEADER_LEN: usize = 8;
 
 pub enum IcmpType {
     EchoReply,
@@ -43,6 +68,9 @@ impl ICMP {
     }
 
     fn packet_from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < ICMP_HEADER_LEN {
+            return None;
+        }
         let icmp_packet = ICMP {
             msg_type: data[0],
             code: data[1],
@@ -73,24 +101,182 @@ impl ICMP {
         reply
     }
 
-    pub fn process_packet(frame: ethernet::EthernetFrame, layer_3_writer: &IPstackWriter) {
-        let ipv4_packet = IPv4::packet_from_net_bytes(frame.payload());
+    fn build_error(msg_type: u8, code: u8, header_dat: u32, ip_packet: &[u8]) -> ICMP {
+        // Quote the offending packet's ip header along with the first few bytes of its payload, that's all the
+        // sender needs to figure out which socket the error belongs to.
+        let header_len = net_util::get_bits(ip_packet[0], 0..4) as usize * 4;
+        let quote_len = std::cmp::min(header_len + ERROR_QUOTE_LEN, ip_packet.len());
+        let mut error = ICMP {
+            msg_type,
+            code,
+            checksum: 0u16,
+            header_dat,
+            payload: ip_packet[0..quote_len].to_owned(),
+        };
+        let (check_sum, _) = net_util::compute_ip_checksum(&error.packet_to_bytes(), 2..4);
+        error.checksum = check_sum;
+        error
+    }
+
+    // Reports a locally originated packet that couldn't be delivered back to its sender.
+    pub fn report_host_unreachable(ip_packet: &[u8], layer_3_writer: &IPstackWriter) {
+        ICMP::report_error(
+            DEST_UNREACHABLE,
+            HOST_UNREACHABLE,
+            0u32,
+            ip_packet,
+            layer_3_writer,
+        );
+    }
+
+    // Reports a locally originated packet for which there is no route.
+    pub fn report_net_unreachable(ip_packet: &[u8], layer_3_writer: &IPstackWriter) {
+        ICMP::report_error(
+            DEST_UNREACHABLE,
+            NET_UNREACHABLE,
+            0u32,
+            ip_packet,
+            layer_3_writer,
+        );
+    }
+
+    // Reports a packet too big for the link and which mustn't be fragmented, along with the link's MTU(RFC 1191 4).
+    pub fn report_frag_needed(ip_packet: &[u8], mtu: usize, layer_3_writer: &IPstackWriter) {
+        ICMP::report_error(
+            DEST_UNREACHABLE,
+            FRAG_NEEDED,
+            mtu as u32,
+            ip_packet,
+            layer_3_writer,
+        );
+    }
+
+    // Lets the sender know that we gave up on reassembling its datagram(RFC 792), `first_fragment` being the
+    // fragment with offset zero.
+    pub fn report_reassembly_timeout(first_fragment: &[u8], layer_3_writer: &IPstackWriter) {
+        ICMP::report_error(
+            TIME_EXCEEDED,
+            REASSEMBLY_TIME_EXCEEDED,
+            0u32,
+            first_fragment,
+            layer_3_writer,
+        );
+    }
+
+    // Lets the sender know that nobody takes its packet, the way the packet filter rejects packets.
+    pub fn report_port_unreachable(ip_packet: &[u8], layer_3_writer: &IPstackWriter) {
+        ICMP::report_error(
+            DEST_UNREACHABLE,
+            PORT_UNREACHABLE,
+            0u32,
+            ip_packet,
+            layer_3_writer,
+        );
+    }
+
+    // Lets the sender know that its packet's TTL ran out on the way.
+    pub fn report_ttl_exceeded(ip_packet: &[u8], layer_3_writer: &IPstackWriter) {
+        ICMP::report_error(TIME_EXCEEDED, TTL_EXCEEDED, 0u32, ip_packet, layer_3_writer);
+    }
+
+    // Tells the sender that packets to the same destination should go to `gateway` directly(RFC 792).
+    pub fn send_redirect(
+        ip_packet: &[u8],
+        gateway: ethernet::ProtocolAddr,
+        layer_3_writer: &IPstackWriter,
+    ) {
+        ICMP::report_error(
+            REDIRECT,
+            REDIRECT_HOST,
+            u32::from_be_bytes(gateway),
+            ip_packet,
+            layer_3_writer,
+        );
+    }
+
+    fn report_error(
+        msg_type: u8,
+        code: u8,
+        header_dat: u32,
+        ip_packet: &[u8],
+        layer_3_writer: &IPstackWriter,
+    ) {
+        let ipv4_packet = match IPv4::parse(ip_packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        // Nor about packets to a broadcast or multicast address, or from anything but a unicast one(RFC 1122 3.2.2).
+        let unicast = |addr: &ethernet::ProtocolAddr| {
+            !address::is_broadcast(addr) && addr[0] < 224 && *addr != [0, 0, 0, 0]
+        };
+        if !unicast(&ipv4_packet.dst) || !unicast(&ipv4_packet.src) {
+            return;
+        }
+        // Never report errors about ICMP errors(RFC 1122 3.2.2)
+        if let Protocol::ICMP = ipv4_packet.ip_header().proto {
+            match ipv4_packet.payload_bytes().first() {
+                Some(&ECHO_REQ) | Some(&ECHO_REPLY) => {}
+                _ => return,
+            }
+        }
+        let error = ICMP::build_error(msg_type, code, header_dat, ip_packet);
+        // The error goes back to the offending packet's source. When that's us, it goes through `process_packet`
+        // just like an error sent by a remote host would.
+        layer_3_writer.write(OutboundPacket::new(
+            ipv4_packet.src,
+            ICMP_PROTO,
+            error.packet_to_bytes(),
+        ));
+    }
+
+    fn handle_dest_unreachable(packet: &ICMP) {
+        let quoted = &packet.payload;
+        if quoted.len() < 20 {
+            return;
+        }
+        let header_len = net_util::get_bits(quoted[0], 0..4) as usize * 4;
+        if packet.code == FRAG_NEEDED {
+            // The next hop MTU goes in the low order 16 bits of the unused header field(RFC 1191 4).
+            let dst: ethernet::ProtocolAddr = quoted[16..20].try_into().unwrap();
+            pmtu::update(dst, (packet.header_dat & 0xffff) as usize);
+        }
+        // The quoted transport header must at least carry the source port.
+        if quoted.len() < header_len + 2 || quoted[9] != udp::UDP_PROTO {
+            return;
+        }
+        let src: ethernet::ProtocolAddr = quoted[12..16].try_into().unwrap();
+        let src_port = net_util::ntohs(&quoted[header_len..header_len + 2]);
+        let err = match packet.code {
+            NET_UNREACHABLE => "Network is unreachable",
+            HOST_UNREACHABLE => "No route to host",
+            PORT_UNREACHABLE => "Connection refused",
+            FRAG_NEEDED => "Message too long",
+            _ => "Destination unreachable",
+        };
+        udp::udp_socket::report_error(src, src_port, err);
+    }
+
+    pub fn process_packet(ipv4_packet: IPv4, layer_3_writer: &IPstackWriter) {
         let icmp_reply = match ICMP::packet_from_bytes(ipv4_packet.payload_bytes()) {
             Some(icmp_packet) => match icmp_packet.icmp_type() {
+                // Echo requests to a broadcast address go unanswered, like linux's icmp_echo_ignore_broadcasts.
+                IcmpType::EchoRequest if address::is_broadcast(&ipv4_packet.dst) => return,
                 IcmpType::EchoRequest => {
                     let reply = ICMP::build_icmp_echo_reply(icmp_packet);
                     reply.packet_to_bytes()
                 }
-                _ => unimplemented!(),
+                IcmpType::DestinationUnreachable => {
+                    ICMP::handle_dest_unreachable(&icmp_packet);
+                    return;
+                }
+                _ => return,
             },
             None => return, // Checksum mismatch, dont do anything
         };
 
-        let layer4_resp = ipv4::Layer4Response {
-            data: icmp_reply,
-            protocol: ICMP,
-            src_ip_header: ipv4_packet.ip_header(),
-        };
-        layer_3_writer.write(layer4_resp);
+        // Replies come from the address the request was sent to.
+        let mut reply = OutboundPacket::new(ipv4_packet.src, ICMP_PROTO, icmp_reply);
+        reply.src = Some(ipv4_packet.dst);
+        layer_3_writer.write(reply);
     }
 }
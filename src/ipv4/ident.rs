@@ -0,0 +1,61 @@
+// IPv4 identification field generation
+// Reference: https://tools.ietf.org/html/rfc6864#section-4.2
+
+use crate::ethernet::ProtocolAddr;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU16, Ordering};
+
+// Number of counters the (src, dst, protocol) tuples are spread over, like linux's ip_idents.
+const ID_COUNTERS: usize = 2048;
+
+lazy_static! {
+    static ref IDENTS: IdGenerator = IdGenerator::new(ID_COUNTERS);
+}
+
+// IDs only have to be unique per (src, dst, protocol) within the datagram's lifetime. Each tuple gets the next value of
+// a counter picked by a keyed hash of the tuple, counters start at random values so IDs can't be predicted off the
+// wire, and no per destination state is needed.
+struct IdGenerator {
+    counters: Vec<AtomicU16>,
+    hash_key: RandomState,
+}
+
+impl IdGenerator {
+    fn new(counters: usize) -> Self {
+        let mut rng = rand::thread_rng();
+        IdGenerator {
+            counters: (0..counters).map(|_| AtomicU16::new(rng.gen())).collect(),
+            hash_key: RandomState::new(),
+        }
+    }
+
+    fn next(&self, src: &ProtocolAddr, dst: &ProtocolAddr, proto: u8) -> u16 {
+        let mut hasher = self.hash_key.build_hasher();
+        (src, dst, proto).hash(&mut hasher);
+        let hash = hasher.finish();
+        let counter = &self.counters[hash as usize % self.counters.len()];
+        counter.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+pub fn next_id(src: &ProtocolAddr, dst: &ProtocolAddr, proto: u8) -> u16 {
+    IDENTS.next(src, dst, proto)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ids_are_sequential_per_destination() {
+        let generator = IdGenerator::new(ID_COUNTERS);
+        let (src, dst) = ([10, 0, 0, 2], [10, 0, 0, 1]);
+        let first = generator.next(&src, &dst, 17);
+        for offset in 1..100u16 {
+            assert_eq!(generator.next(&src, &dst, 17), first.wrapping_add(offset));
+        }
+    }
+}
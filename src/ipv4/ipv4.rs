@@ -1,20 +1,47 @@
 use crate::ethernet;
+use crate::ipv4::address;
+use crate::ipv4::conntrack;
+use crate::ipv4::filter::{self, Hook};
+use crate::ipv4::forward;
 use crate::ipv4::icmp;
+use crate::ipv4::ident;
+use crate::ipv4::nat;
+use crate::ipv4::options::{self, Ipv4Option};
+use crate::ipv4::pmtu;
+use crate::ipv4::reassembly::REASSEMBLER;
+use crate::ipv4::route;
+use crate::ipv4::stats::{self, DropReason};
 use crate::ipv4::udp;
+use crate::loopback;
 use crate::net_util;
-use crate::tap::tap_device::MTU;
+use lazy_static::lazy_static;
 use std::convert::TryInto;
 use std::sync::mpsc::channel;
+use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref IPV4_STACK: Mutex<Option<IPstackWriter>> = Mutex::new(None);
+}
 
 #[derive(Debug, Clone)]
-pub struct IPstackWriter(std::sync::mpsc::Sender<Layer4Response>);
+pub struct IPstackWriter(std::sync::mpsc::Sender<OutboundPacket>);
 
+// A packet for the stack to send, the writer loop turns it into an IPv4 packet.
 #[derive(Debug, Clone)]
-pub struct Layer4Response {
-    pub data: Vec<u8>,
-    pub protocol: u8,
-    pub src_ip_header: IpHeader,
+pub struct OutboundPacket {
+    // None lets the routing table pick the source address.
+    pub src: Option<ethernet::ProtocolAddr>,
+    pub dst: ethernet::ProtocolAddr,
+    pub proto: u8,
+    pub ttl: u8,
+    // Type of service(DSCP and ECN)
+    pub tos: u8,
+    pub payload: Vec<u8>,
+    pub options: Vec<Ipv4Option>,
+    // Set the DF flag, the packet is dropped(with an ICMP fragmentation needed error) rather than fragmented.
+    pub dont_fragment: bool,
 }
 
 // No bit fields :(
@@ -29,10 +56,12 @@ pub struct IPv4 {
     flags: u8,
     frag_offset: u16,
     ttl: u8,
-    proto: Protocol,
+    // Kept as received, the stack forwards and reassembles the protocols it doesn't implement too.
+    proto: u8,
     chksm: u16,
     pub src: [u8; 4],
     pub dst: [u8; 4],
+    options: Vec<Ipv4Option>,
     data: Vec<u8>,
 }
 
@@ -52,11 +81,47 @@ pub struct IpHeader {
     pub dst: [u8; 4],
 }
 
-const ICMP: u8 = 1;
-const TCP: u8 = 6;
-const UDP: u8 = 17;
+// Protocol numbers
+pub const ICMP_PROTO: u8 = 1;
+pub const TCP_PROTO: u8 = 6;
+pub const UDP_PROTO: u8 = 17;
 
-#[derive(Debug, Clone, Copy)]
+pub fn proto_name(proto: u8) -> String {
+    match proto {
+        ICMP_PROTO => "icmp".to_string(),
+        TCP_PROTO => "tcp".to_string(),
+        UDP_PROTO => "udp".to_string(),
+        proto => proto.to_string(),
+    }
+}
+
+// A flow the way conntrack and NAT list it, ex: "udp 10.0.0.2:5000 -> 8.8.8.8:53".
+pub(crate) fn flow_to_string(proto: u8, endpoints: &[(ethernet::ProtocolAddr, u16)]) -> String {
+    let endpoints: Vec<String> = endpoints
+        .iter()
+        .map(|(addr, port)| net_util::addr_identifier(*addr, *port))
+        .collect();
+    format!("{} {}", proto_name(proto), endpoints.join(" -> "))
+}
+
+// Same as linux's ip_default_ttl
+pub const DEFAULT_TTL: u8 = 64;
+
+// Length of a header without options
+pub(crate) const MIN_HEADER_LEN: usize = 20;
+// Largest packet, header included(the total length field is 16 bits).
+pub(crate) const MAX_PACKET_LEN: usize = 65535;
+
+// Flags, MSB 0 bit numbering: reserved, don't fragment, more fragments
+const DONT_FRAGMENT: u8 = 0b010;
+const MORE_FRAGMENTS: u8 = 0b001;
+// Fragment offsets are counted in 8 byte units
+const FRAGMENT_UNIT: usize = 8;
+
+// How often the datagrams waiting for their missing fragments are checked for timeouts.
+const REASSEMBLY_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     ICMP,
     TCP,
@@ -64,51 +129,72 @@ pub enum Protocol {
     Unsupported,
 }
 
-impl ethernet::LinkLayerWritable for IPv4 {
+// A serialized packet on its way to the link, along with the neighbor the routing table picked for it.
+pub(crate) struct RoutedPacket {
+    data: Vec<u8>,
+    next_hop: ethernet::ProtocolAddr,
+}
+
+impl RoutedPacket {
+    pub(crate) fn new(packet: &IPv4, next_hop: ethernet::ProtocolAddr) -> Self {
+        RoutedPacket::from_bytes(packet.packet_to_bytes(), next_hop)
+    }
+
+    // `data` is sent as is, it must be a valid packet.
+    pub(crate) fn from_bytes(data: Vec<u8>, next_hop: ethernet::ProtocolAddr) -> Self {
+        RoutedPacket { data, next_hop }
+    }
+}
+
+impl ethernet::LinkLayerWritable for RoutedPacket {
     fn data(&self) -> Vec<u8> {
-        self.packet_to_bytes()
+        self.data.clone()
     }
 
     fn spa(&self) -> ethernet::ProtocolAddr {
-        self.src
+        self.data[12..16].try_into().unwrap()
     }
 
     fn tpa(&self) -> ethernet::ProtocolAddr {
-        self.dst
+        self.data[16..20].try_into().unwrap()
+    }
+
+    fn next_hop(&self) -> ethernet::ProtocolAddr {
+        self.next_hop
     }
 
     fn ether_type(&self) -> [u8; 2] {
         (ethernet::ETH_IPV4 as u16).to_be_bytes()
     }
+
+    // Broadcasts go to every host on the link, there's nothing to resolve(RFC 1122 3.3.6).
+    fn dst_hw_addr(&self) -> Option<ethernet::HwAddr> {
+        if address::is_broadcast(&self.next_hop) {
+            Some(ethernet::BROADCAST_ADDR)
+        } else {
+            None
+        }
+    }
 }
 
 impl IPstackWriter {
-    pub fn write(&self, packet_to_write: Layer4Response) {
+    pub fn write(&self, packet_to_write: OutboundPacket) {
         self.0.send(packet_to_write).unwrap();
     }
 }
 
-impl IpHeader {
-    pub fn make_unfragmented_ip_header(
-        src_ip: ethernet::ProtocolAddr,
-        dst_ip: ethernet::ProtocolAddr,
-        proto: u8,
-        payload_len: u16,
-    ) -> Self {
-        let ihl = 5u8;
-        IpHeader {
-            version: 4,
-            ihl: ihl,
-            ecn: 0,
-            t_len: (payload_len + (ihl * 4) as u16),
-            id: 0,
-            flags: 0,
-            frag_offset: 0,
-            ttl: 50,
-            proto: set_proto(proto),
-            chksm: 0,
-            src: src_ip,
-            dst: dst_ip,
+impl OutboundPacket {
+    // A packet with the default TTL, no options, and whose source address is left to the routing table.
+    pub fn new(dst: ethernet::ProtocolAddr, proto: u8, payload: Vec<u8>) -> Self {
+        OutboundPacket {
+            src: None,
+            dst,
+            proto,
+            ttl: DEFAULT_TTL,
+            tos: 0,
+            payload,
+            options: Vec::new(),
+            dont_fragment: false,
         }
     }
 }
@@ -119,36 +205,125 @@ impl IPv4 {
         frame: ethernet::EthernetFrame,
         ipv4_stack_writer: &IPstackWriter,
     ) {
-        IPv4::handle_frame(frame, ipv4_stack_writer);
+        let packet = match IPv4::parse(frame.payload()) {
+            Ok(packet) => packet,
+            Err(reason) => return stats::record_drop(reason),
+        };
+        // Loopback addresses never show up on a link(RFC 1122 3.2.1.3).
+        if loopback::is_loopback(&packet.src) || loopback::is_loopback(&packet.dst) {
+            return stats::record_drop(DropReason::Martian);
+        }
+        // Ours are the packets to one of our addresses, or to a broadcast address(RFC 1122 3.3.6).
+        let for_us = address::is_local(&packet.dst) || address::is_broadcast(&packet.dst);
+        if forward::config().enabled && !frame.is_multicast() {
+            let bytes = &frame.payload()[..packet.total_len()];
+            // Unicast packets for other hosts are routed on as they are, fragments included.
+            if !for_us && forward::forwardable(&packet) {
+                return forward::forward(eth, packet, bytes, ipv4_stack_writer);
+            }
+            // So are the replies to the flows NAT translated, once they're addressed to the inside host again.
+            if let Some(reply) = nat::translate_reply(bytes) {
+                if let Ok(reply_packet) = IPv4::parse(&reply) {
+                    return forward::forward(eth, reply_packet, &reply, ipv4_stack_writer);
+                }
+            }
+        }
+        if !for_us {
+            return stats::record_drop(DropReason::NotForUs);
+        }
+        let bytes = &frame.payload()[..packet.total_len()];
+        IPv4::deliver(packet, bytes, eth.name(), ipv4_stack_writer);
+    }
+
+    // Packets sent over the loopback interface come back in here, they're for us by definition.
+    fn process_looped_back(packet: IPv4, ipv4_stack_writer: &IPstackWriter) {
+        if let Some(packet) = IPv4::filter(
+            Hook::Prerouting,
+            loopback::INTERFACE_NAME,
+            packet,
+            ipv4_stack_writer,
+        ) {
+            let bytes = packet.packet_to_bytes();
+            IPv4::deliver(packet, &bytes, loopback::INTERFACE_NAME, ipv4_stack_writer);
+        }
+    }
+
+    // Hands a packet for us, which came in through `interface`, to its transport protocol once it's whole. `bytes`
+    // is the packet as received.
+    fn deliver(packet: IPv4, bytes: &[u8], interface: &str, ipv4_stack_writer: &IPstackWriter) {
+        if !packet.is_fragment() {
+            return IPv4::handle_packet(packet, interface, ipv4_stack_writer);
+        }
+        let reassembled = REASSEMBLER
+            .lock()
+            .unwrap()
+            .insert(packet, bytes, Instant::now());
+        match reassembled {
+            Ok(Some(packet)) => IPv4::handle_packet(packet, interface, ipv4_stack_writer),
+            Ok(None) => {}
+            Err(reason) => stats::record_drop(reason),
+        }
     }
 
     pub fn payload_bytes(&self) -> &[u8] {
         &self.data
     }
+
+    // Validates the header(RFC 1122 3.2.1) and parses the packet. `data` may carry trailing bytes past the total
+    // length(ex: ethernet padding), those are left out of the payload.
     // MSB 0 bit numbering
     // First n bytes means the the first n bytes from the left to right.
-    pub fn packet_from_net_bytes(data: &[u8]) -> IPv4 {
-        let parsed_packet = IPv4 {
-            version: net_util::get_bits(data[0], 4..8),
-            ihl: net_util::get_bits(data[0], 0..4),
+    pub fn parse(data: &[u8]) -> Result<IPv4, DropReason> {
+        if data.len() < MIN_HEADER_LEN {
+            return Err(DropReason::Truncated);
+        }
+        if net_util::get_bits(data[0], 4..8) != 4 {
+            return Err(DropReason::BadVersion);
+        }
+        let ihl = net_util::get_bits(data[0], 0..4);
+        let header_len = ihl as usize * 4;
+        let t_len = net_util::ntohs(&data[2..4]);
+        if header_len < MIN_HEADER_LEN || header_len > t_len as usize {
+            return Err(DropReason::BadHeaderLength);
+        }
+        if t_len as usize > data.len() {
+            return Err(DropReason::Truncated);
+        }
+        let (computed_chksm, received_chksm) =
+            net_util::compute_ip_checksum(&data[0..header_len], 10..12);
+        if computed_chksm != received_chksm {
+            return Err(DropReason::BadChecksum);
+        }
+        let options = match options::parse(&data[MIN_HEADER_LEN..header_len]) {
+            Ok(options) => options,
+            Err(_) => return Err(DropReason::BadOptions),
+        };
+        // Source routing lets the sender pick the path through(and around) our policies, drop it like most
+        // hosts do(RFC 7126 4.3, 4.4).
+        if options.iter().any(|option| option.is_source_route()) {
+            return Err(DropReason::SourceRouted);
+        }
+        Ok(IPv4 {
+            version: 4,
+            ihl,
             ecn: data[1],
-            t_len: net_util::ntohs(&data[2..4]),
+            t_len,
             id: net_util::ntohs(&data[4..6]),
             flags: net_util::get_bits(data[6], 5..8),
             frag_offset: (net_util::get_bits(data[6], 0..5) as u16) << 8 | data[7] as u16,
             ttl: data[8],
-            proto: set_proto(data[9]),
-            chksm: net_util::ntohs(&data[10..12]),
+            proto: data[9],
+            chksm: received_chksm,
             src: data[12..16].try_into().unwrap(),
             dst: data[16..20].try_into().unwrap(),
-            data: data[20..].to_owned(),
-        };
-        parsed_packet
+            options,
+            data: data[header_len..t_len as usize].to_owned(),
+        })
     }
 
-    fn packet_to_bytes(&self) -> Vec<u8> {
+    pub(crate) fn packet_to_bytes(&self) -> Vec<u8> {
         let mut packet_buffer = Vec::new();
-        let flag_frag_offset = self.frag_offset | (self.flags as u16);
+        let flag_frag_offset = (self.flags as u16) << 13 | self.frag_offset;
 
         let version_header_byte = self.version << 4 | self.ihl;
         packet_buffer.push(version_header_byte);
@@ -157,10 +332,11 @@ impl IPv4 {
         packet_buffer.extend_from_slice(&self.id.to_be_bytes()); //
         packet_buffer.extend_from_slice(&flag_frag_offset.to_be_bytes());
         packet_buffer.push(self.ttl);
-        packet_buffer.push(IPv4::get_proto_val(&self));
+        packet_buffer.push(self.proto);
         packet_buffer.extend_from_slice(&self.chksm.to_be_bytes()); // Set header checksum as zero before computing the checksum
         packet_buffer.extend_from_slice(&self.src);
         packet_buffer.extend_from_slice(&self.dst);
+        packet_buffer.append(&mut options::to_bytes(&self.options));
         let (checksum, _) = net_util::compute_ip_checksum(&packet_buffer, 10..12);
         let chksum_bytes = checksum.to_be_bytes();
         packet_buffer[10] = chksum_bytes[0];
@@ -169,6 +345,115 @@ impl IPv4 {
         packet_buffer
     }
 
+    // Total length according to the header.
+    pub fn total_len(&self) -> usize {
+        self.t_len as usize
+    }
+
+    // Total length of the packet once serialized.
+    pub fn len(&self) -> usize {
+        self.header_len() + self.data.len()
+    }
+
+    pub(crate) fn header_len(&self) -> usize {
+        MIN_HEADER_LEN + options::to_bytes(&self.options).len()
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.flags & DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags & MORE_FRAGMENTS != 0
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    // Protocol number, ex: 17 for UDP.
+    pub fn proto(&self) -> u8 {
+        self.proto
+    }
+
+    // Offset of the fragment's data in the original datagram, in bytes.
+    pub fn fragment_offset(&self) -> usize {
+        self.frag_offset as usize * FRAGMENT_UNIT
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.frag_offset != 0
+    }
+
+    // Turns the first fragment of a datagram into the whole datagram.
+    pub(crate) fn reassembled(mut self, payload: Vec<u8>) -> IPv4 {
+        self.flags &= !MORE_FRAGMENTS;
+        self.frag_offset = 0;
+        self.t_len = (self.header_len() + payload.len()) as u16;
+        self.data = payload;
+        self
+    }
+
+    // Splits the packet into fragments which fit in `mtu`(RFC 791 3.2, "Fragmentation"). The packet is returned
+    // as is when it already fits, it's up to the caller to check the DF flag beforehand.
+    pub fn fragment(self, mtu: usize) -> Vec<IPv4> {
+        if self.len() <= mtu {
+            return vec![self];
+        }
+        // Only the options with the copied flag set go into every fragment, the rest only into the first one.
+        let copied_options: Vec<Ipv4Option> = self
+            .options
+            .iter()
+            .filter(|option| option.copied())
+            .cloned()
+            .collect();
+        let mut fragments = Vec::new();
+        let mut offset = 0;
+        while offset < self.data.len() {
+            let options = if offset == 0 {
+                self.options.clone()
+            } else {
+                copied_options.clone()
+            };
+            let header_len = MIN_HEADER_LEN + options::to_bytes(&options).len();
+            // Every fragment but the last carries a multiple of 8 bytes.
+            let max_data_len = (mtu - header_len) / FRAGMENT_UNIT * FRAGMENT_UNIT;
+            let end = std::cmp::min(offset + max_data_len, self.data.len());
+            // The last fragment keeps the packet's own MF flag, in case the packet is a fragment itself.
+            let flags = if end == self.data.len() {
+                self.flags
+            } else {
+                self.flags | MORE_FRAGMENTS
+            };
+            fragments.push(IPv4 {
+                version: self.version,
+                ihl: (header_len / 4) as u8,
+                ecn: self.ecn,
+                t_len: (header_len + end - offset) as u16,
+                id: self.id,
+                flags,
+                frag_offset: self.frag_offset + (offset / FRAGMENT_UNIT) as u16,
+                ttl: self.ttl,
+                proto: self.proto,
+                chksm: 0u16,
+                src: self.src,
+                dst: self.dst,
+                options,
+                data: self.data[offset..end].to_owned(),
+            });
+            offset = end;
+        }
+        fragments
+    }
+
+    pub fn options(&self) -> &[Ipv4Option] {
+        &self.options
+    }
+
     pub fn ip_header(&self) -> IpHeader {
         IpHeader {
             version: self.version,
@@ -179,7 +464,7 @@ impl IPv4 {
             flags: self.flags,
             frag_offset: self.frag_offset,
             ttl: self.ttl,
-            proto: self.proto,
+            proto: set_proto(self.proto),
             chksm: self.chksm,
             src: self.src,
             dst: self.dst,
@@ -193,62 +478,63 @@ impl IPv4 {
         )
     }
 
-    fn get_proto_val(&self) -> u8 {
-        match self.proto {
-            Protocol::TCP => TCP,
-            Protocol::ICMP => ICMP,
-            Protocol::UDP => UDP,
-            Protocol::Unsupported => unimplemented!(),
-        }
-    }
-
-    fn build_unfragmented_packet(src_header: IpHeader, payload: Vec<u8>, proto: u8) -> IPv4 {
-        let total_packet_len = (20 as usize + payload.len()) as u16;
+    // `src` is the packet's own source address when it has one.
+    fn build_packet(packet: OutboundPacket, src: ethernet::ProtocolAddr) -> IPv4 {
+        let header_len = MIN_HEADER_LEN + options::to_bytes(&packet.options).len();
+        let total_packet_len = (header_len + packet.payload.len()) as u16;
         IPv4 {
-            version: 04u8,
-            ihl: 5u8, //No options field, so the header is always 20bytes.
-            ecn: src_header.ecn,
+            version: 4u8,
+            ihl: (header_len / 4) as u8,
+            ecn: packet.tos,
             t_len: total_packet_len,
-            id: 0u16,
-            flags: 0u8,
+            id: ident::next_id(&src, &packet.dst, packet.proto),
+            flags: if packet.dont_fragment {
+                DONT_FRAGMENT
+            } else {
+                0u8
+            },
             frag_offset: 0u16,
-            ttl: 50u8,
-            proto: set_proto(proto),
+            ttl: packet.ttl,
+            proto: packet.proto,
             chksm: 0u16,
-            src: src_header.dst,
-            dst: src_header.src,
-            data: payload,
-        }
-    }
-
-    fn build_ipv4_response(src_ip_header: IpHeader, payload: Vec<u8>, protocol: u8) -> IPv4 {
-        if (payload.len() as u32) > MTU {
-            // Need to implement ip packet fragmenting
-            unimplemented!()
-        } else {
-            IPv4::build_unfragmented_packet(src_ip_header, payload, protocol)
+            src: packet.src.unwrap_or(src),
+            dst: packet.dst,
+            options: packet.options,
+            data: packet.payload,
         }
     }
 
-    fn handle_frame(frame: ethernet::EthernetFrame, ipv4_stack: &IPstackWriter) {
-        let protocol = IPv4::protocol_from_ip_bytes(frame.payload());
-
-        match protocol {
-            Protocol::ICMP => {
-                icmp::ICMP::process_packet(frame, ipv4_stack);
-            }
-            Protocol::UDP => udp::UDP::process_packet(frame, ipv4_stack),
+    fn handle_packet(packet: IPv4, interface: &str, ipv4_stack: &IPstackWriter) {
+        let packet = match IPv4::filter(Hook::Input, interface, packet, ipv4_stack) {
+            Some(packet) => packet,
+            None => return,
+        };
+        conntrack::track(&packet);
+        match set_proto(packet.proto) {
+            Protocol::ICMP => icmp::ICMP::process_packet(packet, ipv4_stack),
+            Protocol::UDP => udp::UDP::process_packet(packet, ipv4_stack),
             Protocol::TCP => {
                 // TODO: TCP
             }
             Protocol::Unsupported => {
                 // Send ICMP error
+                stats::record_drop(DropReason::UnknownProtocol);
             }
         }
     }
 
-    fn protocol_from_ip_bytes(ipv4_bytes: &[u8]) -> Protocol {
-        set_proto(ipv4_bytes[9])
+    // Runs `packet` through the filter hook, None when it doesn't make it through.
+    fn filter(
+        hook: Hook,
+        interface: &str,
+        packet: IPv4,
+        ipv4_stack: &IPstackWriter,
+    ) -> Option<IPv4> {
+        if !filter::hooked(hook) {
+            return Some(packet);
+        }
+        let bytes = filter::pass(hook, interface, packet.packet_to_bytes(), ipv4_stack)?;
+        IPv4::parse(&bytes).map_err(stats::record_drop).ok()
     }
 
     pub fn src_from_bytes(ip_bytes: &[u8]) -> &[u8] {
@@ -260,42 +546,282 @@ impl IPv4 {
     }
 
     pub fn payload_from_bytes(ip_bytes: &[u8]) -> &[u8] {
-        &ip_bytes[20..]
+        let header_len = net_util::get_bits(ip_bytes[0], 0..4) as usize * 4;
+        &ip_bytes[header_len..]
     }
 }
 
-pub fn initialize_ipv4_stack(eth_writer: ethernet::ChannelWriter) -> IPstackWriter {
-    let (tx, rx) = channel::<Layer4Response>();
-    intialize_writer_loop(eth_writer, rx);
+// Starts the IPv4 stack the first time around, the links started after that share it.
+pub fn initialize_ipv4_stack() -> IPstackWriter {
+    let mut stack = IPV4_STACK.lock().unwrap();
+    if let Some(writer) = stack.as_ref() {
+        return writer.clone();
+    }
+    let (tx, rx) = channel::<OutboundPacket>();
+    intialize_writer_loop(IPstackWriter(tx.clone()), rx);
+    intialize_reassembly_timer(IPstackWriter(tx.clone()));
     udp::udp_socket::intialize_stack(IPstackWriter(tx.clone()));
+    *stack = Some(IPstackWriter(tx.clone()));
     IPstackWriter(tx)
 }
 
 // TODO: Implement graceful thread shutdown by implementing Drop for IPV4.
 fn intialize_writer_loop(
-    eth_writer: ethernet::ChannelWriter,
-    rx: std::sync::mpsc::Receiver<Layer4Response>,
+    ipv4_stack_writer: IPstackWriter,
+    rx: std::sync::mpsc::Receiver<OutboundPacket>,
 ) {
     thread::spawn(move || loop {
         let packet_to_write = rx.recv().unwrap();
-        let src_ip_header = packet_to_write.src_ip_header;
-        let ip_resp_packet = IPv4::build_ipv4_response(
-            src_ip_header,
-            packet_to_write.data,
-            packet_to_write.protocol,
-        );
-        eth_writer.send(Box::new(ip_resp_packet)).unwrap();
+        let src = route::source_addr(&packet_to_write.dst);
+        let ip_resp_packet = IPv4::build_packet(packet_to_write, src);
+        let interface = route::lookup(&ip_resp_packet.dst)
+            .map_or(ethernet::INTERFACE_NAME.to_string(), |route| {
+                route.interface
+            });
+        let ip_resp_packet =
+            match IPv4::filter(Hook::Output, &interface, ip_resp_packet, &ipv4_stack_writer) {
+                Some(packet) => packet,
+                None => continue,
+            };
+        conntrack::track(&ip_resp_packet);
+        // Looked up again, the filter may have changed the destination.
+        let route = match route::lookup(&ip_resp_packet.dst) {
+            Some(route) => route,
+            None => {
+                icmp::ICMP::report_net_unreachable(
+                    &ip_resp_packet.packet_to_bytes(),
+                    &ipv4_stack_writer,
+                );
+                continue;
+            }
+        };
+        let next_hop = route.next_hop(&ip_resp_packet.dst);
+        let mtu = pmtu::path_mtu(&ip_resp_packet.dst);
+        if ip_resp_packet.len() > mtu && ip_resp_packet.dont_fragment() {
+            // The error makes its way back to the sender, just like one sent by a router on the path would.
+            icmp::ICMP::report_frag_needed(
+                &ip_resp_packet.packet_to_bytes(),
+                mtu,
+                &ipv4_stack_writer,
+            );
+            continue;
+        }
+        if route.interface == loopback::INTERFACE_NAME {
+            IPv4::process_looped_back(ip_resp_packet, &ipv4_stack_writer);
+            continue;
+        }
+        // Dropped when the link isn't running, like with the loopback stack.
+        let (eth_writer, _) = match ethernet::link(&route.interface) {
+            Some(link) => link,
+            None => continue,
+        };
+        for fragment in ip_resp_packet.fragment(mtu) {
+            eth_writer
+                .send(Box::new(RoutedPacket::new(&fragment, next_hop)))
+                .unwrap();
+        }
+    });
+}
+
+fn intialize_reassembly_timer(ipv4_stack_writer: IPstackWriter) {
+    thread::spawn(move || loop {
+        thread::sleep(REASSEMBLY_TIMER_INTERVAL);
+        let expired = REASSEMBLER.lock().unwrap().expire(Instant::now());
+        for first_fragment in expired {
+            icmp::ICMP::report_reassembly_timeout(&first_fragment, &ipv4_stack_writer);
+        }
     });
 }
 
 fn set_proto(byte: u8) -> Protocol {
-    if byte == ICMP {
+    if byte == ICMP_PROTO {
         Protocol::ICMP
-    } else if byte == TCP {
+    } else if byte == TCP_PROTO {
         Protocol::TCP
-    } else if byte == UDP {
+    } else if byte == UDP_PROTO {
         Protocol::UDP
     } else {
         Protocol::Unsupported
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // UDP datagram from 10.0.0.1:5000 to 10.0.0.2:5055 carrying "hi", with a valid header checksum.
+    fn udp_packet() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 30, 0x12, 0x34, 0x40, 0, 64, UDP_PROTO, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&[0x13, 0x88, 0x13, 0xbf, 0, 10, 0, 0, b'h', b'i']);
+        set_checksum(&mut packet);
+        packet
+    }
+
+    fn set_checksum(packet: &mut [u8]) {
+        let header_len = net_util::get_bits(packet[0], 0..4) as usize * 4;
+        let (checksum, _) = net_util::compute_ip_checksum(&packet[0..header_len], 10..12);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn test_parse() {
+        let packet = IPv4::parse(&udp_packet()).unwrap();
+        assert_eq!(packet.src, [10, 0, 0, 1]);
+        assert_eq!(packet.dst, [10, 0, 0, 2]);
+        assert_eq!(packet.payload_bytes().len(), 10);
+    }
+
+    #[test]
+    fn test_parse_trims_padding() {
+        let mut padded = udp_packet();
+        padded.extend_from_slice(&[0; 16]);
+        assert_eq!(
+            IPv4::parse(&padded).unwrap().payload_bytes(),
+            &udp_packet()[20..]
+        );
+    }
+
+    #[test]
+    fn test_parse_honours_ihl() {
+        // Same datagram with a 4 byte header option(NOP NOP NOP EOL)
+        let mut packet = udp_packet();
+        packet[0] = 0x46;
+        packet[3] = 34;
+        packet.splice(20..20, vec![1, 1, 1, 0]);
+        set_checksum(&mut packet);
+        assert_eq!(
+            IPv4::parse(&packet).unwrap().payload_bytes(),
+            &udp_packet()[20..]
+        );
+    }
+
+    #[test]
+    fn test_parse_options() {
+        // Same datagram with a Router Alert option
+        let mut packet = udp_packet();
+        packet[0] = 0x46;
+        packet[3] = 34;
+        packet.splice(20..20, vec![148, 4, 0, 0]);
+        set_checksum(&mut packet);
+        let parsed = IPv4::parse(&packet).unwrap();
+        assert_eq!(parsed.options(), &[Ipv4Option::RouterAlert(0)]);
+        assert_eq!(parsed.payload_bytes(), &udp_packet()[20..]);
+
+        // Loose source route
+        packet.splice(20..24, vec![131, 3, 4, 0]);
+        set_checksum(&mut packet);
+        assert_eq!(IPv4::parse(&packet).err(), Some(DropReason::SourceRouted));
+
+        // Option running past the header
+        packet.splice(20..24, vec![7, 8, 4, 0]);
+        set_checksum(&mut packet);
+        assert_eq!(IPv4::parse(&packet).err(), Some(DropReason::BadOptions));
+    }
+
+    fn outbound_packet(payload: Vec<u8>, options: Vec<Ipv4Option>) -> OutboundPacket {
+        OutboundPacket {
+            options,
+            ..OutboundPacket::new([10, 0, 0, 1], UDP_PROTO, payload)
+        }
+    }
+
+    #[test]
+    fn test_build_packet() {
+        let mut outbound = outbound_packet(vec![1, 2, 3], vec![]);
+        outbound.tos = 0x10;
+        let packet =
+            IPv4::parse(&IPv4::build_packet(outbound, [10, 0, 0, 2]).packet_to_bytes()).unwrap();
+        assert_eq!(packet.src, [10, 0, 0, 2]);
+        assert_eq!(packet.dst, [10, 0, 0, 1]);
+        assert_eq!(packet.ttl, DEFAULT_TTL);
+        assert_eq!(packet.ecn, 0x10);
+        assert_eq!(packet.ip_header().proto, Protocol::UDP);
+
+        // An explicit source address wins over the routing table's pick
+        let mut outbound = outbound_packet(vec![1, 2, 3], vec![]);
+        outbound.src = Some([10, 0, 0, 3]);
+        assert_eq!(
+            IPv4::build_packet(outbound, [10, 0, 0, 2]).src,
+            [10, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn test_options_round_trip() {
+        let options = vec![Ipv4Option::RouterAlert(0)];
+        let packet = IPv4::build_packet(
+            outbound_packet(vec![1, 2, 3], options.clone()),
+            [10, 0, 0, 2],
+        );
+        let parsed = IPv4::parse(&packet.packet_to_bytes()).unwrap();
+        assert_eq!(parsed.options(), &options[..]);
+        assert_eq!(parsed.payload_bytes(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_fragment() {
+        let payload: Vec<u8> = (0..4000).map(|byte| byte as u8).collect();
+        let options = vec![
+            Ipv4Option::RouterAlert(0),
+            Ipv4Option::RecordRoute {
+                pointer: 4,
+                route: vec![[0; 4]],
+            },
+        ];
+        let packet = IPv4::build_packet(outbound_packet(payload.clone(), options), [10, 0, 0, 2]);
+        let fragments: Vec<IPv4> = packet
+            .fragment(1500)
+            .iter()
+            .map(|fragment| IPv4::parse(&fragment.packet_to_bytes()).unwrap())
+            .collect();
+
+        assert_eq!(fragments.len(), 3);
+        let mut reassembled = Vec::new();
+        for (index, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= 1500);
+            assert_eq!(fragment.id, fragments[0].id);
+            assert_eq!(fragment.frag_offset as usize * 8, reassembled.len());
+            assert_eq!(fragment.flags & MORE_FRAGMENTS != 0, index < 2);
+            reassembled.extend_from_slice(fragment.payload_bytes());
+        }
+        assert_eq!(reassembled, payload);
+
+        // Record route only goes into the first fragment, router alert into all of them.
+        assert_eq!(fragments[0].options().len(), 2);
+        assert_eq!(fragments[1].options(), &[Ipv4Option::RouterAlert(0)]);
+    }
+
+    #[test]
+    fn test_fragment_small_packet() {
+        let mut outbound = outbound_packet(vec![0; 1480], vec![]);
+        outbound.dont_fragment = true;
+        let packet = IPv4::build_packet(outbound, [10, 0, 0, 2]);
+        assert!(packet.dont_fragment());
+        assert_eq!(packet.fragment(1500).len(), 1);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_headers() {
+        let packet = udp_packet();
+        let reason = |packet: &[u8]| IPv4::parse(packet).err().unwrap();
+        let with_byte = |index: usize, value: u8| {
+            let mut packet = udp_packet();
+            packet[index] = value;
+            set_checksum(&mut packet);
+            reason(&packet)
+        };
+
+        assert_eq!(reason(&packet[0..19]), DropReason::Truncated);
+        assert_eq!(reason(&packet[0..29]), DropReason::Truncated);
+        assert_eq!(with_byte(0, 0x65), DropReason::BadVersion);
+        assert_eq!(with_byte(0, 0x44), DropReason::BadHeaderLength);
+        // Total length shorter than the header
+        assert_eq!(with_byte(3, 16), DropReason::BadHeaderLength);
+
+        let mut corrupted = udp_packet();
+        corrupted[8] = 1;
+        assert_eq!(reason(&corrupted), DropReason::BadChecksum);
+    }
+}
@@ -1,5 +1,16 @@
-mod icmp;
+pub mod address;
+pub mod conntrack;
+pub mod filter;
+pub mod forward;
+pub mod icmp;
+mod ident;
 mod ipv4;
+pub mod nat;
+pub mod options;
+pub mod pmtu;
+mod reassembly;
+pub mod route;
+pub mod stats;
 mod udp;
 pub use ipv4::*;
 pub use udp::udp_socket;
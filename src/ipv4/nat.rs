@@ -0,0 +1,429 @@
+// Source NAT(masquerading) of forwarded traffic
+// Reference: https://tools.ietf.org/html/rfc3022, https://tools.ietf.org/html/rfc4787
+
+use crate::ethernet::{self, ProtocolAddr};
+use crate::ipv4::conntrack::{ICMP_TIMEOUT, UDP_TIMEOUT};
+use crate::ipv4::icmp::icmp::{ECHO_REPLY, ECHO_REQ};
+use crate::ipv4::stats::DropReason;
+use crate::ipv4::udp::{self, UDP};
+use crate::ipv4::udp_socket;
+use crate::ipv4::{self, route, ICMP_PROTO};
+use crate::net_util::{self, Cidr};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Ports(and ICMP echo identifiers) handed out to the translated flows, when their own one is taken.
+const NAT_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+lazy_static! {
+    static ref NAT: Mutex<Nat> = Mutex::new(Nat::new());
+}
+
+// Packets from `inside` leaving through `interface` get the interface's address as their source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatRule {
+    pub inside: Cidr,
+    pub interface: String,
+}
+
+impl fmt::Display for NatRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "masquerade {} dev {}", self.inside, self.interface)
+    }
+}
+
+// Both ends of a translated flow, ports being echo identifiers for ICMP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Flow {
+    proto: u8,
+    inside: (ProtocolAddr, u16),
+    remote: (ProtocolAddr, u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    external: (ProtocolAddr, u16),
+    last_used: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatMapping {
+    pub proto: u8,
+    pub inside: (ProtocolAddr, u16),
+    pub external: (ProtocolAddr, u16),
+    pub remote: (ProtocolAddr, u16),
+    pub idle: Duration,
+}
+
+impl fmt::Display for NatMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} idle {}s",
+            ipv4::flow_to_string(self.proto, &[self.inside, self.external, self.remote]),
+            self.idle.as_secs()
+        )
+    }
+}
+
+struct Nat {
+    rules: Vec<NatRule>,
+    flows: HashMap<Flow, Mapping>,
+    // Translated flows by protocol and external port.
+    external_ports: HashMap<(u8, u16), Flow>,
+    next_port: u16,
+}
+
+impl Nat {
+    fn new() -> Self {
+        Nat {
+            rules: Vec::new(),
+            flows: HashMap::new(),
+            external_ports: HashMap::new(),
+            next_port: *NAT_PORTS.start(),
+        }
+    }
+
+    // Whether a rule translates the packets from `addr` leaving through `interface`.
+    fn translates(&self, addr: &ProtocolAddr, interface: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.inside.contains(addr) && rule.interface == interface)
+    }
+
+    // The external address and port of `flow`, mapping it first when it's new. None when every port is taken.
+    fn outbound(
+        &mut self,
+        flow: Flow,
+        external_addr: ProtocolAddr,
+        now: Instant,
+    ) -> Option<(ProtocolAddr, u16)> {
+        self.expire(now);
+        if let Some(mapping) = self.flows.get_mut(&flow) {
+            mapping.last_used = now;
+            return Some(mapping.external);
+        }
+        let port = self.allocate_port(flow.proto, flow.inside.1, external_addr)?;
+        self.external_ports.insert((flow.proto, port), flow);
+        self.flows.insert(
+            flow,
+            Mapping {
+                external: (external_addr, port),
+                last_used: now,
+            },
+        );
+        Some((external_addr, port))
+    }
+
+    // The inside end of the flow a reply from `remote` to external `port` belongs to. Only the remote end the flow
+    // was started with gets through(address and port dependent filtering, RFC 4787 5).
+    fn inbound(
+        &mut self,
+        proto: u8,
+        port: u16,
+        remote: (ProtocolAddr, u16),
+        now: Instant,
+    ) -> Option<(ProtocolAddr, u16)> {
+        self.expire(now);
+        let flow = *self.external_ports.get(&(proto, port))?;
+        if flow.remote != remote {
+            return None;
+        }
+        self.flows.get_mut(&flow)?.last_used = now;
+        Some(flow.inside)
+    }
+
+    // Keeps the flow's own port when it's free, like linux does.
+    fn allocate_port(&mut self, proto: u8, preferred: u16, addr: ProtocolAddr) -> Option<u16> {
+        if self.port_free(proto, preferred, addr) {
+            return Some(preferred);
+        }
+        let range_len = (NAT_PORTS.end() - NAT_PORTS.start()) as usize + 1;
+        for _ in 0..range_len {
+            let port = self.next_port;
+            self.next_port = if port == *NAT_PORTS.end() {
+                *NAT_PORTS.start()
+            } else {
+                port + 1
+            };
+            if self.port_free(proto, port, addr) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn port_free(&self, proto: u8, port: u16, addr: ProtocolAddr) -> bool {
+        // Ports of our own UDP sockets on the external address(or on all of them) aren't up for grabs either.
+        let bound = proto == udp::UDP_PROTO
+            && [addr, [0, 0, 0, 0]].iter().any(|addr| {
+                udp_socket::get_sock(&net_util::addr_identifier(*addr, port)).is_some()
+            });
+        port != 0 && !bound && !self.external_ports.contains_key(&(proto, port))
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let external_ports = &mut self.external_ports;
+        self.flows.retain(|flow, mapping| {
+            let timeout = if flow.proto == ICMP_PROTO {
+                ICMP_TIMEOUT
+            } else {
+                UDP_TIMEOUT
+            };
+            let alive = now.duration_since(mapping.last_used) < timeout;
+            if !alive {
+                external_ports.remove(&(flow.proto, mapping.external.1));
+            }
+            alive
+        });
+    }
+}
+
+pub fn add_rule(rule: NatRule) -> Result<(), String> {
+    if !ethernet::exists(&rule.interface) {
+        return Err(format!("Unknown interface {}", rule.interface));
+    }
+    let mut nat = NAT.lock().unwrap();
+    if nat
+        .rules
+        .iter()
+        .any(|other| other.inside.same_network(&rule.inside))
+    {
+        return Err(format!("{} is already translated", rule.inside));
+    }
+    nat.rules.push(rule);
+    Ok(())
+}
+
+pub fn remove_rule(inside: &Cidr) -> bool {
+    let mut nat = NAT.lock().unwrap();
+    let len = nat.rules.len();
+    nat.rules.retain(|rule| !rule.inside.same_network(inside));
+    nat.rules.len() != len
+}
+
+pub fn rules() -> Vec<NatRule> {
+    NAT.lock().unwrap().rules.clone()
+}
+
+pub fn mappings() -> Vec<NatMapping> {
+    let now = Instant::now();
+    let mut nat = NAT.lock().unwrap();
+    nat.expire(now);
+    nat.flows
+        .iter()
+        .map(|(flow, mapping)| NatMapping {
+            proto: flow.proto,
+            inside: flow.inside,
+            external: mapping.external,
+            remote: flow.remote,
+            idle: now.duration_since(mapping.last_used),
+        })
+        .collect()
+}
+
+// Rewrites the source of a forwarded packet from an inside host leaving through `interface`. Returns whether the
+// packet was translated, fails for the inside packets we don't know how to translate.
+pub fn translate_outbound(packet: &mut [u8], interface: &str) -> Result<bool, DropReason> {
+    let src: ProtocolAddr = packet[12..16].try_into().unwrap();
+    let dst: ProtocolAddr = packet[16..20].try_into().unwrap();
+    let mut nat = NAT.lock().unwrap();
+    if !nat.translates(&src, interface) {
+        return Ok(false);
+    }
+    let (proto, inside_port, remote_port) =
+        flow_ends(packet, End::Source).ok_or(DropReason::Untranslatable)?;
+    let flow = Flow {
+        proto,
+        inside: (src, inside_port),
+        remote: (dst, remote_port),
+    };
+    let (external_addr, external_port) = nat
+        .outbound(flow, route::source_addr(&dst), Instant::now())
+        .ok_or(DropReason::Untranslatable)?;
+    drop(nat);
+
+    packet[12..16].copy_from_slice(&external_addr);
+    rewrite(packet, End::Source, external_port);
+    Ok(true)
+}
+
+// Rewrites the destination of a reply to a translated flow back to the inside host, returns None when `packet`
+// isn't one.
+pub fn translate_reply(packet: &[u8]) -> Option<Vec<u8>> {
+    let src: ProtocolAddr = packet[12..16].try_into().unwrap();
+    let (proto, external_port, remote_port) = flow_ends(packet, End::Destination)?;
+    let (inside_addr, inside_port) =
+        NAT.lock()
+            .unwrap()
+            .inbound(proto, external_port, (src, remote_port), Instant::now())?;
+
+    let mut packet = packet.to_vec();
+    packet[16..20].copy_from_slice(&inside_addr);
+    rewrite(&mut packet, End::Destination, inside_port);
+    Some(packet)
+}
+
+// The end of the packet which is ours(the inside host's, or the external address').
+#[derive(Clone, Copy, PartialEq)]
+enum End {
+    Source,
+    Destination,
+}
+
+// (protocol, our port, the remote port) of an unfragmented UDP datagram, or ICMP echo message. The echo identifier
+// stands in for our port, the remote one being 0; requests go out and replies come back.
+fn flow_ends(packet: &[u8], ours: End) -> Option<(u8, u16, u16)> {
+    let header_len = net_util::get_bits(packet[0], 0..4) as usize * 4;
+    // MF flag and fragment offset
+    let fragmented = packet[6] & 0x3f != 0 || packet[7] != 0;
+    let payload = &packet[header_len..];
+    if fragmented || payload.len() < 8 {
+        return None;
+    }
+    match packet[9] {
+        udp::UDP_PROTO => {
+            let (src_port, dst_port) = (
+                net_util::ntohs(&payload[0..2]),
+                net_util::ntohs(&payload[2..4]),
+            );
+            Some(match ours {
+                End::Source => (udp::UDP_PROTO, src_port, dst_port),
+                End::Destination => (udp::UDP_PROTO, dst_port, src_port),
+            })
+        }
+        ICMP_PROTO => {
+            let echo_type = match ours {
+                End::Source => ECHO_REQ,
+                End::Destination => ECHO_REPLY,
+            };
+            if payload[0] != echo_type {
+                return None;
+            }
+            Some((ICMP_PROTO, net_util::ntohs(&payload[4..6]), 0))
+        }
+        _ => None,
+    }
+}
+
+// Sets our port(or echo identifier) to `port`, then fixes the transport and IP checksums up.
+fn rewrite(packet: &mut [u8], ours: End, port: u16) {
+    let header_len = net_util::get_bits(packet[0], 0..4) as usize * 4;
+    let (src, dst) = (packet[12..16].to_owned(), packet[16..20].to_owned());
+    let proto = packet[9];
+    let payload = &mut packet[header_len..];
+    if proto == udp::UDP_PROTO {
+        let offset = if ours == End::Source { 0 } else { 2 };
+        payload[offset..offset + 2].copy_from_slice(&port.to_be_bytes());
+        // A zero checksum means the sender didn't compute one(RFC 768).
+        if payload[6..8] != [0, 0] {
+            let checksum = UDP::checksum(payload, &src, &dst);
+            payload[6..8].copy_from_slice(&checksum.to_be_bytes());
+        }
+    } else {
+        payload[4..6].copy_from_slice(&port.to_be_bytes());
+        let (checksum, _) = net_util::compute_ip_checksum(payload, 2..4);
+        payload[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    let (checksum, _) = net_util::compute_ip_checksum(&packet[..header_len], 10..12);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // UDP datagram from `src:5000` to 8.8.8.8:53, with valid checksums.
+    fn udp_packet(src: ProtocolAddr) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 30, 0, 1, 0, 0, 64, 17, 0, 0, src[0], src[1], src[2], src[3], 8, 8, 8, 8,
+        ];
+        packet.extend_from_slice(&[0x13, 0x88, 0, 53, 0, 10, 0xff, 0xff, b'h', b'i']);
+        rewrite(&mut packet, End::Source, 5000);
+        packet
+    }
+
+    fn checksums_valid(packet: &[u8]) -> bool {
+        let (ip_checksum, received) = net_util::compute_ip_checksum(&packet[..20], 10..12);
+        ip_checksum == received
+            && UDP::checksum(&packet[20..], &packet[12..16], &packet[16..20])
+                == net_util::ntohs(&packet[26..28])
+    }
+
+    #[test]
+    fn test_translate_udp() {
+        let inside = Cidr::parse("10.0.3.0/24").unwrap();
+        add_rule(NatRule {
+            inside,
+            interface: ethernet::INTERFACE_NAME.to_string(),
+        })
+        .unwrap();
+
+        // Only the packets leaving through the rule's interface
+        let mut packet = udp_packet([10, 0, 3, 5]);
+        assert!(!translate_outbound(&mut packet, "eth1").unwrap());
+        assert!(translate_outbound(&mut packet, ethernet::INTERFACE_NAME).unwrap());
+        assert_eq!(packet[12..16], route::source_addr(&[8, 8, 8, 8]));
+        assert!(checksums_valid(&packet));
+
+        // The reply to the external address and port goes back to the inside host.
+        let mut reply = packet.clone();
+        reply[12..16].copy_from_slice(&[8, 8, 8, 8]);
+        reply[16..20].copy_from_slice(&packet[12..16]);
+        reply[20..24].copy_from_slice(&[0, 53, packet[20], packet[21]]);
+        rewrite(
+            &mut reply,
+            End::Destination,
+            net_util::ntohs(&packet[20..22]),
+        );
+        let translated = translate_reply(&reply).unwrap();
+        assert_eq!(translated[16..20], [10, 0, 3, 5]);
+        assert_eq!(net_util::ntohs(&translated[22..24]), 5000);
+        assert!(checksums_valid(&translated));
+
+        // Nobody else gets through the mapping
+        reply[12..16].copy_from_slice(&[8, 8, 4, 4]);
+        assert!(translate_reply(&reply).is_none());
+
+        // Hosts outside the prefix are left alone
+        let mut outside = udp_packet([10, 0, 4, 5]);
+        assert!(!translate_outbound(&mut outside, ethernet::INTERFACE_NAME).unwrap());
+        assert!(remove_rule(&inside));
+    }
+
+    #[test]
+    fn test_port_allocation_and_expiry() {
+        let mut nat = Nat::new();
+        let now = Instant::now();
+        let flow = |inside: ProtocolAddr| Flow {
+            proto: ICMP_PROTO,
+            inside: (inside, 7),
+            remote: ([8, 8, 8, 8], 0),
+        };
+        let first = nat
+            .outbound(flow([10, 0, 3, 5]), [10, 0, 0, 2], now)
+            .unwrap();
+        let second = nat
+            .outbound(flow([10, 0, 3, 6]), [10, 0, 0, 2], now)
+            .unwrap();
+        // Two inside hosts using the same identifier get different ones.
+        assert_eq!(first.1, 7);
+        assert_ne!(second.1, 7);
+        assert_eq!(
+            nat.inbound(ICMP_PROTO, second.1, ([8, 8, 8, 8], 0), now),
+            Some(([10, 0, 3, 6], 7))
+        );
+
+        let later = now + ICMP_TIMEOUT;
+        assert_eq!(
+            nat.inbound(ICMP_PROTO, second.1, ([8, 8, 8, 8], 0), later),
+            None
+        );
+        assert!(nat.flows.is_empty() && nat.external_ports.is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+// IPv4 header options
+// Reference: https://tools.ietf.org/html/rfc791#section-3.1, https://tools.ietf.org/html/rfc2113(Router Alert)
+
+use crate::ethernet::ProtocolAddr;
+use std::convert::TryInto;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+const END_OF_LIST: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const RECORD_ROUTE: u8 = 7;
+const TIMESTAMP: u8 = 68;
+const LOOSE_SOURCE_ROUTE: u8 = 131;
+const STRICT_SOURCE_ROUTE: u8 = 137;
+const ROUTER_ALERT: u8 = 148;
+// High bit of the option type
+const COPIED_FLAG: u8 = 0x80;
+
+// The header length is at most 15 words, 5 of which are the fixed header.
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ipv4Option {
+    // `pointer` is the 1-based offset of the next free slot in `route`, counting the option's own 3 byte header.
+    RecordRoute {
+        pointer: u8,
+        route: Vec<ProtocolAddr>,
+    },
+    // `data` is left undecoded, its layout depends on `flags`(timestamps only, or address/timestamp pairs).
+    Timestamp {
+        pointer: u8,
+        overflow: u8,
+        flags: u8,
+        data: Vec<u8>,
+    },
+    // Asks every router on the path to take a closer look at the packet, ex: IGMP(RFC 2113). A value of 0 means
+    // "Router shall examine packet".
+    RouterAlert(u16),
+    LooseSourceRoute {
+        pointer: u8,
+        route: Vec<ProtocolAddr>,
+    },
+    StrictSourceRoute {
+        pointer: u8,
+        route: Vec<ProtocolAddr>,
+    },
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl Ipv4Option {
+    pub fn is_source_route(&self) -> bool {
+        matches!(
+            self,
+            Ipv4Option::LooseSourceRoute { .. } | Ipv4Option::StrictSourceRoute { .. }
+        )
+    }
+
+    // Whether the option goes into every fragment of a packet, rather than only into the first one.
+    pub fn copied(&self) -> bool {
+        self.kind() & COPIED_FLAG != 0
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Ipv4Option::RecordRoute { .. } => RECORD_ROUTE,
+            Ipv4Option::Timestamp { .. } => TIMESTAMP,
+            Ipv4Option::RouterAlert(_) => ROUTER_ALERT,
+            Ipv4Option::LooseSourceRoute { .. } => LOOSE_SOURCE_ROUTE,
+            Ipv4Option::StrictSourceRoute { .. } => STRICT_SOURCE_ROUTE,
+            Ipv4Option::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    // The option's type-length-value encoding.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut value = Vec::new();
+        match self {
+            Ipv4Option::RecordRoute { pointer, route }
+            | Ipv4Option::LooseSourceRoute { pointer, route }
+            | Ipv4Option::StrictSourceRoute { pointer, route } => {
+                value.push(*pointer);
+                for addr in route {
+                    value.extend_from_slice(addr);
+                }
+            }
+            Ipv4Option::Timestamp {
+                pointer,
+                overflow,
+                flags,
+                data,
+            } => {
+                value.push(*pointer);
+                value.push(overflow << 4 | (flags & 0x0f));
+                value.extend_from_slice(data);
+            }
+            Ipv4Option::RouterAlert(alert) => value.extend_from_slice(&alert.to_be_bytes()),
+            Ipv4Option::Unknown { data, .. } => value.extend_from_slice(data),
+        }
+        let mut bytes = vec![self.kind(), (value.len() + 2) as u8];
+        bytes.append(&mut value);
+        bytes
+    }
+
+    fn from_tlv(kind: u8, value: &[u8]) -> Result<Ipv4Option, &'static str> {
+        match kind {
+            RECORD_ROUTE | LOOSE_SOURCE_ROUTE | STRICT_SOURCE_ROUTE => {
+                if value.is_empty() || (value.len() - 1) % 4 != 0 {
+                    return Err("Bad route option length");
+                }
+                let pointer = value[0];
+                let route = value[1..]
+                    .chunks(4)
+                    .map(|addr| addr.try_into().unwrap())
+                    .collect();
+                Ok(match kind {
+                    RECORD_ROUTE => Ipv4Option::RecordRoute { pointer, route },
+                    LOOSE_SOURCE_ROUTE => Ipv4Option::LooseSourceRoute { pointer, route },
+                    _ => Ipv4Option::StrictSourceRoute { pointer, route },
+                })
+            }
+            TIMESTAMP => {
+                if value.len() < 2 {
+                    return Err("Bad timestamp option length");
+                }
+                Ok(Ipv4Option::Timestamp {
+                    pointer: value[0],
+                    overflow: value[1] >> 4,
+                    flags: value[1] & 0x0f,
+                    data: value[2..].to_owned(),
+                })
+            }
+            ROUTER_ALERT => {
+                if value.len() != 2 {
+                    return Err("Bad router alert option length");
+                }
+                Ok(Ipv4Option::RouterAlert(u16::from_be_bytes([
+                    value[0], value[1],
+                ])))
+            }
+            _ => Ok(Ipv4Option::Unknown {
+                kind,
+                data: value.to_owned(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Option {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let route = |route: &Vec<ProtocolAddr>| {
+            route
+                .iter()
+                .map(|addr| Ipv4Addr::from(*addr).to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        match self {
+            Ipv4Option::RecordRoute { pointer, route: r } => {
+                write!(f, "RR ptr {} {{{}}}", pointer, route(r))
+            }
+            Ipv4Option::Timestamp {
+                pointer,
+                overflow,
+                flags,
+                data,
+            } => write!(
+                f,
+                "TS ptr {} oflw {} flg {} len {}",
+                pointer,
+                overflow,
+                flags,
+                data.len()
+            ),
+            Ipv4Option::RouterAlert(alert) => write!(f, "RA value {}", alert),
+            Ipv4Option::LooseSourceRoute { pointer, route: r } => {
+                write!(f, "LSRR ptr {} {{{}}}", pointer, route(r))
+            }
+            Ipv4Option::StrictSourceRoute { pointer, route: r } => {
+                write!(f, "SSRR ptr {} {{{}}}", pointer, route(r))
+            }
+            Ipv4Option::Unknown { kind, data } => write!(f, "opt-{} len {}", kind, data.len()),
+        }
+    }
+}
+
+// Parses the options area of a header, the padding(NOPs and everything after the end of list) is left out.
+pub fn parse(bytes: &[u8]) -> Result<Vec<Ipv4Option>, &'static str> {
+    let mut options = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        match bytes[offset] {
+            END_OF_LIST => break,
+            NO_OPERATION => offset += 1,
+            kind => {
+                if offset + 1 >= bytes.len() {
+                    return Err("Truncated option");
+                }
+                let len = bytes[offset + 1] as usize;
+                if len < 2 || offset + len > bytes.len() {
+                    return Err("Bad option length");
+                }
+                options.push(Ipv4Option::from_tlv(
+                    kind,
+                    &bytes[offset + 2..offset + len],
+                )?);
+                offset += len;
+            }
+        }
+    }
+    Ok(options)
+}
+
+// Encodes the options, padded with END_OF_LIST to a multiple of 4 bytes as the header length is counted in words.
+pub fn to_bytes(options: &[Ipv4Option]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = options
+        .iter()
+        .flat_map(|option| option.to_bytes())
+        .collect();
+    while bytes.len() % 4 != 0 {
+        bytes.push(END_OF_LIST);
+    }
+    bytes
+}
+
+// Makes sure `options` fit in a header.
+pub fn validate(options: &[Ipv4Option]) -> Result<(), &'static str> {
+    if to_bytes(options).len() > MAX_OPTIONS_LEN {
+        return Err("IP options don't fit in 40 bytes");
+    }
+    for option in options {
+        if option.to_bytes().len() > u8::MAX as usize {
+            return Err("IP option too long");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        // NOP, Router Alert, Record Route with 2 slots(the first one filled), End of list
+        let bytes = [
+            1, 148, 4, 0, 0, 7, 11, 8, 10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            parse(&bytes).unwrap(),
+            vec![
+                Ipv4Option::RouterAlert(0),
+                Ipv4Option::RecordRoute {
+                    pointer: 8,
+                    route: vec![[10, 0, 0, 1], [0, 0, 0, 0]],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        // Length running past the options area
+        assert!(parse(&[7, 12, 4, 0]).is_err());
+        // Length below the minimum
+        assert!(parse(&[68, 1, 0, 0]).is_err());
+        // Missing length
+        assert!(parse(&[1, 1, 1, 148]).is_err());
+        assert!(parse(&[148, 3, 0, 0]).is_err());
+        // Route data which isn't a whole number of addresses
+        assert!(parse(&[131, 6, 4, 10, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let options = vec![
+            Ipv4Option::RouterAlert(0),
+            Ipv4Option::Timestamp {
+                pointer: 5,
+                overflow: 0,
+                flags: 0,
+                data: vec![0; 8],
+            },
+            Ipv4Option::LooseSourceRoute {
+                pointer: 4,
+                route: vec![[10, 0, 1, 1]],
+            },
+        ];
+        let bytes = to_bytes(&options);
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(parse(&bytes).unwrap(), options);
+        assert!(parse(&bytes).unwrap()[2].is_source_route());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[Ipv4Option::RouterAlert(0)]).is_ok());
+        let record_route = Ipv4Option::RecordRoute {
+            pointer: 4,
+            route: vec![[0; 4]; 9],
+        };
+        assert!(validate(std::slice::from_ref(&record_route)).is_ok());
+        assert!(validate(&[record_route, Ipv4Option::RouterAlert(0)]).is_err());
+    }
+}
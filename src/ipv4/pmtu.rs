@@ -0,0 +1,103 @@
+// Path MTU discovery
+// Reference: https://tools.ietf.org/html/rfc1191
+
+use crate::ethernet::{self, ProtocolAddr};
+use crate::ipv4::route;
+use crate::loopback;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a learnt path MTU is trusted, after which we try the link MTU again(RFC 1191 6.3).
+pub const PMTU_EXPIRY: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref PATH_MTUS: Mutex<PathMtuCache> = Mutex::new(PathMtuCache::new(PMTU_EXPIRY));
+}
+
+struct PathMtuCache {
+    paths: HashMap<ProtocolAddr, (usize, Instant)>,
+    expiry: Duration,
+}
+
+impl PathMtuCache {
+    fn new(expiry: Duration) -> Self {
+        PathMtuCache {
+            paths: HashMap::new(),
+            expiry,
+        }
+    }
+
+    fn get(&mut self, dst: &ProtocolAddr, now: Instant) -> Option<usize> {
+        let expiry = self.expiry;
+        self.paths
+            .retain(|_, (_, learnt)| now.duration_since(*learnt) < expiry);
+        self.paths.get(dst).map(|(mtu, _)| *mtu)
+    }
+
+    // Only ever lowers the path MTU, raising it is left to the expiry.
+    fn update(&mut self, dst: ProtocolAddr, mtu: usize, link_mtu: usize, now: Instant) {
+        if mtu < ethernet::MIN_MTU || mtu >= link_mtu {
+            return;
+        }
+        match self.get(&dst, now) {
+            Some(current) if current <= mtu => {}
+            _ => {
+                self.paths.insert(dst, (mtu, now));
+            }
+        }
+    }
+}
+
+// Largest packet which can make it to `dst` without being fragmented.
+pub fn path_mtu(dst: &ProtocolAddr) -> usize {
+    if loopback::is_local(dst) {
+        return loopback::MTU;
+    }
+    let link_mtu = link_mtu(dst);
+    match PATH_MTUS.lock().unwrap().get(dst, Instant::now()) {
+        Some(mtu) => std::cmp::min(mtu, link_mtu),
+        None => link_mtu,
+    }
+}
+
+// Records the next hop MTU reported by a router which couldn't forward a DF packet to `dst`.
+pub fn update(dst: ProtocolAddr, mtu: usize) {
+    let link_mtu = link_mtu(&dst);
+    PATH_MTUS
+        .lock()
+        .unwrap()
+        .update(dst, mtu, link_mtu, Instant::now());
+}
+
+// MTU of the link the packets to `dst` leave through.
+fn link_mtu(dst: &ProtocolAddr) -> usize {
+    route::lookup(dst).map_or(ethernet::mtu(ethernet::INTERFACE_NAME), |route| {
+        ethernet::mtu(&route.interface)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_mtu_cache() {
+        let mut cache = PathMtuCache::new(PMTU_EXPIRY);
+        let now = Instant::now();
+        let dst = [10, 0, 1, 1];
+
+        cache.update(dst, 1400, 1500, now);
+        assert_eq!(cache.get(&dst, now), Some(1400));
+
+        // Never raised, and never below the minimum MTU
+        cache.update(dst, 1450, 1500, now);
+        cache.update(dst, 40, 1500, now);
+        assert_eq!(cache.get(&dst, now), Some(1400));
+        cache.update(dst, 1300, 1500, now);
+        assert_eq!(cache.get(&dst, now), Some(1300));
+
+        assert_eq!(cache.get(&dst, now + PMTU_EXPIRY), None);
+    }
+}
@@ -0,0 +1,420 @@
+// IPv4 fragment reassembly
+// Reference: https://tools.ietf.org/html/rfc791#section-3.2, https://tools.ietf.org/html/rfc815
+
+use crate::ethernet::ProtocolAddr;
+use crate::ipv4::icmp::icmp::ERROR_QUOTE_LEN;
+use crate::ipv4::stats::{self, DropReason};
+use crate::ipv4::{IPv4, MAX_PACKET_LEN};
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Same as linux's ipfrag_time and ipfrag_high_thresh.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const MAX_REASSEMBLY_MEMORY: usize = 4 * 1024 * 1024;
+
+lazy_static! {
+    pub static ref REASSEMBLER: Mutex<Reassembler> =
+        Mutex::new(Reassembler::new(REASSEMBLY_TIMEOUT, MAX_REASSEMBLY_MEMORY));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DatagramKey {
+    src: ProtocolAddr,
+    dst: ProtocolAddr,
+    // The protocol number as received, datagrams of protocols the stack doesn't implement mustn't be mixed up.
+    proto: u8,
+    id: u16,
+}
+
+struct Datagram {
+    // The fragment with offset zero, its header becomes the reassembled datagram's header.
+    first_fragment: Option<IPv4>,
+    // Its header and the start of its data as received, quoted back to the sender when the datagram times out.
+    first_fragment_quote: Vec<u8>,
+    // Fragment data by offset, never overlapping.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    // Payload length, known once the last fragment(MF unset) arrives.
+    total_len: Option<usize>,
+    received: usize,
+    created: Instant,
+}
+
+impl Datagram {
+    fn new(now: Instant) -> Self {
+        Datagram {
+            first_fragment: None,
+            first_fragment_quote: Vec::new(),
+            fragments: BTreeMap::new(),
+            total_len: None,
+            received: 0,
+            created: now,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.first_fragment.is_some() && self.total_len == Some(self.received)
+    }
+
+    // Fragments never overlap, so the received bytes add up to the payload length only once every hole is filled.
+    fn payload(&self) -> Vec<u8> {
+        self.fragments
+            .values()
+            .flat_map(|data| data.iter().cloned())
+            .collect()
+    }
+}
+
+pub struct Reassembler {
+    datagrams: HashMap<DatagramKey, Datagram>,
+    timeout: Duration,
+    max_memory: usize,
+    // Fragment data held right now
+    memory: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_memory: usize) -> Self {
+        Reassembler {
+            datagrams: HashMap::new(),
+            timeout,
+            max_memory,
+            memory: 0,
+        }
+    }
+
+    // Adds a fragment to its datagram, returns the datagram once all of its fragments are in. `bytes` is the fragment
+    // as received.
+    pub fn insert(
+        &mut self,
+        fragment: IPv4,
+        bytes: &[u8],
+        now: Instant,
+    ) -> Result<Option<IPv4>, DropReason> {
+        let key = DatagramKey {
+            src: fragment.src,
+            dst: fragment.dst,
+            proto: fragment.proto(),
+            id: fragment.id(),
+        };
+        let offset = fragment.fragment_offset();
+        let data_len = fragment.payload_bytes().len();
+        let end = offset + data_len;
+        let last = !fragment.more_fragments();
+        // Every fragment but the last one carries a non empty multiple of 8 bytes.
+        if (!last && (data_len == 0 || data_len % 8 != 0))
+            || fragment.header_len() + end > MAX_PACKET_LEN
+        {
+            return Err(DropReason::BadFragment);
+        }
+        if !self.reserve(&key, data_len) {
+            return Err(DropReason::ReassemblyMemory);
+        }
+
+        let datagram = self
+            .datagrams
+            .entry(key)
+            .or_insert_with(|| Datagram::new(now));
+        let mut overlaps = false;
+        for (other_offset, other_data) in datagram.fragments.iter() {
+            let other_end = other_offset + other_data.len();
+            if offset < other_end && *other_offset < end {
+                // Retransmitted fragments are harmless, any other overlap is either broken or an attempt to sneak
+                // different data past whoever inspected the first copy, so the whole datagram goes.
+                if *other_offset == offset && other_data[..] == fragment.payload_bytes()[..] {
+                    return Ok(None);
+                }
+                overlaps = true;
+                break;
+            }
+        }
+        let conflicting_len = match datagram.total_len {
+            Some(total_len) => end > total_len || (last && end != total_len),
+            None => {
+                last && matches!(
+                    datagram.fragments.iter().next_back(),
+                    Some((last_offset, data)) if last_offset + data.len() > end
+                )
+            }
+        };
+        if overlaps || conflicting_len {
+            self.discard(&key);
+            return Err(DropReason::ReassemblyOverlap);
+        }
+
+        if last {
+            datagram.total_len = Some(end);
+        }
+        datagram.received += data_len;
+        datagram
+            .fragments
+            .insert(offset, fragment.payload_bytes().to_owned());
+        if offset == 0 {
+            let quote_len = std::cmp::min(fragment.header_len() + ERROR_QUOTE_LEN, bytes.len());
+            datagram.first_fragment_quote = bytes[..quote_len].to_vec();
+            datagram.first_fragment = Some(fragment);
+        }
+        self.memory += data_len;
+
+        if !datagram.is_complete() {
+            return Ok(None);
+        }
+        let datagram = self.datagrams.remove(&key).unwrap();
+        self.memory -= datagram.received;
+        let payload = datagram.payload();
+        Ok(datagram
+            .first_fragment
+            .map(|first_fragment| first_fragment.reassembled(payload)))
+    }
+
+    // Drops the datagrams which have been waiting for their missing fragments for too long, returns the start of the
+    // first fragment of each when it was received, so that the sender can be told about it.
+    pub fn expire(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let timeout = self.timeout;
+        let expired: Vec<DatagramKey> = self
+            .datagrams
+            .iter()
+            .filter(|(_, datagram)| now.duration_since(datagram.created) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|key| {
+                stats::record_drop(DropReason::ReassemblyTimeout);
+                self.discard(key)
+            })
+            .collect()
+    }
+
+    // Makes room for `len` more bytes by dropping the oldest datagrams other than `key`'s, returns false when
+    // that's not enough.
+    fn reserve(&mut self, key: &DatagramKey, len: usize) -> bool {
+        while self.memory + len > self.max_memory {
+            let oldest = self
+                .datagrams
+                .iter()
+                .filter(|(other_key, _)| *other_key != key)
+                .min_by_key(|(_, datagram)| datagram.created)
+                .map(|(other_key, _)| *other_key);
+            match oldest {
+                Some(oldest) => {
+                    stats::record_drop(DropReason::ReassemblyMemory);
+                    self.discard(&oldest);
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn discard(&mut self, key: &DatagramKey) -> Option<Vec<u8>> {
+        let datagram = self.datagrams.remove(key)?;
+        self.memory -= datagram.received;
+        let quote = datagram.first_fragment_quote;
+        datagram.first_fragment.map(|_| quote)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net_util;
+
+    // UDP packet from 10.0.0.1 to 10.0.0.2 with a `payload_len` bytes payload, split into fragments of at most
+    // `mtu` bytes.
+    fn fragments(id: u16, payload_len: usize, mtu: usize) -> Vec<IPv4> {
+        protocol_fragments(17, id, payload_len, mtu)
+    }
+
+    fn protocol_fragments(proto: u8, id: u16, payload_len: usize, mtu: usize) -> Vec<IPv4> {
+        let total_len = 20 + payload_len;
+        let mut packet = vec![
+            0x45,
+            0,
+            (total_len >> 8) as u8,
+            total_len as u8,
+            (id >> 8) as u8,
+            id as u8,
+            0,
+            0,
+            64,
+            proto,
+            0,
+            0,
+            10,
+            0,
+            0,
+            1,
+            10,
+            0,
+            0,
+            2,
+        ];
+        let (checksum, _) = net_util::compute_ip_checksum(&packet, 10..12);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend((0..payload_len).map(|byte| byte as u8));
+        IPv4::parse(&packet).unwrap().fragment(mtu)
+    }
+
+    fn payload(payload_len: usize) -> Vec<u8> {
+        (0..payload_len).map(|byte| byte as u8).collect()
+    }
+
+    fn reassembler() -> Reassembler {
+        Reassembler::new(REASSEMBLY_TIMEOUT, MAX_REASSEMBLY_MEMORY)
+    }
+
+    fn insert(
+        reassembler: &mut Reassembler,
+        fragment: IPv4,
+        now: Instant,
+    ) -> Result<Option<IPv4>, DropReason> {
+        let bytes = fragment.packet_to_bytes();
+        reassembler.insert(fragment, &bytes, now)
+    }
+
+    #[test]
+    fn test_in_order() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let mut fragments = fragments(1, 3000, 1000);
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            assert!(insert(&mut reassembler, fragment, now).unwrap().is_none());
+        }
+        let datagram = insert(&mut reassembler, last, now).unwrap().unwrap();
+        assert_eq!(datagram.payload_bytes(), &payload(3000)[..]);
+        assert!(!datagram.more_fragments());
+        assert_eq!(datagram.fragment_offset(), 0);
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn test_out_of_order_and_duplicates() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let fragments = fragments(2, 3000, 1000);
+        let mut reassembled = None;
+        for index in &[3, 1, 1, 0, 2] {
+            reassembled = insert(&mut reassembler, fragments[*index].clone(), now).unwrap();
+        }
+        assert_eq!(reassembled.unwrap().payload_bytes(), &payload(3000)[..]);
+    }
+
+    #[test]
+    fn test_interleaved_datagrams() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let first = fragments(3, 2000, 1000);
+        let second = fragments(4, 2000, 1000);
+        for (a, b) in first.iter().zip(second.iter()).take(2) {
+            assert!(insert(&mut reassembler, a.clone(), now).unwrap().is_none());
+            assert!(insert(&mut reassembler, b.clone(), now).unwrap().is_none());
+        }
+        assert!(insert(&mut reassembler, first[2].clone(), now)
+            .unwrap()
+            .is_some());
+        assert!(insert(&mut reassembler, second[2].clone(), now)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_overlap() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let small = fragments(5, 3000, 1000);
+        let large = fragments(5, 3000, 1500);
+        insert(&mut reassembler, small[1].clone(), now).unwrap();
+        assert_eq!(
+            insert(&mut reassembler, large[0].clone(), now).err(),
+            Some(DropReason::ReassemblyOverlap)
+        );
+        // The whole datagram is gone
+        assert_eq!(reassembler.memory, 0);
+        assert!(reassembler.datagrams.is_empty());
+    }
+
+    #[test]
+    fn test_conflicting_length() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let last = fragments(6, 3000, 1000).pop().unwrap();
+        insert(&mut reassembler, last, now).unwrap();
+        // Another last fragment, ending past the first one
+        let other_last = fragments(6, 4000, 1000).pop().unwrap();
+        assert_eq!(
+            insert(&mut reassembler, other_last, now).err(),
+            Some(DropReason::ReassemblyOverlap)
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let fragments = fragments(7, 3000, 1000);
+        insert(&mut reassembler, fragments[0].clone(), now).unwrap();
+        insert(&mut reassembler, fragments[2].clone(), now).unwrap();
+        assert!(reassembler
+            .expire(now + REASSEMBLY_TIMEOUT - Duration::from_secs(1))
+            .is_empty());
+
+        let expired = reassembler.expire(now + REASSEMBLY_TIMEOUT);
+        // The first fragment's header, along with the start of its data
+        assert_eq!(expired, vec![fragments[0].packet_to_bytes()[..28].to_vec()]);
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn test_unsupported_protocols() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        // GRE and ESP datagrams with the same addresses and id
+        let gre = protocol_fragments(47, 10, 2000, 1000);
+        let esp = protocol_fragments(50, 10, 2000, 1000);
+        for fragment in &[&gre[0], &esp[1], &esp[2], &gre[1]] {
+            assert!(insert(&mut reassembler, (*fragment).clone(), now)
+                .unwrap()
+                .is_none());
+        }
+        let datagram = insert(&mut reassembler, gre[2].clone(), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(datagram.proto(), 47);
+        assert_eq!(datagram.payload_bytes(), &payload(2000)[..]);
+
+        let datagram = insert(&mut reassembler, esp[0].clone(), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(datagram.proto(), 50);
+
+        // Timeouts are reported with the first fragment as received
+        insert(&mut reassembler, gre[0].clone(), now).unwrap();
+        let expired = reassembler.expire(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(expired, vec![gre[0].packet_to_bytes()[..28].to_vec()]);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT, 2000);
+        let now = Instant::now();
+        let old = fragments(8, 3000, 1000);
+        let new = fragments(9, 3000, 1000);
+        insert(&mut reassembler, old[0].clone(), now).unwrap();
+        insert(&mut reassembler, old[1].clone(), now).unwrap();
+
+        // The oldest datagram makes room for the new one
+        let later = now + Duration::from_secs(1);
+        insert(&mut reassembler, new[0].clone(), later).unwrap();
+        assert_eq!(reassembler.datagrams.len(), 1);
+        insert(&mut reassembler, new[1].clone(), later).unwrap();
+
+        // But a single datagram can't take more than the limit
+        assert_eq!(
+            insert(&mut reassembler, new[2].clone(), later).err(),
+            Some(DropReason::ReassemblyMemory)
+        );
+    }
+}
@@ -0,0 +1,363 @@
+// IPv4 routing table
+// Reference: https://tools.ietf.org/html/rfc1812#section-5.2.4.3
+
+use crate::ethernet::{self, ProtocolAddr};
+use crate::ipv4::address;
+use crate::loopback;
+use crate::net_util::Cidr;
+use lazy_static::lazy_static;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref ROUTING_TABLE: RwLock<RoutingTable> =
+        RwLock::new(RoutingTable { routes: Vec::new() });
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub destination: Cidr,
+    // Neighbor the packets are handed to, None when the destination is on the link.
+    pub gateway: Option<ProtocolAddr>,
+    pub interface: String,
+    // Routes with a lower metric win between routes to the same destination.
+    pub metric: u32,
+    // Preferred source address of the packets sent through the route.
+    pub src: Option<ProtocolAddr>,
+}
+
+impl Route {
+    // Parses an `ip route` style route: `<destination | default> [via <gateway>] [dev <interface>] [metric <metric>]
+    // [src <src>]`, ex: "10.0.1.0/24 via 10.0.0.1 metric 10".
+    pub fn parse(input: &str) -> Result<Route, String> {
+        let mut args = input.split_whitespace();
+        let destination = parse_destination(args.next().unwrap_or(""))?;
+        let mut route = Route {
+            destination,
+            gateway: None,
+            interface: ethernet::INTERFACE_NAME.to_string(),
+            metric: 0,
+            src: None,
+        };
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+            match arg {
+                "via" => route.gateway = Some(parse_addr(value)?),
+                "dev" => route.interface = value.to_string(),
+                "metric" => {
+                    route.metric = value
+                        .parse()
+                        .map_err(|_| format!("Invalid metric {}", value))?
+                }
+                "src" => route.src = Some(parse_addr(value)?),
+                _ => return Err(format!("Unknown route attribute {}", arg)),
+            }
+        }
+        Ok(route)
+    }
+
+    // Route to one of our own addresses, through the loopback interface(like the ones of linux's local table).
+    fn local(dst: &ProtocolAddr) -> Route {
+        Route {
+            destination: Cidr::new(*dst, 32).unwrap(),
+            gateway: None,
+            interface: loopback::INTERFACE_NAME.to_string(),
+            metric: 0,
+            src: Some(if loopback::is_loopback(dst) {
+                loopback::ADDR
+            } else {
+                *dst
+            }),
+        }
+    }
+
+    // Route to the limited broadcast address, which stays on the link(RFC 1122 3.3.6), no matter what the routing
+    // table says. Lets the DHCP client talk before it has an address or a route.
+    fn broadcast() -> Route {
+        Route {
+            destination: Cidr::new(address::LIMITED_BROADCAST, 32).unwrap(),
+            gateway: None,
+            interface: ethernet::INTERFACE_NAME.to_string(),
+            metric: 0,
+            src: None,
+        }
+    }
+
+    // Where a packet to `dst` goes next.
+    pub fn next_hop(&self, dst: &ProtocolAddr) -> ProtocolAddr {
+        self.gateway.unwrap_or(*dst)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.destination.prefix_len() == 0 {
+            write!(f, "default")?;
+        } else {
+            write!(f, "{}", self.destination)?;
+        }
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", Ipv4Addr::from(gateway))?;
+        }
+        write!(f, " dev {}", self.interface)?;
+        if let Some(src) = self.src {
+            write!(f, " src {}", Ipv4Addr::from(src))?;
+        }
+        if self.metric != 0 {
+            write!(f, " metric {}", self.metric)?;
+        }
+        Ok(())
+    }
+}
+
+struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    fn add(&mut self, route: Route) -> Result<(), String> {
+        if route.destination.network() != route.destination.addr() {
+            return Err(format!(
+                "Invalid prefix for given prefix length {}",
+                route.destination
+            ));
+        }
+        if !ethernet::exists(&route.interface) {
+            return Err(format!("Unknown interface {}", route.interface));
+        }
+        // Gateways have to be neighbors on the route's link, we'd have no way to reach them otherwise.
+        if let Some(gateway) = route.gateway {
+            let on_link = self.routes.iter().any(|other| {
+                other.gateway.is_none()
+                    && other.interface == route.interface
+                    && other.destination.contains(&gateway)
+            });
+            if !on_link {
+                return Err(format!(
+                    "Gateway {} is not on a directly connected network",
+                    Ipv4Addr::from(gateway)
+                ));
+            }
+        }
+        if self.routes.iter().any(|other| {
+            other.destination.same_network(&route.destination) && other.metric == route.metric
+        }) {
+            return Err(format!("Route to {} already exists", route.destination));
+        }
+        self.routes.push(route);
+        Ok(())
+    }
+
+    // Removes every route to `destination`.
+    fn remove(&mut self, destination: &Cidr) -> bool {
+        let len = self.routes.len();
+        self.routes
+            .retain(|route| !route.destination.same_network(destination));
+        self.routes.len() != len
+    }
+
+    // Longest prefix match among the `usable` routes, the lowest metric breaking ties.
+    fn lookup(&self, dst: &ProtocolAddr, usable: impl Fn(&Route) -> bool) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.destination.contains(dst) && usable(route))
+            .max_by(|a, b| {
+                a.destination
+                    .prefix_len()
+                    .cmp(&b.destination.prefix_len())
+                    .then(b.metric.cmp(&a.metric))
+            })
+    }
+}
+
+pub fn add(route: Route) -> Result<(), String> {
+    ROUTING_TABLE.write().unwrap().add(route)
+}
+
+pub fn remove(destination: &Cidr) -> bool {
+    ROUTING_TABLE.write().unwrap().remove(destination)
+}
+
+pub fn list() -> Vec<Route> {
+    ROUTING_TABLE.read().unwrap().routes.clone()
+}
+
+// Our own addresses are reached through the loopback interface, whatever the routing table says.
+pub fn lookup(dst: &ProtocolAddr) -> Option<Route> {
+    if loopback::is_local(dst) {
+        return Some(Route::local(dst));
+    }
+    if *dst == address::LIMITED_BROADCAST {
+        return Some(Route::broadcast()).filter(|route| ethernet::is_up(&route.interface));
+    }
+    // The routes through a link which is down aren't usable.
+    ROUTING_TABLE
+        .read()
+        .unwrap()
+        .lookup(dst, |route| ethernet::is_up(&route.interface))
+        .cloned()
+}
+
+// The neighbor a packet to `dst` has to be sent to, None when there is no route to `dst`.
+pub fn next_hop(dst: &ProtocolAddr) -> Option<ProtocolAddr> {
+    lookup(dst).map(|route| route.next_hop(dst))
+}
+
+// Adds the route to the subnet of an address assigned to `interface`, like the kernel does along with the address.
+pub(crate) fn add_link_route(interface: &str, addr: Cidr) -> Result<(), String> {
+    ROUTING_TABLE.write().unwrap().add(Route {
+        destination: Cidr::new(addr.network(), addr.prefix_len()).unwrap(),
+        gateway: None,
+        interface: interface.to_string(),
+        metric: 0,
+        src: Some(addr.addr()),
+    })
+}
+
+// Removes the route to the subnet of an address which went away.
+pub(crate) fn remove_link_route(addr: &Cidr) {
+    ROUTING_TABLE
+        .write()
+        .unwrap()
+        .routes
+        .retain(|route| !(route.gateway.is_none() && route.destination.same_network(addr)));
+}
+
+// The subnet's route goes on with another address as its source.
+pub(crate) fn replace_link_route_src(addr: &Cidr, src: ProtocolAddr) {
+    for route in ROUTING_TABLE.write().unwrap().routes.iter_mut() {
+        if route.gateway.is_none() && route.destination.same_network(addr) {
+            route.src = Some(src);
+        }
+    }
+}
+
+// A prefix, or "default" for 0.0.0.0/0.
+pub fn parse_destination(destination: &str) -> Result<Cidr, String> {
+    match destination {
+        "default" => Cidr::new([0, 0, 0, 0], 0),
+        "" => Err("Missing route destination".to_string()),
+        destination => Cidr::parse(destination),
+    }
+}
+
+// Source address for the packets to `dst`(RFC 1122 3.3.4.3): the route's preferred source, and the interface's
+// primary address in the next hop's subnet otherwise. 0.0.0.0 when there's no address to send from.
+pub fn source_addr(dst: &ProtocolAddr) -> ProtocolAddr {
+    lookup(dst)
+        .and_then(|route| {
+            route
+                .src
+                .or_else(|| address::primary_addr(&route.interface, &route.next_hop(dst)))
+        })
+        .unwrap_or([0, 0, 0, 0])
+}
+
+fn parse_addr(addr: &str) -> Result<ProtocolAddr, String> {
+    addr.parse::<Ipv4Addr>()
+        .map(|addr| addr.octets())
+        .map_err(|_| format!("Invalid address {}", addr))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table(routes: &[&str]) -> RoutingTable {
+        let mut table = RoutingTable { routes: Vec::new() };
+        // The route that comes along with 10.0.0.2/24 on eth0
+        let link_route = Route::parse("10.0.0.0/24 dev eth0 src 10.0.0.2").unwrap();
+        table.add(link_route).unwrap();
+        for route in routes {
+            table.add(Route::parse(route).unwrap()).unwrap();
+        }
+        table
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let table = table(&[
+            "default via 10.0.0.1",
+            "10.1.0.0/16 via 10.0.0.3",
+            "10.1.2.0/24 via 10.0.0.4",
+        ]);
+        let next_hop = |dst| {
+            table
+                .lookup(&dst, |_| true)
+                .map(|route| route.next_hop(&dst))
+        };
+        assert_eq!(next_hop([10, 1, 2, 3]), Some([10, 0, 0, 4]));
+        assert_eq!(next_hop([10, 1, 3, 3]), Some([10, 0, 0, 3]));
+        assert_eq!(next_hop([8, 8, 8, 8]), Some([10, 0, 0, 1]));
+        // On the link
+        assert_eq!(next_hop([10, 0, 0, 9]), Some([10, 0, 0, 9]));
+        // Nor through a link which is down
+        assert!(table
+            .lookup(&[10, 1, 2, 3], |route| route.interface != "eth0")
+            .is_none());
+    }
+
+    #[test]
+    fn test_metric() {
+        let mut table = table(&["10.1.0.0/16 via 10.0.0.3 metric 20"]);
+        table
+            .add(Route::parse("10.1.0.0/16 via 10.0.0.4 metric 10").unwrap())
+            .unwrap();
+        assert_eq!(
+            table.lookup(&[10, 1, 0, 1], |_| true).unwrap().gateway,
+            Some([10, 0, 0, 4])
+        );
+        assert!(table
+            .add(Route::parse("10.1.0.0/16 via 10.0.0.5 metric 10").unwrap())
+            .is_err());
+
+        assert!(table.remove(&Cidr::parse("10.1.0.0/16").unwrap()));
+        assert!(table.lookup(&[10, 1, 0, 1], |_| true).is_none());
+    }
+
+    #[test]
+    fn test_add_rejects_invalid_routes() {
+        let mut table = table(&[]);
+        // Unreachable gateway, host bits set, unknown interface
+        for route in &[
+            "default via 192.168.1.1",
+            "10.1.0.1/16 via 10.0.0.1",
+            "10.1.0.0/16 dev eth9",
+        ] {
+            assert!(table.add(Route::parse(route).unwrap()).is_err());
+        }
+        assert!(Route::parse("10.1.0.0/16 via").is_err());
+        assert!(Route::parse("10.1.0.0/16 metric low").is_err());
+    }
+
+    #[test]
+    fn test_local_routes() {
+        let route = lookup(&[127, 0, 0, 5]).unwrap();
+        assert_eq!(route.to_string(), "127.0.0.5/32 dev lo src 127.0.0.1");
+        assert_eq!(next_hop(&[127, 0, 0, 5]), Some([127, 0, 0, 5]));
+        address::add(address::InterfaceAddr::new(
+            ethernet::INTERFACE_NAME,
+            Cidr::parse("192.168.86.2/24").unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(
+            lookup(&[192, 168, 86, 2]).unwrap().interface,
+            loopback::INTERFACE_NAME
+        );
+        assert_eq!(source_addr(&[192, 168, 86, 2]), [192, 168, 86, 2]);
+    }
+
+    #[test]
+    fn test_display() {
+        for route in &[
+            "default via 10.0.0.1 dev eth0",
+            "10.0.0.0/24 dev eth0 src 10.0.0.2",
+            "10.1.0.0/16 via 10.0.0.3 dev eth0 metric 10",
+        ] {
+            assert_eq!(Route::parse(route).unwrap().to_string(), *route);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Counters of the IPv4 packets dropped on receive or while forwarding them, and why.
+
+use lazy_static::lazy_static;
+use std::fmt;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref STATS: Mutex<Ipv4Stats> = Mutex::new(Ipv4Stats::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    // Shorter than a header, or than the total length the header claims.
+    Truncated,
+    BadVersion,
+    // IHL below 5, or a header longer than the packet's total length.
+    BadHeaderLength,
+    BadChecksum,
+    // Malformed options
+    BadOptions,
+    // Loose or strict source route options, which we never accept.
+    SourceRouted,
+    UnknownProtocol,
+    // A fragment which can't be part of a valid datagram, ex: a middle fragment whose length isn't a multiple of 8.
+    BadFragment,
+    // Fragments overlapping each other, or disagreeing on the datagram's length. The whole datagram is dropped.
+    ReassemblyOverlap,
+    // Some of the datagram's fragments never showed up.
+    ReassemblyTimeout,
+    // Fragments dropped to stay under the reassembly memory limit.
+    ReassemblyMemory,
+    // Packets to forward whose TTL ran out.
+    TtlExceeded,
+    // Packets to forward with no route to their destination.
+    NoRoute,
+    // Packets to forward larger than the link MTU, with DF set.
+    FragmentationNeeded,
+    // Packets from a NAT inside prefix which can't be translated, ex: fragments, or when the NAT ports run out.
+    Untranslatable,
+    // Packets dropped or rejected by the packet filter.
+    Filtered,
+    // Packets on the link from or to a loopback address.
+    Martian,
+    // Packets for another host, with forwarding off.
+    NotForUs,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ipv4Stats {
+    pub truncated: u64,
+    pub bad_version: u64,
+    pub bad_header_length: u64,
+    pub bad_checksum: u64,
+    pub bad_options: u64,
+    pub source_routed: u64,
+    pub unknown_protocol: u64,
+    pub bad_fragment: u64,
+    pub reassembly_overlap: u64,
+    pub reassembly_timeout: u64,
+    pub reassembly_memory: u64,
+    pub ttl_exceeded: u64,
+    pub no_route: u64,
+    pub fragmentation_needed: u64,
+    pub untranslatable: u64,
+    pub filtered: u64,
+    pub martian: u64,
+    pub not_for_us: u64,
+}
+
+impl fmt::Display for Ipv4Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "truncated {} bad_version {} bad_header_length {} bad_checksum {} bad_options {} source_routed {} unknown_protocol {} \
+             bad_fragment {} reassembly_overlap {} reassembly_timeout {} reassembly_memory {} ttl_exceeded {} no_route {} \
+             fragmentation_needed {} untranslatable {} filtered {} martian {} not_for_us {}",
+            self.truncated,
+            self.bad_version,
+            self.bad_header_length,
+            self.bad_checksum,
+            self.bad_options,
+            self.source_routed,
+            self.unknown_protocol,
+            self.bad_fragment,
+            self.reassembly_overlap,
+            self.reassembly_timeout,
+            self.reassembly_memory,
+            self.ttl_exceeded,
+            self.no_route,
+            self.fragmentation_needed,
+            self.untranslatable,
+            self.filtered,
+            self.martian,
+            self.not_for_us
+        )
+    }
+}
+
+pub fn stats() -> Ipv4Stats {
+    *STATS.lock().unwrap()
+}
+
+pub fn record_drop(reason: DropReason) {
+    let mut stats = STATS.lock().unwrap();
+    match reason {
+        DropReason::Truncated => stats.truncated += 1,
+        DropReason::BadVersion => stats.bad_version += 1,
+        DropReason::BadHeaderLength => stats.bad_header_length += 1,
+        DropReason::BadChecksum => stats.bad_checksum += 1,
+        DropReason::BadOptions => stats.bad_options += 1,
+        DropReason::SourceRouted => stats.source_routed += 1,
+        DropReason::UnknownProtocol => stats.unknown_protocol += 1,
+        DropReason::BadFragment => stats.bad_fragment += 1,
+        DropReason::ReassemblyOverlap => stats.reassembly_overlap += 1,
+        DropReason::ReassemblyTimeout => stats.reassembly_timeout += 1,
+        DropReason::ReassemblyMemory => stats.reassembly_memory += 1,
+        DropReason::TtlExceeded => stats.ttl_exceeded += 1,
+        DropReason::NoRoute => stats.no_route += 1,
+        DropReason::FragmentationNeeded => stats.fragmentation_needed += 1,
+        DropReason::Untranslatable => stats.untranslatable += 1,
+        DropReason::Filtered => stats.filtered += 1,
+        DropReason::Martian => stats.martian += 1,
+        DropReason::NotForUs => stats.not_for_us += 1,
+    }
+}
@@ -17,7 +17,8 @@ pub struct UdpHeader {
     chksm: u16,
 }
 
-pub const UDP_PROTO: u8 = 17;
+pub use crate::ipv4::UDP_PROTO;
+pub const UDP_HEADER_LEN: usize = 8;
 
 impl UdpHeader {
     pub fn src_port(&self) -> u16 {
@@ -42,17 +43,20 @@ impl UdpHeader {
 }
 
 impl UDP {
-    pub fn process_packet(frame: ethernet::EthernetFrame, layer_3_writer: &IPstackWriter) {
-        let ipv4_packet = ipv4::IPv4::packet_from_net_bytes(frame.payload());
+    pub fn process_packet(ipv4_packet: ipv4::IPv4, layer_3_writer: &IPstackWriter) {
         let ip_header = ipv4_packet.ip_header();
+        let ip_options = ipv4_packet.options().to_vec();
         match Self::packet_from_bytes(ipv4_packet) {
             Some(mut datagram) => {
-                let identifier = net_util::addr_identifier(ethernet::IP_ADDR, datagram.dst_port());
-                match udp_socket::get_sock(&identifier) {
+                // Sockets bound to the packet's destination come first, then the ones bound to 0.0.0.0.
+                let socket = [ip_header.dst, [0, 0, 0, 0]].iter().find_map(|addr| {
+                    udp_socket::get_sock(&net_util::addr_identifier(*addr, datagram.dst_port()))
+                });
+                match socket {
                     Some(mut_wrapped_sock) => {
                         let (lock, cond_var) = &*mut_wrapped_sock;
                         let mut sock = lock.lock().unwrap();
-                        sock.sock.write_to_sockbuff(datagram, ip_header);
+                        sock.sock.write_to_sockbuff(datagram, ip_header, ip_options);
                         sock.buff_empty = false;
                         cond_var.notify_all();
                     }
@@ -118,6 +122,9 @@ impl UDP {
     }
 
     pub fn packet_from_bytes(ipv4_packet: ipv4::IPv4) -> Option<UDP> {
+        if ipv4_packet.payload_bytes().len() < UDP_HEADER_LEN {
+            return None;
+        }
         let pseudo_header = Self::create_pseudo_header(
             ipv4_packet.payload_bytes(),
             &ipv4_packet.src,
@@ -142,6 +149,13 @@ impl UDP {
         }
     }
 
+    // Checksum of a serialized datagram going from `src_ip` to `dst_ip`, ex: once NAT rewrote either of them.
+    pub(crate) fn checksum(udp_packet_bytes: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> u16 {
+        let pseudo_header = Self::create_pseudo_header(udp_packet_bytes, src_ip, dst_ip);
+        let (checksum, _) = net_util::compute_ip_checksum(&pseudo_header, 18..20);
+        checksum
+    }
+
     // https://en.wikipedia.org/wiki/User_Datagram_Protocol#IPv4_pseudo_header
     fn create_pseudo_header(udp_packet_bytes: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> Vec<u8> {
         let mut pseudo_header = Vec::new();
@@ -1,14 +1,16 @@
 // UDP Sockets API
 
-use super::udp::{UdpHeader, UDP, UDP_PROTO};
+use super::udp::{UdpHeader, UDP, UDP_HEADER_LEN, UDP_PROTO};
 use crate::ethernet;
-use crate::ipv4::{IPstackWriter, IpHeader, Layer4Response};
+use crate::ipv4::options::{self, Ipv4Option};
+use crate::ipv4::{self, pmtu, route, IPstackWriter, IpHeader, OutboundPacket};
 use crate::net_util;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::net::IpAddr;
 use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
 
 // For lack of better options, using a global mutable states here.
 lazy_static! {
@@ -21,6 +23,8 @@ static mut LAYER3_WRITER: Option<IPstackWriter> = None;
 pub struct UdpSockObj {
     pub sock: UdpSocket,
     pub buff_empty: bool,
+    // Asynchronous error(ex: an ICMP destination unreachable) reported on the next socket call.
+    pub pending_error: Option<&'static str>,
 }
 
 pub struct UdpSocket {
@@ -30,12 +34,19 @@ pub struct UdpSocket {
     max_buff_size: u16,
     layer_3_writer: Mutex<IPstackWriter>,
     connected_sock: Option<UdpSocketIdentifier>,
+    // Options added to the header of every datagram sent(IP_OPTIONS).
+    ip_options: Vec<Ipv4Option>,
+    // Sets DF on every datagram sent, which then has to fit in the path MTU(IP_MTU_DISCOVER/IP_PMTUDISC_DO).
+    dont_fragment: bool,
+    // How long `recv_from` waits for a datagram, forever when None(SO_RCVTIMEO).
+    read_timeout: Option<Duration>,
 }
 
 #[derive(Clone, Debug)]
 struct PayloadBuff {
     udp_packet: UDP,
     src_ip_header: IpHeader,
+    ip_options: Vec<Ipv4Option>,
 }
 
 #[derive(Clone)]
@@ -48,6 +59,14 @@ pub struct UdpSocketIdentifier {
 pub struct SocketOutPut {
     src_ip_header: IpHeader,
     src_udp_header: UdpHeader,
+    ip_options: Vec<Ipv4Option>,
+}
+
+impl SocketOutPut {
+    // Options of the packet the datagram came in(IP_RECVOPTS).
+    pub fn ip_options(&self) -> &[Ipv4Option] {
+        &self.ip_options
+    }
 }
 
 pub fn get_sock(identifier: &str) -> Option<std::sync::Arc<(Mutex<UdpSockObj>, Condvar)>> {
@@ -60,6 +79,30 @@ pub fn get_sock(identifier: &str) -> Option<std::sync::Arc<(Mutex<UdpSockObj>, C
     }
 }
 
+// Hands an error reported by the network for a datagram sent from `bind_ip:port` over to the socket.
+pub fn report_error(bind_ip: ethernet::ProtocolAddr, port: u16, err: &'static str) {
+    let identifier = net_util::addr_identifier(bind_ip, port);
+    if let Some(mut_wrapped_sock) = get_sock(&identifier) {
+        let (lock, cond_var) = &*mut_wrapped_sock;
+        let mut sock = lock.lock().unwrap();
+        sock.pending_error = Some(err);
+        cond_var.notify_all();
+    }
+}
+
+// Hands an error over to every socket bound to `bind_ip`, ex: when the address is removed from its interface.
+pub fn report_addr_error(bind_ip: ethernet::ProtocolAddr, err: &'static str) {
+    let sockets: Vec<_> = SOCKETS.read().unwrap().values().cloned().collect();
+    for mut_wrapped_sock in sockets {
+        let (lock, cond_var) = &*mut_wrapped_sock;
+        let mut sock = lock.lock().unwrap();
+        if sock.sock.sock_addr() == bind_ip {
+            sock.pending_error = Some(err);
+            cond_var.notify_all();
+        }
+    }
+}
+
 pub fn intialize_stack(ip_stack_writer: IPstackWriter) {
     unsafe {
         LAYER3_WRITER = Some(ip_stack_writer);
@@ -93,9 +136,24 @@ impl UdpSocketIdentifier {
             None => return Err("Socket has become stale"),
         };
         let (lock, cond_var) = &*mut_sock;
-        let mut sock = cond_var
-            .wait_while(lock.lock().unwrap(), |sock_obj| sock_obj.buff_empty)
-            .unwrap();
+        let sock = lock.lock().unwrap();
+        let nothing_to_read =
+            |sock_obj: &mut UdpSockObj| sock_obj.buff_empty && sock_obj.pending_error.is_none();
+        let mut sock = match sock.sock.read_timeout {
+            Some(timeout) => {
+                let (sock, result) = cond_var
+                    .wait_timeout_while(sock, timeout, nothing_to_read)
+                    .unwrap();
+                if result.timed_out() {
+                    return Err("Resource temporarily unavailable");
+                }
+                sock
+            }
+            None => cond_var.wait_while(sock, nothing_to_read).unwrap(),
+        };
+        if let Some(err) = sock.pending_error.take() {
+            return Err(err);
+        }
 
         let recent_buff = sock.sock.buffer.pop().unwrap();
         if sock.sock.buffer.len() == 0 {
@@ -119,6 +177,7 @@ impl UdpSocketIdentifier {
             SocketOutPut {
                 src_ip_header: received_ip_header,
                 src_udp_header: last_udp_packet.header(),
+                ip_options: recent_buff.ip_options,
             },
         ))
     }
@@ -143,6 +202,44 @@ impl UdpSocketIdentifier {
         Ok(())
     }
 
+    // Sets the options carried by every datagram sent from now on, an empty list clears them.
+    pub fn set_ip_options(&self, ip_options: Vec<Ipv4Option>) -> Result<(), &'static str> {
+        options::validate(&ip_options)?;
+        let mut_sock = match get_sock(self.identifier()) {
+            Some(sk) => sk,
+            None => return Err("Socket has become stale"),
+        };
+        let (mut_sock, _) = &*mut_sock;
+        mut_sock.lock().unwrap().sock.ip_options = ip_options;
+        Ok(())
+    }
+
+    // With DF set, datagrams are never fragmented on the way and sending one larger than the path MTU fails with
+    // "Message too long"(EMSGSIZE).
+    pub fn set_dont_fragment(&self, dont_fragment: bool) -> Result<(), &'static str> {
+        let mut_sock = match get_sock(self.identifier()) {
+            Some(sk) => sk,
+            None => return Err("Socket has become stale"),
+        };
+        let (mut_sock, _) = &*mut_sock;
+        mut_sock.lock().unwrap().sock.dont_fragment = dont_fragment;
+        Ok(())
+    }
+
+    // Makes `recv_from` give up with "Resource temporarily unavailable"(EAGAIN) when nothing arrives in time.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), &'static str> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err("Invalid argument");
+        }
+        let mut_sock = match get_sock(self.identifier()) {
+            Some(sk) => sk,
+            None => return Err("Socket has become stale"),
+        };
+        let (mut_sock, _) = &*mut_sock;
+        mut_sock.lock().unwrap().sock.read_timeout = timeout;
+        Ok(())
+    }
+
     pub fn send(&self, buf: &[u8]) -> Result<usize, &'static str> {
         let mut_sock = match get_sock(self.identifier()) {
             Some(sk) => sk,
@@ -151,33 +248,20 @@ impl UdpSocketIdentifier {
 
         let (mut_sock, _) = &*mut_sock;
 
-        let sock = mut_sock.lock().unwrap();
+        let mut sock = mut_sock.lock().unwrap();
+        if let Some(err) = sock.pending_error.take() {
+            return Err(err);
+        }
 
-        if let Some(remote_sock) = &sock.sock.connected_sock {
-            let (dst_ip, dst_port, src_ip, src_port) = (
-                remote_sock.ip(),
-                remote_sock.port(),
-                sock.sock.sock_addr(),
-                sock.sock.sock_port(),
-            );
-            let (_, udp_resp_bytes) = UDP::create_packet(buf, src_port, dst_port, src_ip, dst_ip);
-            let udp_len = udp_resp_bytes.len();
-
-            let ip_header =
-                IpHeader::make_unfragmented_ip_header(dst_ip, src_ip, UDP_PROTO, udp_len as u16);
-            let l4_resp = Layer4Response {
-                data: udp_resp_bytes,
-                protocol: UDP_PROTO,
-                src_ip_header: ip_header,
-            };
-            sock.sock.write(l4_resp);
-            Ok(udp_len)
-        } else {
-            Err("Socket not connected to any remote socket!")
+        match &sock.sock.connected_sock {
+            Some(remote_sock) => sock
+                .sock
+                .send_datagram(buf, remote_sock.ip(), remote_sock.port()),
+            None => Err("Socket not connected to any remote socket!"),
         }
     }
 
-    pub fn send_to(&self, buf: &[u8], src: &SocketOutPut) {
+    pub fn send_to(&self, buf: &[u8], src: &SocketOutPut) -> Result<usize, &'static str> {
         let mut_sock = match get_sock(self.identifier()) {
             Some(sk) => sk,
             None => panic!("Errored while trying to retreive the socket!"),
@@ -185,28 +269,65 @@ impl UdpSocketIdentifier {
         let (mut_sock, _) = &*mut_sock;
 
         let sock = mut_sock.lock().unwrap();
-        // we need to send the response back to where we received it from.
-        let (dst_ip, dst_port, src_ip, src_port) = (
-            src.src_ip_header.src,
-            src.src_udp_header.src_port(),
-            sock.sock.sock_addr(),
-            sock.sock.sock_port(),
-        );
-
-        let (_, udp_resp_bytes) = UDP::create_packet(buf, src_port, dst_port, src_ip, dst_ip);
-        sock.sock.write(Layer4Response {
-            data: udp_resp_bytes,
-            protocol: UDP_PROTO,
-            src_ip_header: src.src_ip_header,
-        });
+        // The response goes back to where the datagram came from.
+        sock.sock
+            .send_datagram(buf, src.src_ip_header.src, src.src_udp_header.src_port())
     }
 }
 impl UdpSocket {
-    pub fn write_to_sockbuff(&mut self, udp_packet: UDP, src_ip_header: IpHeader) {
+    // Sends from the bound address, or from the one the routing table picks when bound to 0.0.0.0.
+    fn send_datagram(
+        &self,
+        buf: &[u8],
+        dst_ip: ethernet::ProtocolAddr,
+        dst_port: u16,
+    ) -> Result<usize, &'static str> {
+        if route::next_hop(&dst_ip).is_none() {
+            return Err("Network is unreachable");
+        }
+        let src_ip = match self.sock_addr() {
+            [0, 0, 0, 0] => route::source_addr(&dst_ip),
+            addr => addr,
+        };
+        let udp_len = UDP_HEADER_LEN + buf.len();
+        self.check_packet_len(&dst_ip, udp_len)?;
+        let (_, udp_bytes) = UDP::create_packet(buf, self.sock_port(), dst_port, src_ip, dst_ip);
+        self.write(OutboundPacket {
+            src: Some(src_ip),
+            options: self.ip_options.clone(),
+            dont_fragment: self.dont_fragment,
+            ..OutboundPacket::new(dst_ip, UDP_PROTO, udp_bytes)
+        });
+        Ok(udp_len)
+    }
+
+    // Packets can't be larger than 65535 bytes, fragmented or not, and a DF datagram which doesn't fit in the path MTU
+    // would only be dropped on the way.
+    fn check_packet_len(
+        &self,
+        dst: &ethernet::ProtocolAddr,
+        udp_len: usize,
+    ) -> Result<(), &'static str> {
+        let packet_len = ipv4::MIN_HEADER_LEN + options::to_bytes(&self.ip_options).len() + udp_len;
+        if packet_len > ipv4::MAX_PACKET_LEN
+            || (self.dont_fragment && packet_len > pmtu::path_mtu(dst))
+        {
+            return Err("Message too long");
+        }
+        Ok(())
+    }
+
+    pub fn write_to_sockbuff(
+        &mut self,
+        udp_packet: UDP,
+        src_ip_header: IpHeader,
+        ip_options: Vec<Ipv4Option>,
+    ) {
         if (self.buffer.len() as u16) < self.max_buff_size {
             self.buffer.push(PayloadBuff {
                 src_ip_header: src_ip_header,
                 udp_packet: udp_packet,
+                ip_options,
             });
         };
         // Buffer full, drop the packet.
@@ -238,7 +359,7 @@ impl UdpSocket {
         Ok((ip_address, sock_addr.port()))
     }
 
-    fn write(&self, resp: Layer4Response) {
+    fn write(&self, packet: OutboundPacket) {
         let writer = loop {
             if let Ok(writer) = self.layer_3_writer.try_lock() {
                 break writer;
@@ -246,7 +367,7 @@ impl UdpSocket {
                 continue;
             }
         };
-        writer.write(resp);
+        writer.write(packet);
     }
 
     fn sock_addr(&self) -> ethernet::ProtocolAddr {
@@ -280,10 +401,14 @@ impl UdpSocket {
                 max_buff_size: 10000,
                 layer_3_writer: Mutex::new(ip_stack_writer),
                 connected_sock: None,
+                ip_options: Vec::new(),
+                dont_fragment: false,
+                read_timeout: None,
             };
             let sock_obj = UdpSockObj {
                 sock: socket,
                 buff_empty: true,
+                pending_error: None,
             };
             created_sockets.insert(identifier, Arc::new((Mutex::new(sock_obj), Condvar::new())));
         }
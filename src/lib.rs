@@ -2,13 +2,27 @@
 extern crate ioctl_macros;
 use std::{process, thread, time};
 mod arp;
+pub mod arping;
+mod bridge;
+pub mod control;
+pub mod dhcp;
 mod ethernet;
+pub mod events;
+pub mod ip;
 mod ipv4;
+mod loopback;
+pub mod neighbor;
 mod net_util;
+pub mod resolv;
 mod tap;
 pub mod udp_socket;
 use arp::ARP;
-use ethernet::Ethernet;
+use bridge::Bridge;
+use ethernet::{Ethernet, ProtocolAddr};
+use ipv4::address::InterfaceAddr;
+use net_util::Cidr;
+use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+use std::net::Ipv4Addr;
 
 fn show_error<T>(err: T) -> !
 where
@@ -18,7 +32,11 @@ where
     process::exit(-1)
 }
 
-pub fn start_stack() {
+// Starts the stack on a new tap device, with `addr` on its interface(ex: "10.0.0.2/24"). The host end of the tap gets
+// the first other address of the subnet. Fails when the stack's address turns out to be in use by another host.
+pub fn start_stack(addr: &str) -> Result<(), String> {
+    let addr = Cidr::parse(addr)?;
+    let host_addr = host_end(&addr)?;
     let (fd, device) = tap::create_tap_device("tap1").unwrap();
 
     // Allow some time for the kernel to allocate the tun/tap device
@@ -29,18 +47,194 @@ pub fn start_stack() {
         Err(err) => show_error(err),
     }
 
-    match tap::add_ip_route(&device, "10.0.0.1/24") {
+    let host_cidr = format!("{}/{}", Ipv4Addr::from(host_addr), addr.prefix_len());
+    match tap::add_ip_route(&device, &host_cidr) {
         Ok(_) => (),
         Err(err) => show_error(err),
     }
 
-    let mut eth = match Ethernet::bind(fd) {
+    let eth = match Ethernet::bind(fd, ethernet::INTERFACE_NAME) {
         Ok(eth) => eth,
         Err(err) => show_error(err),
     };
+    run_stack(eth, addr)?;
+    // Everything off the link goes through the host end of the tap.
+    ip::add_route(&format!("default via {}", Ipv4Addr::from(host_addr)))
+}
+
+// The subnet's first address other than the stack's, ex: 10.0.0.1 for 10.0.0.2/24.
+fn host_end(addr: &Cidr) -> Result<ProtocolAddr, String> {
+    if addr.prefix_len() > 30 {
+        return Err(format!("No room for the host end of the tap in {}", addr));
+    }
+    let first = u32::from_be_bytes(addr.network()) + 1;
+    if first == u32::from_be_bytes(addr.addr()) {
+        Ok((first + 1).to_be_bytes())
+    } else {
+        Ok(first.to_be_bytes())
+    }
+}
+
+// Starts the stack with nothing but the loopback interface, no tap device needed. Handy for local client/server
+// tests, see examples/udp-example. The packets routed to the link are dropped.
+pub fn start_loopback_stack() {
+    ipv4::initialize_ipv4_stack();
+}
+
+fn run_stack(mut eth: Ethernet, addr: Cidr) -> Result<(), String> {
+    let (name, hw_addr, writer) = (eth.name().to_string(), eth.hw_address(), eth.writer());
     std::thread::spawn(move || {
         eth.start_stack();
     });
-    // Allow some time for the stack to get started before returning
-    thread::sleep(time::Duration::from_secs(5));
+    // Make sure nobody else on the link is using our address before we start using it. Probing takes a few
+    // seconds, which also gives the stack enough time to get started before returning.
+    arp::acd::claim(addr.addr(), hw_addr, &writer)?;
+    ipv4::address::add(InterfaceAddr::new(&name, addr))
+}
+
+// Starts the stack behind a software bridge which switches frames between the given tap devices.
+// The stack itself is attached to the bridge as just another port, so it can talk to every host on any
+// of the bridged segments. Unlike `start_stack`, no address is assigned to the host side of the taps.
+pub fn start_bridged_stack(device_names: &[&str], addr: &str) -> Result<(), String> {
+    let addr = Cidr::parse(addr)?;
+    run_stack(bridged_eth(device_names), addr)
+}
+
+// Like `start_bridged_stack`, but the address, default route and DNS servers come from a DHCP server on one of the
+// bridged segments. The link has no address till the first lease is bound.
+pub fn start_bridged_dhcp_stack(device_names: &[&str]) -> Result<dhcp::Lease, String> {
+    let mut eth = bridged_eth(device_names);
+    std::thread::spawn(move || {
+        eth.start_stack();
+    });
+    while ethernet::link(ethernet::INTERFACE_NAME).is_none() {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    dhcp::client::start()
+}
+
+// Starts the stack as a router between new tap devices, one per (device name, address) pair, ex:
+// [("tap1", "10.0.1.1/24"), ("tap2", "10.0.2.1/24")]. The interfaces are named eth0, eth1.. in that order, and
+// forwarding is turned on. Like with `start_bridged_stack`, no address is assigned to the host side of the taps.
+pub fn start_routed_stack(links: &[(&str, &str)]) -> Result<(), String> {
+    for (index, (device_name, addr)) in links.iter().enumerate() {
+        let addr = Cidr::parse(addr)?;
+        let (fd, device) = match tap::create_tap_device(device_name) {
+            Ok(res) => res,
+            Err(err) => show_error(err),
+        };
+        thread::sleep(time::Duration::from_secs(1));
+        match tap::set_device_link_up(&device) {
+            Ok(_) => (),
+            Err(err) => show_error(err),
+        }
+        let eth = match Ethernet::bind(fd, &format!("eth{}", index)) {
+            Ok(eth) => eth,
+            Err(err) => show_error(err),
+        };
+        run_stack(eth, addr)?;
+    }
+    ip::set_forwarding(ip::ForwardingConfig {
+        enabled: true,
+        ..ip::forwarding()
+    });
+    Ok(())
+}
+
+fn bridged_eth(device_names: &[&str]) -> Ethernet {
+    let mut bridge = Bridge::new(bridge::DEFAULT_AGEING_TIME);
+
+    for device_name in device_names {
+        let (fd, device) = match tap::create_tap_device(device_name) {
+            Ok(res) => res,
+            Err(err) => show_error(err),
+        };
+        thread::sleep(time::Duration::from_secs(1));
+        match tap::set_device_link_up(&device) {
+            Ok(_) => (),
+            Err(err) => show_error(err),
+        }
+        bridge.add_port(fd);
+    }
+
+    // The stack and the bridge talk over a socket pair, which preserves frame boundaries just like a tap device.
+    let (stack_fd, bridge_fd) = match socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::empty(),
+    ) {
+        Ok(fds) => fds,
+        Err(err) => show_error(err),
+    };
+
+    let eth = match Ethernet::bind(stack_fd, ethernet::INTERFACE_NAME) {
+        Ok(eth) => eth,
+        Err(err) => show_error(err),
+    };
+    bridge.add_local_port(bridge_fd, eth.hw_address());
+    bridge.start();
+    eth
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Once;
+
+    static STACK: Once = Once::new();
+
+    fn start_stack() {
+        STACK.call_once(super::start_loopback_stack);
+    }
+
+    #[test]
+    fn test_loopback_udp() {
+        start_stack();
+        let server = super::udp_socket::bind("127.0.0.1:5055").unwrap();
+        let client = super::udp_socket::bind("127.0.0.1:4055").unwrap();
+        client.connect("127.0.0.1:5055").unwrap();
+        client.send(b"ping").unwrap();
+        let mut buf = Vec::with_capacity(100);
+        let (_, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(buf, b"ping");
+        server.send_to(b"pong", &from).unwrap();
+        let mut buf = Vec::with_capacity(100);
+        client.recv_from(&mut buf).unwrap();
+        assert_eq!(buf, b"pong");
+    }
+
+    #[test]
+    fn test_oversized_datagram() {
+        start_stack();
+        let server = super::udp_socket::bind("127.0.0.1:5057").unwrap();
+        let client = super::udp_socket::bind("127.0.0.1:4057").unwrap();
+        client.connect("127.0.0.1:5057").unwrap();
+        // 20 bytes of IP header and 8 of UDP header, DF or not
+        assert_eq!(client.send(&[0; 65508]).err(), Some("Message too long"));
+        assert_eq!(client.send(&[0; 65507]), Ok(65515));
+        let mut buf = Vec::with_capacity(65507);
+        server.recv_from(&mut buf).unwrap();
+        assert_eq!(buf.len(), 65507);
+    }
+
+    #[test]
+    fn test_address_removal_fails_bound_sockets() {
+        start_stack();
+        let events = super::events::subscribe();
+        super::ip::add_address("192.168.81.2/24").unwrap();
+        let socket = super::udp_socket::bind("192.168.81.2:5056").unwrap();
+        super::ip::delete_address("192.168.81.2/24").unwrap();
+        let mut buf = Vec::with_capacity(100);
+        assert_eq!(
+            socket.recv_from(&mut buf).err(),
+            Some("Cannot assign requested address")
+        );
+        let removed = events.try_iter().any(|event| match event {
+            super::events::StackEvent::AddressRemoved { addr, .. } => {
+                addr.addr() == [192, 168, 81, 2]
+            }
+            _ => false,
+        });
+        assert!(removed);
+    }
 }
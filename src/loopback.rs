@@ -0,0 +1,23 @@
+// Loopback interface
+// Reference: https://tools.ietf.org/html/rfc1122#section-3.2.1.3, https://tools.ietf.org/html/rfc5735
+//
+// The packets the stack sends to one of its own addresses, or to 127.0.0.0/8, never make it to the link: the IPv4
+// writer hands them straight back to the IPv4 layer, with no ARP or ethernet framing on the way.
+
+use crate::ethernet::ProtocolAddr;
+use crate::ipv4::address;
+
+pub const INTERFACE_NAME: &str = "lo";
+pub const ADDR: ProtocolAddr = [127, 0, 0, 1];
+// Largest IPv4 packet, nothing sent over the loopback is ever fragmented.
+pub const MTU: usize = 65535;
+
+// 127.0.0.0/8
+pub fn is_loopback(addr: &ProtocolAddr) -> bool {
+    addr[0] == 127
+}
+
+// Whether the packets to `addr` stay within the stack: the whole of 127.0.0.0/8, and the interfaces' addresses.
+pub fn is_local(addr: &ProtocolAddr) -> bool {
+    is_loopback(addr) || address::is_local(addr)
+}
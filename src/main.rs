@@ -1,3 +1,15 @@
+use std::io::{self, BufRead};
+
 fn main() {
-    user_net::start_stack()
+    user_net::start_stack("10.0.0.2/24").unwrap();
+
+    // Read control commands(see `user_net::control`) from stdin, ex: `neigh show`
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        match user_net::control::execute(&line.unwrap()) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(err) => eprintln!("ERROR: {}", err),
+        }
+    }
 }
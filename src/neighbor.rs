@@ -0,0 +1,92 @@
+// Neighbor(ARP) table API
+
+pub use crate::arp::neighbor::{NeighborConfig, NeighborEntry, NeighborState};
+pub use crate::arp::policy::{ArpPolicy, ArpStats};
+
+use crate::arp::neighbor::NEIGHBOR_TABLE;
+use crate::arp::{policy, proxy};
+use crate::ethernet::ProtocolAddr;
+use crate::net_util::{self, Cidr};
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+pub fn config() -> NeighborConfig {
+    NEIGHBOR_TABLE.lock().unwrap().config()
+}
+
+// Changes the neighbor table timers, ex: `base_reachable_time` and `gc_stale_time`.
+pub fn set_config(config: NeighborConfig) {
+    NEIGHBOR_TABLE.lock().unwrap().set_config(config);
+}
+
+// Adds a static entry(like `ip neigh add <addr> lladdr <hw_addr> nud permanent`), ex: add("10.0.0.1", "02:42:ac:11:00:02").
+// Static entries are never aged out or overwritten by received ARP packets.
+pub fn add(addr: &str, hw_addr: &str) -> Result<(), String> {
+    let protocol_addr = parse_addr(addr)?;
+    let hw_addr = net_util::parse_hw_addr(hw_addr)?;
+    NEIGHBOR_TABLE
+        .lock()
+        .unwrap()
+        .add_permanent(protocol_addr, hw_addr, Instant::now());
+    Ok(())
+}
+
+pub fn delete(addr: &str) -> Result<(), String> {
+    let protocol_addr = parse_addr(addr)?;
+    if NEIGHBOR_TABLE.lock().unwrap().remove(&protocol_addr) {
+        Ok(())
+    } else {
+        Err(format!("No neighbor entry for {}", addr))
+    }
+}
+
+// Removes all the dynamically learnt entries.
+pub fn flush() {
+    NEIGHBOR_TABLE.lock().unwrap().flush();
+}
+
+pub fn list() -> Vec<NeighborEntry> {
+    NEIGHBOR_TABLE.lock().unwrap().entries(Instant::now())
+}
+
+fn parse_addr(addr: &str) -> Result<ProtocolAddr, String> {
+    addr.trim()
+        .parse::<Ipv4Addr>()
+        .map(|addr| addr.octets())
+        .map_err(|_| format!("Invalid address {}", addr))
+}
+
+// Answer ARP requests for every address in `subnet`(ex: "10.0.1.0/24") with the stack's hw address.
+pub fn add_proxy(subnet: &str) -> Result<(), String> {
+    proxy::add(Cidr::parse(subnet)?);
+    Ok(())
+}
+
+pub fn remove_proxy(subnet: &str) -> Result<(), String> {
+    if proxy::remove(&Cidr::parse(subnet)?) {
+        Ok(())
+    } else {
+        Err(format!("{} is not proxied", subnet))
+    }
+}
+
+pub fn proxies() -> Vec<String> {
+    proxy::list()
+        .iter()
+        .map(|subnet| subnet.to_string())
+        .collect()
+}
+
+pub fn arp_policy() -> ArpPolicy {
+    policy::policy()
+}
+
+// Changes how much the stack trusts the ARP packets it receives, ex: whether unsolicited replies are accepted.
+pub fn set_arp_policy(arp_policy: ArpPolicy) {
+    policy::set_policy(arp_policy);
+}
+
+// Counters of the ARP packets dropped by validation and policy.
+pub fn arp_stats() -> ArpStats {
+    policy::stats()
+}
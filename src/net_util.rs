@@ -1,5 +1,79 @@
-use crate::ethernet::ProtocolAddr;
+use crate::ethernet::{HwAddr, ProtocolAddr};
 use std::convert::TryInto;
+use std::net::Ipv4Addr;
+
+// An IPv4 address along with a prefix length, ex: 10.0.0.0/24
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: ProtocolAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: ProtocolAddr, prefix_len: u8) -> Result<Self, String> {
+        if prefix_len > 32 {
+            return Err(format!("Invalid prefix length {}", prefix_len));
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    // Parses `a.b.c.d/len`, a bare address is treated as a /32.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut parts = input.trim().splitn(2, '/');
+        let addr: Ipv4Addr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| format!("Invalid address {}", input))?;
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid prefix length in {}", input))?,
+            None => 32,
+        };
+        Self::new(addr.octets(), prefix_len)
+    }
+
+    pub fn addr(&self) -> ProtocolAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn netmask(&self) -> u32 {
+        if self.prefix_len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - self.prefix_len)
+        }
+    }
+
+    pub fn network(&self) -> ProtocolAddr {
+        (u32::from_be_bytes(self.addr) & self.netmask()).to_be_bytes()
+    }
+
+    // The network's last address, all host bits set.
+    pub fn broadcast(&self) -> ProtocolAddr {
+        (u32::from_be_bytes(self.network()) | !self.netmask()).to_be_bytes()
+    }
+
+    pub fn contains(&self, addr: &ProtocolAddr) -> bool {
+        u32::from_be_bytes(*addr) & self.netmask() == u32::from_be_bytes(self.network())
+    }
+
+    // Whether both describe the same network, regardless of the host bits.
+    pub fn same_network(&self, other: &Cidr) -> bool {
+        self.prefix_len == other.prefix_len && self.network() == other.network()
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.addr), self.prefix_len)
+    }
+}
 
 #[inline]
 pub fn ntohs(input: &[u8]) -> u16 {
@@ -68,6 +142,28 @@ pub fn addr_identifier(ip_address: ProtocolAddr, port: u16) -> String {
     )
 }
 
+pub fn hw_addr_to_string(hw_addr: &HwAddr) -> String {
+    hw_addr
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+// Parses a colon separated hw address, ex: 02:42:ac:11:00:02
+pub fn parse_hw_addr(input: &str) -> Result<HwAddr, String> {
+    let bytes = input
+        .trim()
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("Invalid hw address {}", input))?;
+    bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| format!("Invalid hw address {}", input))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -134,4 +230,36 @@ mod test {
         // checksum calculation => 0xffff + 0xffff+ 0xffff => 0x2FFFD => Add the overflowed carry back => 0xfffd + 2 => 0xffff => 1's compliment(0xffff)
         assert_eq!(computed_chksum, 0x0000);
     }
+
+    #[test]
+    fn test_cidr() {
+        let cidr = Cidr::parse("10.0.0.2/24").unwrap();
+        assert_eq!(cidr.addr, [10, 0, 0, 2]);
+        assert_eq!(cidr.network(), [10, 0, 0, 0]);
+        assert_eq!(cidr.netmask(), 0xffffff00);
+        assert!(cidr.contains(&[10, 0, 0, 255]));
+        assert!(!cidr.contains(&[10, 0, 1, 1]));
+        assert!(cidr.same_network(&Cidr::parse("10.0.0.0/24").unwrap()));
+        assert_eq!(cidr.to_string(), "10.0.0.2/24");
+
+        let host = Cidr::parse("192.168.1.1").unwrap();
+        assert_eq!(host.prefix_len, 32);
+        assert!(host.contains(&[192, 168, 1, 1]));
+        assert!(!host.contains(&[192, 168, 1, 2]));
+
+        let default = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(default.contains(&[8, 8, 8, 8]));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/24").is_err());
+    }
+
+    #[test]
+    fn test_hw_addr() {
+        let hw_addr = parse_hw_addr("02:42:AC:11:00:0f").unwrap();
+        assert_eq!(hw_addr, [0x02, 0x42, 0xac, 0x11, 0x00, 0x0f]);
+        assert_eq!(hw_addr_to_string(&hw_addr), "02:42:ac:11:00:0f");
+        assert!(parse_hw_addr("02:42:ac:11:00").is_err());
+        assert!(parse_hw_addr("02:42:ac:11:00:zz").is_err());
+    }
 }
@@ -0,0 +1,20 @@
+// DNS servers configuration(like resolv.conf)
+//
+// The stack has no resolver of its own, it only keeps the list of servers around for the applications, the DHCP
+// client fills it in along with the lease.
+
+use lazy_static::lazy_static;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref NAMESERVERS: RwLock<Vec<Ipv4Addr>> = RwLock::new(Vec::new());
+}
+
+pub fn nameservers() -> Vec<Ipv4Addr> {
+    NAMESERVERS.read().unwrap().clone()
+}
+
+pub fn set_nameservers(nameservers: Vec<Ipv4Addr>) {
+    *NAMESERVERS.write().unwrap() = nameservers;
+}